@@ -12,24 +12,42 @@ fn main() {
         .subcommand(extract_app())
         .subcommand(repack_app())
         .subcommand(pack_app())
-        .subcommand(list_app());
+        .subcommand(list_app())
+        .subcommand(rekey_app())
+        .subcommand(diff_app());
     let matches = app.get_matches();
     match matches.subcommand() {
         ("extract", Some(matches)) => extract(matches),
         ("repack", Some(matches)) => repack(matches),
         ("pack", Some(matches)) => pack(matches),
         ("list", Some(matches)) => list(matches),
+        ("rekey", Some(matches)) => rekey(matches),
+        ("diff", Some(matches)) => diff(matches),
         _ => println!("{}", matches.usage()),
     }
 }
 
 fn key_arg() -> Arg<'static, 'static> {
-    Arg::with_name("key")
-        .short("k")
-        .long("key")
-        .takes_value(true)
-        .env("PK2_BLOWFISH_KEY")
-        .default_value("169841")
+    Arg::with_name("key").short("k").long("key").takes_value(true).env("PK2_BLOWFISH_KEY")
+}
+
+/// Opens the archive with the given key, or with the first known key that
+/// matches if none was given. Returns the archive and the key that was used.
+fn open_archive<'a>(archive_path: &Path, key: Option<&'a str>) -> (pk2::Pk2, &'a [u8]) {
+    let res = match key {
+        Some(key) => pk2::Pk2::open(archive_path, key).map(|archive| (archive, key.as_bytes())),
+        None => {
+            pk2::Pk2::open_detect_key(archive_path, pk2::keys::known()).map(|(archive, key)| {
+                // the key of unencrypted archives is never checked, so any candidate matches
+                if !archive.is_encrypted() {
+                    return (archive, &b""[..]);
+                }
+                eprintln!("Detected {} key.", pk2::keys::region_of(key).unwrap_or("unknown"));
+                (archive, key)
+            })
+        }
+    };
+    res.unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path))
 }
 
 fn extract_app() -> App<'static, 'static> {
@@ -45,7 +63,7 @@ fn extract_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the archive to open"),
         )
-        .arg(key_arg().help("Sets the blowfish key"))
+        .arg(key_arg().help("Sets the blowfish key, detected from the known keys if omitted"))
         .arg(
             Arg::with_name("out")
                 .short("o")
@@ -62,15 +80,13 @@ fn extract_app() -> App<'static, 'static> {
 }
 
 fn extract(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
     let archive_path = matches.value_of_os("archive").map(Path::new).unwrap();
     let out_path = matches
         .value_of_os("out")
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension(""));
     let write_times = matches.is_present("time");
-    let archive = pk2::Pk2::open(archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    let (archive, _) = open_archive(archive_path, matches.value_of("key"));
     let folder = archive.open_directory("/").unwrap();
     println!("Extracting {:?} to {:?}.", archive_path, out_path);
     extract_files(folder, &out_path, write_times);
@@ -121,13 +137,15 @@ fn repack_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the archive to open"),
         )
-        .arg(key_arg().help("Sets the blowfish key for the input archive"))
+        .arg(key_arg().help(
+            "Sets the blowfish key for the input archive, detected from the known keys if omitted",
+        ))
         .arg(
             Arg::with_name("packkey")
                 .short("p")
                 .long("packkey")
                 .takes_value(true)
-                .help("Sets the blowfish key for the output archive"),
+                .help("Sets the blowfish key for the output archive, defaults to the input key"),
         )
         .arg(
             Arg::with_name("out")
@@ -139,16 +157,13 @@ fn repack_app() -> App<'static, 'static> {
 }
 
 fn repack(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
-    let packkey =
-        matches.value_of("packkey").or_else(|| matches.value_of("key")).unwrap().as_bytes();
     let archive_path = matches.value_of_os("archive").map(Path::new).unwrap();
     let out_archive_path = matches
         .value_of_os("out")
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension("repack.pk2"));
-    let in_archive = pk2::Pk2::open(archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    let (in_archive, key) = open_archive(archive_path, matches.value_of("key"));
+    let packkey = matches.value_of("packkey").map_or(key, str::as_bytes);
     let mut out_archive = pk2::Pk2::create_new(&out_archive_path, packkey)
         .unwrap_or_else(|_| panic!("failed to create archive at {:?}", out_archive_path));
     let folder = in_archive.open_directory("/").unwrap();
@@ -189,7 +204,10 @@ fn pack_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the directory to pack"),
         )
-        .arg(key_arg().help("Sets the blowfish key for the resulting archive"))
+        .arg(
+            key_arg()
+                .help("Sets the blowfish key for the resulting archive, defaults to the iSRO key"),
+        )
         .arg(
             Arg::with_name("archive")
                 .short("a")
@@ -200,7 +218,7 @@ fn pack_app() -> App<'static, 'static> {
 }
 
 fn pack(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
+    let key = matches.value_of("key").map_or(pk2::keys::ISRO, str::as_bytes);
     let input_path = matches.value_of_os("directory").map(Path::new).unwrap();
     let out_archive_path = matches
         .value_of_os("archive")
@@ -216,8 +234,6 @@ fn pack(matches: &ArgMatches<'static>) {
 }
 
 fn pack_files(out_archive: &mut pk2::Pk2, dir_path: &Path, base: &Path) {
-    use std::io::{Read, Write};
-    let mut buf = Vec::new();
     for entry in std::fs::read_dir(dir_path).unwrap() {
         let entry = entry.unwrap();
         let ty = entry.file_type().unwrap();
@@ -226,13 +242,11 @@ fn pack_files(out_archive: &mut pk2::Pk2, dir_path: &Path, base: &Path) {
             pack_files(out_archive, &path, base);
         } else if ty.is_file() {
             let mut file = std::fs::File::open(&path).unwrap();
-            file.read_to_end(&mut buf).unwrap();
-            out_archive
-                .create_file(Path::new("/").join(path.strip_prefix(base).unwrap()))
-                .unwrap()
-                .write_all(&buf)
-                .unwrap();
-            buf.clear();
+            let mut out_file = out_archive
+                .create_file_streaming(Path::new("/").join(path.strip_prefix(base).unwrap()))
+                .unwrap_or_else(|e| panic!("failed to pack {}: {e}", path.display()));
+            std::io::copy(&mut file, &mut out_file).unwrap();
+            out_file.flush_drop().unwrap();
         }
     }
 }
@@ -250,15 +264,13 @@ fn list_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the archive to open"),
         )
-        .arg(key_arg().help("Sets the blowfish key"))
+        .arg(key_arg().help("Sets the blowfish key, detected from the known keys if omitted"))
         .arg(Arg::with_name("time").short("t").long("time").help("If passed, shows file times"))
 }
 
 fn list(matches: &ArgMatches<'static>) {
-    let key = matches.value_of("key").unwrap().as_bytes();
     let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
-    let archive = pk2::Pk2::open(&archive_path, key)
-        .unwrap_or_else(|_| panic!("failed to open archive at {:?}", archive_path));
+    let (archive, _) = open_archive(&archive_path, matches.value_of("key"));
     let folder = archive.open_directory("/").unwrap();
     list_files(folder, "/".as_ref(), 1);
 }
@@ -278,3 +290,90 @@ fn list_files(folder: pk2::fs::Directory, path: &Path, ident_level: usize) {
         }
     }
 }
+
+fn rekey_app() -> App<'static, 'static> {
+    SubCommand::with_name("rekey")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to rekey in place"),
+        )
+        .arg(
+            key_arg()
+                .help("Sets the current blowfish key, detected from the known keys if omitted"),
+        )
+        .arg(
+            Arg::with_name("newkey")
+                .short("n")
+                .long("newkey")
+                .takes_value(true)
+                .required_unless("decrypt")
+                .help("Sets the new blowfish key"),
+        )
+        .arg(
+            Arg::with_name("decrypt")
+                .short("d")
+                .long("decrypt")
+                .conflicts_with("newkey")
+                .help("If passed, removes the encryption instead"),
+        )
+}
+
+fn rekey(matches: &ArgMatches<'static>) {
+    let archive_path = matches.value_of_os("archive").map(Path::new).unwrap();
+    let new_key = matches.value_of("newkey").unwrap_or("");
+    let (mut archive, _) = open_archive(archive_path, matches.value_of("key"));
+    println!("Rekeying {:?}.", archive_path);
+    archive
+        .rekey(new_key)
+        .unwrap_or_else(|e| panic!("failed to rekey archive at {:?}: {}", archive_path, e));
+}
+
+fn diff_app() -> App<'static, 'static> {
+    SubCommand::with_name("diff")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(Arg::with_name("old").required(true).help("Sets the old archive"))
+        .arg(Arg::with_name("new").required(true).help("Sets the new archive"))
+        .arg(key_arg().help(
+            "Sets the blowfish key of both archives, detected from the known keys if omitted",
+        ))
+        .arg(Arg::with_name("json").long("json").help("If passed, prints the changes as JSON"))
+        .arg(
+            Arg::with_name("hash")
+                .long("hash")
+                .help("If passed, compares files by hashes and includes them in the output"),
+        )
+        .arg(
+            Arg::with_name("size-only")
+                .long("size-only")
+                .conflicts_with("hash")
+                .help("If passed, only compares the sizes of files"),
+        )
+}
+
+fn diff(matches: &ArgMatches<'static>) {
+    let old_path = matches.value_of_os("old").map(Path::new).unwrap();
+    let new_path = matches.value_of_os("new").map(Path::new).unwrap();
+    let (old, _) = open_archive(old_path, matches.value_of("key"));
+    let (new, _) = open_archive(new_path, matches.value_of("key"));
+    let options = pk2::DiffOptions::new()
+        .hash_contents(matches.is_present("hash"))
+        .compare_contents(!matches.is_present("size-only"));
+    let diff = pk2::diff_with(&old, &new, &options)
+        .unwrap_or_else(|e| panic!("failed to diff {:?} and {:?}: {}", old_path, new_path, e));
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+    } else if diff.is_empty() {
+        println!("The archives have the same contents.");
+    } else {
+        diff.changes().iter().for_each(|change| println!("{}", change));
+    }
+}
@@ -1,51 +1,299 @@
+#[cfg(feature = "async")]
+pub(crate) mod async_pk2;
+pub mod check;
+mod compact;
+pub(crate) mod diff;
 pub mod fs;
-use self::fs::{Directory, File, FileMut};
+#[cfg(feature = "serde")]
+pub mod manifest;
+pub mod patch;
+mod rekey;
+pub(crate) mod repair;
+mod transaction;
+use self::fs::{Directory, File, FileMut, Glob, Metadata, StreamingFileMut};
+use self::transaction::Journal;
+pub use self::transaction::TransactionStream;
 
-use std::cell::RefCell;
+use std::num::NonZeroU64;
 use std::path::{Component, Path};
 use std::{fs as stdfs, io};
 
 use crate::blowfish::Blowfish;
 use crate::constants::{
-    PK2_CHECKSUM, PK2_CURRENT_DIR_IDENT, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
+    PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
     PK2_ROOT_BLOCK_VIRTUAL,
 };
-use crate::error::{ChainLookupError, ChainLookupResult, OpenError, OpenResult};
-use crate::io::RawIo;
+use crate::error::{
+    ChainLookupError, ChainLookupResult, Context, Error, ErrorKind, Operation, Result,
+};
+#[cfg(feature = "mmap")]
+use crate::io::MmapFile;
+use crate::io::{PositionalFile, RawIo, ReadStream, StreamCell};
+use crate::options::{FormatProfile, Pk2Options};
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
 use crate::raw::block_manager::BlockManager;
-use crate::raw::entry::PackEntry;
+use crate::raw::entry::{DirectoryEntry, NameCodec, PackEntry};
+use crate::raw::free_space::FreeSpaceMap;
 use crate::raw::header::PackHeader;
-use crate::raw::{ChainIndex, StreamOffset};
+use crate::raw::{BlockOffset, ChainIndex, StreamOffset};
 
 pub struct Pk2<B = stdfs::File> {
-    stream: RefCell<B>,
+    stream: StreamCell<B>,
     blowfish: Option<Blowfish>,
     block_manager: BlockManager,
+    free_space: FreeSpaceMap,
+    journal: Option<Journal>,
+    options: Pk2Options,
 }
 
 impl Pk2<stdfs::File> {
     /// Creates a new [`File`](stdfs::File) based archive at the given path.
-    pub fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
+    pub fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::create_new_with(path, key, &Pk2Options::default())
+    }
+
+    /// Creates a new [`File`](stdfs::File) based archive at the given path
+    /// with the given options.
+    pub fn create_new_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let path = path.as_ref();
         let file = stdfs::OpenOptions::new()
             .create_new(true)
             .write(true)
             .read(true)
-            .open(path.as_ref())?;
-        Self::_create_impl(file, key)
+            .open(path)
+            .context(Operation::Create, path)?;
+        let mut this = Self::_create_impl(file, key, options).context(Operation::Create, path)?;
+        this.journal = Some(Journal::new(path));
+        Ok(this)
+    }
+
+    /// Opens an archive at the given path, replaying or discarding the journal
+    /// of a [transaction](Pk2::transaction) that was interrupted while being
+    /// committed.
+    pub fn open<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::open_with(path, key, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path with the given options, see
+    /// [`Pk2::open`].
+    pub fn open_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let journal = Journal::new(path);
+        journal.recover().context(Operation::Open, path)?;
+        let file = stdfs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(path)
+            .context(Operation::Open, path)?;
+        let mut this = Self::_open_in_impl(file, key, options).context(Operation::Open, path)?;
+        this.journal = Some(journal);
+        Ok(this)
+    }
+
+    /// Opens an archive at the given path with the first of the `candidates`
+    /// that matches the archive's key, returning the archive together with
+    /// that key. Fails with [`ErrorKind::InvalidKey`] if none of them matches.
+    /// The key of unencrypted archives is not checked, so they are opened with
+    /// the first candidate, see [`Pk2::is_encrypted`].
+    ///
+    /// [`keys::known`](crate::keys::known) contains the keys of all official
+    /// regions.
+    pub fn open_detect_key<P, I, K>(path: P, candidates: I) -> Result<(Self, K)>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        Self::open_detect_key_with(path, candidates, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path with the given options and the
+    /// first of the `candidates` that matches its key, see
+    /// [`Pk2::open_detect_key`].
+    pub fn open_detect_key_with<P, I, K>(
+        path: P,
+        candidates: I,
+        options: &Pk2Options,
+    ) -> Result<(Self, K)>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let path = path.as_ref();
+        let journal = Journal::new(path);
+        journal.recover().context(Operation::Open, path)?;
+        let mut file = stdfs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(path)
+            .context(Operation::Open, path)?;
+        for key in candidates {
+            io::Seek::rewind(&mut file).context(Operation::Open, path)?;
+            match Self::read_header(&mut file, &key, options.get_profile()) {
+                Ok(_) => {
+                    let mut this =
+                        Self::open_in_with(file, &key, options).context(Operation::Open, path)?;
+                    this.journal = Some(journal);
+                    return Ok((this, key));
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidKey) => continue,
+                Err(e) => return Err(e.with_operation(Operation::Open).with_path(path)),
+            }
+        }
+        Err(Error::from(ErrorKind::InvalidKey).with_operation(Operation::Open).with_path(path))
+    }
+
+    /// Opens an archive at the given path for reading with a [`PositionalFile`]
+    /// backend. Unlike other archives, its files can be read by any number of
+    /// threads at once without them having to take turns.
+    pub fn open_positional<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+    ) -> Result<Pk2<PositionalFile>> {
+        Self::open_positional_with(path, key, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path for reading with a [`PositionalFile`]
+    /// backend and the given options, see [`Pk2::open_positional`].
+    pub fn open_positional_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Pk2<PositionalFile>> {
+        let path = path.as_ref();
+        Journal::new(path).recover().context(Operation::Open, path)?;
+        let file =
+            stdfs::OpenOptions::new().read(true).open(path).context(Operation::Open, path)?;
+        let Pk2 { stream, blowfish, block_manager, free_space, journal, options } =
+            Self::_open_in_impl(file, key, options).context(Operation::Open, path)?;
+        Ok(Pk2 {
+            stream: StreamCell::new(PositionalFile::new(stream.into_inner())),
+            blowfish,
+            block_manager,
+            free_space,
+            journal,
+            options,
+        })
+    }
+
+    /// Opens an archive at the given path as a read-only memory map. Files of
+    /// the returned archive can be borrowed straight from the mapping with
+    /// [`File::as_bytes`].
+    ///
+    /// # Safety
+    ///
+    /// The archive file must not be modified by this or any other process
+    /// while the archive is open, see [`MmapFile::map`].
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+    ) -> Result<Pk2<MmapFile>> {
+        Self::open_mmap_with(path, key, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path as a read-only memory map with the
+    /// given options, see [`Pk2::open_mmap`].
+    ///
+    /// # Safety
+    ///
+    /// See [`Pk2::open_mmap`].
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Pk2<MmapFile>> {
+        let path = path.as_ref();
+        Journal::new(path).recover().context(Operation::Open, path)?;
+        let file =
+            stdfs::OpenOptions::new().read(true).open(path).context(Operation::Open, path)?;
+        let mmap = MmapFile::map(&file).context(Operation::Open, path)?;
+        let data = mmap.slice(StreamOffset(0), usize::MAX);
+        let Pk2 { blowfish, block_manager, free_space, journal, options, .. } =
+            Pk2::_open_in_impl(io::Cursor::new(data), key, options)
+                .context(Operation::Open, path)?;
+        Ok(Pk2 {
+            stream: StreamCell::new(mmap),
+            blowfish,
+            block_manager,
+            free_space,
+            journal,
+            options,
+        })
     }
 
-    /// Opens an archive at the given path.
-    pub fn open<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
-        let file = stdfs::OpenOptions::new().write(true).read(true).open(path)?;
-        Self::_open_in_impl(file, key)
+    /// Opens an archive at the given path, parsing only its root directory.
+    /// All other directories are loaded the first time a path goes through
+    /// them, which makes opening big archives to access a few files cheap.
+    ///
+    /// As the unused space of the archive is not known without the full index,
+    /// only space freed after opening is reused for new data.
+    /// [`Pk2::compact`] loads the full index before defragmenting the archive,
+    /// as does deleting a directory, since the chains of its tree may also be
+    /// linked from other directories.
+    pub fn open_lazy<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::open_lazy_with(path, key, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path with the given options, parsing only
+    /// its root directory, see [`Pk2::open_lazy`].
+    pub fn open_lazy_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        Self::_open_lazy_impl(path, key, options).context(Operation::Open, path)
+    }
+
+    fn _open_lazy_impl<K: AsRef<[u8]>>(path: &Path, key: K, options: &Pk2Options) -> Result<Self> {
+        let journal = Journal::new(path);
+        journal.recover()?;
+        let mut file = stdfs::OpenOptions::new().write(true).read(true).open(path)?;
+        let blowfish = Self::read_header(&mut file, key, options.get_profile())?;
+        // chains are loaded through a handle of their own, so that loading them does not
+        // require access to the stream files are read from
+        let index = stdfs::OpenOptions::new().read(true).open(path)?;
+        let block_manager = BlockManager::new_lazy(blowfish.as_ref(), options.names(), index)?;
+        let free_space = FreeSpaceMap::fully_used(crate::io::stream_len(&mut file)?);
+        Ok(Pk2 {
+            stream: StreamCell::new(file),
+            blowfish,
+            block_manager,
+            free_space,
+            journal: Some(journal),
+            options: options.clone(),
+        })
     }
 
     /// Opens an archive at the given path with its file index sorted. This creates a read only
     /// archive, trying to write to it will result in an error.
-    pub fn open_sorted<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> OpenResult<Self> {
-        let file = stdfs::OpenOptions::new().read(true).open(path)?;
-        let mut this = Self::_open_in_impl(file, key)?;
+    pub fn open_sorted<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::open_sorted_with(path, key, &Pk2Options::default())
+    }
+
+    /// Opens an archive at the given path with the given options and its file
+    /// index sorted, see [`Pk2::open_sorted`].
+    pub fn open_sorted_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        Journal::new(path).recover().context(Operation::Open, path)?;
+        let file =
+            stdfs::OpenOptions::new().read(true).open(path).context(Operation::Open, path)?;
+        let mut this = Self::_open_in_impl(file, key, options).context(Operation::Open, path)?;
         this.block_manager.sort();
         Ok(this)
     }
@@ -53,14 +301,14 @@ impl Pk2<stdfs::File> {
 
 impl Pk2<io::Cursor<Vec<u8>>> {
     /// Creates a new archive in memory.
-    pub fn create_new_in_memory<K: AsRef<[u8]>>(
-        key: K,
-    ) -> Result<Self, crate::blowfish::InvalidKey> {
-        Self::_create_impl(io::Cursor::new(Vec::with_capacity(4096)), key).map_err(|e| {
-            debug_assert!(matches!(&e, OpenError::InvalidKey));
-            // the only error that can actually occur here is an InvalidKey error
-            crate::blowfish::InvalidKey
-        })
+    pub fn create_new_in_memory<K: AsRef<[u8]>>(key: K) -> Result<Self> {
+        Self::create_new_in_memory_with(key, &Pk2Options::default())
+    }
+
+    /// Creates a new archive in memory with the given options.
+    pub fn create_new_in_memory_with<K: AsRef<[u8]>>(key: K, options: &Pk2Options) -> Result<Self> {
+        let stream = io::Cursor::new(Vec::with_capacity(4096));
+        Self::_create_impl(stream, key, options).operation(Operation::Create)
     }
 }
 
@@ -74,26 +322,52 @@ impl<B> Pk2<B>
 where
     B: io::Read + io::Seek,
 {
-    pub fn open_in<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        stream.seek(io::SeekFrom::Start(0))?;
-        Self::_open_in_impl(stream, key)
-    }
-
-    fn _open_in_impl<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        let header = PackHeader::from_reader(&mut stream)?;
-        header.validate_sig()?;
-        let blowfish = if header.encrypted {
-            let bf = Blowfish::new(key.as_ref())?;
-            let mut checksum = *PK2_CHECKSUM;
-            let _ = bf.encrypt(&mut checksum);
-            header.verify(checksum)?;
-            Some(bf)
-        } else {
-            None
-        };
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut stream)?;
+    pub fn open_in<K: AsRef<[u8]>>(stream: B, key: K) -> Result<Self> {
+        Self::open_in_with(stream, key, &Pk2Options::default())
+    }
 
-        Ok(Pk2 { stream: RefCell::new(stream), blowfish, block_manager })
+    pub fn open_in_with<K: AsRef<[u8]>>(
+        mut stream: B,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        stream.seek(io::SeekFrom::Start(0)).operation(Operation::Open)?;
+        Self::_open_in_impl(stream, key, options).operation(Operation::Open)
+    }
+
+    fn _open_in_impl<K: AsRef<[u8]>>(mut stream: B, key: K, options: &Pk2Options) -> Result<Self> {
+        let blowfish = Self::read_header(&mut stream, key, options.get_profile())?;
+        let block_manager = BlockManager::new(blowfish.as_ref(), options.names(), &mut stream)?;
+        let free_space = FreeSpaceMap::new(&block_manager, crate::io::stream_len(&mut stream)?);
+
+        Ok(Pk2 {
+            stream: StreamCell::new(stream),
+            blowfish,
+            block_manager,
+            free_space,
+            journal: None,
+            options: options.clone(),
+        })
+    }
+}
+
+impl<B> Pk2<B> {
+    /// Reads and validates the header, returning the blowfish instance for
+    /// the blocks of encrypted archives.
+    fn read_header<R: io::Read, K: AsRef<[u8]>>(
+        stream: &mut R,
+        key: K,
+        profile: &FormatProfile,
+    ) -> Result<Option<Blowfish>> {
+        let header = PackHeader::from_reader(stream)?;
+        header.validate_sig(profile)?;
+        if header.encrypted {
+            let bf = Blowfish::new(key.as_ref(), &profile.salt)?;
+            header.verify(&bf, profile)?;
+            Ok(Some(bf))
+        } else {
+            Ok(None)
+        }
     }
 }
 
@@ -101,26 +375,50 @@ impl<B> Pk2<B>
 where
     B: io::Read + io::Write + io::Seek,
 {
-    pub fn create_new_in<K: AsRef<[u8]>>(mut stream: B, key: K) -> OpenResult<Self> {
-        stream.seek(io::SeekFrom::Start(0))?;
-        Self::_create_impl(stream, key)
+    pub fn create_new_in<K: AsRef<[u8]>>(stream: B, key: K) -> Result<Self> {
+        Self::create_new_in_with(stream, key, &Pk2Options::default())
+    }
+
+    pub fn create_new_in_with<K: AsRef<[u8]>>(
+        mut stream: B,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        stream.seek(io::SeekFrom::Start(0)).operation(Operation::Create)?;
+        Self::_create_impl(stream, key, options).operation(Operation::Create)
     }
 
-    fn _create_impl<K: AsRef<[u8]>>(stream: B, key: K) -> OpenResult<Self> {
+    fn _create_impl<K: AsRef<[u8]>>(stream: B, key: K, options: &Pk2Options) -> Result<Self> {
+        let profile = options.get_profile();
         let (header, mut stream, blowfish) = if key.as_ref().is_empty() {
-            (PackHeader::default(), stream, None)
+            (PackHeader::new(profile), stream, None)
         } else {
-            let bf = Blowfish::new(key.as_ref())?;
-            (PackHeader::new_encrypted(&bf), stream, Some(bf))
+            let bf = Blowfish::new(key.as_ref(), &profile.salt)?;
+            (PackHeader::new_encrypted(&bf, profile), stream, Some(bf))
         };
 
         header.to_writer(&mut stream)?;
         let mut block = PackBlock::default();
         block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, PK2_ROOT_BLOCK, None);
-        crate::io::write_block(blowfish.as_ref(), &mut stream, PK2_ROOT_BLOCK.into(), &block)?;
+        let names = options.names();
+        crate::io::write_block(
+            blowfish.as_ref(),
+            names,
+            &mut stream,
+            PK2_ROOT_BLOCK.into(),
+            &block,
+        )?;
 
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut stream)?;
-        Ok(Pk2 { stream: RefCell::new(stream), blowfish, block_manager })
+        let block_manager = BlockManager::new(blowfish.as_ref(), names, &mut stream)?;
+        let free_space = FreeSpaceMap::new(&block_manager, crate::io::stream_len(&mut stream)?);
+        Ok(Pk2 {
+            stream: StreamCell::new(stream),
+            blowfish,
+            block_manager,
+            free_space,
+            journal: None,
+            options: options.clone(),
+        })
     }
 }
 
@@ -144,7 +442,7 @@ impl<B> Pk2<B> {
     fn root_resolve_path_to_entry_and_parent<P: AsRef<Path>>(
         &self,
         path: P,
-    ) -> ChainLookupResult<(ChainIndex, usize, &PackEntry)> {
+    ) -> Result<(ChainIndex, usize, &PackEntry)> {
         self.block_manager
             .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, check_root(path.as_ref())?)
     }
@@ -165,14 +463,32 @@ impl<B> Pk2<B> {
 }
 
 impl<B> Pk2<B> {
-    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> ChainLookupResult<File<B>> {
+    /// Enables or disables an index of the names of every directory's
+    /// entries. With the index enabled looking up a path takes time
+    /// proportional to its number of components instead of the sizes of the
+    /// directories along it, at the cost of some memory.
+    pub fn set_name_index(&mut self, enabled: bool) {
+        self.block_manager.set_name_index(enabled);
+    }
+
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> Result<File<'_, B>> {
+        let path = path.as_ref();
+        self.open_file_impl(path).context(Operation::OpenFile, path)
+    }
+
+    fn open_file_impl(&self, path: &Path) -> Result<File<'_, B>> {
         let (chain, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
         Self::is_file(entry)?;
         Ok(File::new(self, chain, entry_idx))
     }
 
-    pub fn open_directory<P: AsRef<Path>>(&self, path: P) -> ChainLookupResult<Directory<B>> {
-        let path = check_root(path.as_ref())?;
+    pub fn open_directory<P: AsRef<Path>>(&self, path: P) -> Result<Directory<'_, B>> {
+        let path = path.as_ref();
+        self.open_directory_impl(path).context(Operation::OpenDirectory, path)
+    }
+
+    fn open_directory_impl(&self, path: &Path) -> Result<Directory<'_, B>> {
+        let path = check_root(path)?;
         let (chain, entry_idx) =
             match self.block_manager.resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path) {
                 Ok((chain, entry_idx, entry)) => {
@@ -180,36 +496,63 @@ impl<B> Pk2<B> {
                     (chain, entry_idx)
                 }
                 // path was just root
-                Err(ChainLookupError::InvalidPath) => (PK2_ROOT_BLOCK_VIRTUAL, 0),
+                Err(e) if e.is_lookup(ChainLookupError::InvalidPath) => (PK2_ROOT_BLOCK_VIRTUAL, 0),
                 Err(e) => return Err(e),
             };
         Ok(Directory::new(self, chain, entry_idx))
     }
 
-    pub fn open_root_dir(&self) -> Directory<B> {
+    pub fn open_root_dir(&self) -> Directory<'_, B> {
         Directory::new(self, PK2_ROOT_BLOCK_VIRTUAL, 0)
     }
 
+    /// Returns the metadata of the file or directory at the given path.
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> Result<Metadata> {
+        let path = path.as_ref();
+        let relative = check_root(path).context(Operation::Metadata, path)?;
+        let root = self.open_root_dir();
+        if relative.as_os_str().is_empty() {
+            return Ok(root.metadata());
+        }
+        root.open_impl(relative).map(|entry| entry.metadata()).context(Operation::Metadata, path)
+    }
+
+    /// Returns an iterator over all entries whose path matches the glob
+    /// `pattern`, which has to start with `/`. See [`Glob`] for the syntax.
+    pub fn glob(&self, pattern: &str) -> Result<Glob<'_, B>> {
+        let relative = pattern
+            .strip_prefix('/')
+            .ok_or(ChainLookupError::InvalidPath)
+            .context(Operation::Glob, pattern)?;
+        Ok(self.open_root_dir().glob(relative))
+    }
+
     /// Invokes cb on every file in the sub directories of `base`, including
     /// files inside of its subdirectories. Cb gets invoked with its
     /// relative path to `base` and the file object.
+    ///
+    /// Errors returned by `cb` carry the path of the file, including `base`.
     pub fn for_each_file(
         &self,
         base: impl AsRef<Path>,
         cb: impl FnMut(&Path, File<B>) -> io::Result<()>,
-    ) -> io::Result<()> {
-        self.open_directory(base)?.for_each_file(cb)
+    ) -> Result<()> {
+        let base = base.as_ref();
+        self.open_directory(base)?.for_each_file_in(base, cb)
     }
 }
 
 impl<B> Pk2<B>
 where
-    B: io::Read + io::Seek,
+    B: ReadStream,
 {
-    pub fn read<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let path = path.as_ref();
         let mut file = self.open_file(path)?;
         let mut buf = Vec::with_capacity(file.size() as usize);
-        std::io::Read::read_to_end(&mut file, &mut buf)?;
+        std::io::Read::read_to_end(&mut file, &mut buf)
+            .offset(file.entry().pos_data().0)
+            .context(Operation::Read, path)?;
         Ok(buf)
     }
 }
@@ -218,46 +561,317 @@ impl<B> Pk2<B>
 where
     B: io::Read + io::Write + io::Seek,
 {
-    pub fn open_file_mut<P: AsRef<Path>>(&mut self, path: P) -> ChainLookupResult<FileMut<B>> {
-        let (chain, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
-        Self::is_file(entry)?;
-        Ok(FileMut::new(self, chain, entry_idx))
+    pub fn open_file_mut<P: AsRef<Path>>(&mut self, path: P) -> Result<FileMut<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx, entry) =
+            self.root_resolve_path_to_entry_and_parent(path).context(Operation::OpenFile, path)?;
+        Self::is_file(entry).context(Operation::OpenFile, path)?;
+        Ok(FileMut::new(self, chain, entry_idx, false))
     }
 
-    /// Currently only replaces the entry with an empty one making the data
-    /// inaccessible by normal means
-    pub fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+    /// Replaces the entry with an empty one, releasing the space of its data
+    /// for reuse by later writes.
+    pub fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.delete_file_impl(path).context(Operation::DeleteFile, path)
+    }
+
+    fn delete_file_impl(&mut self, path: &Path) -> io::Result<()> {
         let (chain_index, entry_idx, entry) = self
             .block_manager
-            .resolve_path_to_entry_and_parent_mut(PK2_ROOT_BLOCK, check_root(path.as_ref())?)?;
+            .resolve_path_to_entry_and_parent_mut(PK2_ROOT_BLOCK, check_root(path)?)?;
         Self::is_file(entry)?;
-        entry.clear();
+        if let PackEntry::File(file) = entry.clear() {
+            self.free_space.free(file.pos_data(), file.size() as u64);
+        }
 
         crate::io::write_chain_entry(
             self.blowfish.as_ref(),
-            &mut *self.stream.borrow_mut(),
-            self.get_chain(chain_index).unwrap(),
+            self.options.names(),
+            self.stream.get_mut(),
+            self.block_manager.get(chain_index).unwrap(),
             entry_idx,
         )?;
         Ok(())
     }
 
-    pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileMut<B>> {
-        let path = check_root(path.as_ref())?;
+    /// Removes an empty directory, failing with
+    /// [`DirectoryNotEmpty`](io::ErrorKind::DirectoryNotEmpty) if it still has
+    /// children.
+    pub fn delete_directory<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.delete_directory_impl(path, false).context(Operation::DeleteDirectory, path)
+    }
+
+    /// Removes a directory after removing all of its contents, releasing the
+    /// blocks of every contained chain as well as the data of all contained
+    /// files.
+    pub fn remove_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.delete_directory_impl(path, true).context(Operation::DeleteDirectory, path)
+    }
+
+    fn delete_directory_impl(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        let (chain_index, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
+        Self::is_dir(entry)?;
+        let dir = entry.as_directory().unwrap();
+        if !dir.is_normal_link() {
+            return Err(ChainLookupError::InvalidPath.into());
+        }
+        let children = dir.children_position();
+        if !recursive {
+            let chain = self.get_chain(children).ok_or(ChainLookupError::InvalidChainIndex)?;
+            if chain.entries().any(|entry| {
+                entry.as_directory().map_or(entry.is_file(), DirectoryEntry::is_normal_link)
+            }) {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into());
+            }
+        }
+
+        self.get_entry_mut(chain_index, entry_idx).unwrap().clear();
+        crate::io::write_chain_entry(
+            self.blowfish.as_ref(),
+            self.options.names(),
+            self.stream.get_mut(),
+            self.block_manager.get(chain_index).unwrap(),
+            entry_idx,
+        )?;
+        self.release_chain_recursive(children)
+    }
+
+    /// Turns a lazy archive into one with its whole index loaded, whose unused
+    /// space is known as well.
+    fn load_full_index(&mut self) -> Result<()> {
+        if self.block_manager.is_lazy() {
+            self.block_manager.load_all()?;
+            let stream_len = self.free_space.stream_len();
+            self.free_space = FreeSpaceMap::new(&self.block_manager, stream_len);
+        }
+        Ok(())
+    }
+
+    /// Removes the chain and all chains of its subdirectories from the block
+    /// manager, marking their blocks and file data as free. The blocks are
+    /// overwritten with empty ones, so that the deleted tree cannot be found
+    /// in the free space anymore.
+    ///
+    /// Chains that are still linked from another directory are kept. As these
+    /// links are only known for the whole index, lazy archives load it first.
+    fn release_chain_recursive(&mut self, chain: ChainIndex) -> io::Result<()> {
+        self.load_full_index()?;
+        let mut chains = vec![chain];
+        while let Some(chain) = chains.pop() {
+            if !self.block_manager.unlink(chain) {
+                continue;
+            }
+            let Some(chain) = self.block_manager.remove(chain) else { continue };
+            for entry in chain.entries() {
+                match entry {
+                    PackEntry::File(file) => {
+                        self.free_space.free(file.pos_data(), file.size() as u64)
+                    }
+                    PackEntry::Directory(dir) if dir.is_normal_link() => {
+                        chains.push(dir.children_position())
+                    }
+                    _ => (),
+                }
+            }
+            for offset in chain.block_offsets() {
+                crate::io::write_block(
+                    self.blowfish.as_ref(),
+                    self.options.names(),
+                    self.stream.get_mut(),
+                    offset,
+                    &PackBlock::default(),
+                )?;
+                self.free_space.free(StreamOffset(offset.0), PK2_FILE_BLOCK_SIZE as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames the file or directory at `from` to `to`, moving it into
+    /// another directory if the parents of both paths differ. The parent
+    /// directory of `to` has to exist already while `to` itself must not.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        let from = from.as_ref();
+        self.rename_impl(from, to.as_ref()).context(Operation::Rename, from)
+    }
+
+    fn rename_impl(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        use crate::io::write_chain_entry;
+        let (from, to) = (check_root(from)?, check_root(to)?);
+        let (src_chain, src_idx, entry) =
+            self.block_manager.resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, from)?;
+        let moved_chain = match entry.as_directory() {
+            Some(dir) if !dir.is_normal_link() => return Err(ChainLookupError::InvalidPath.into()),
+            dir => dir.map(DirectoryEntry::children_position),
+        };
+        let (dst_chain, name) = self.block_manager.resolve_path_to_parent(PK2_ROOT_BLOCK, to)?;
+        if name == PK2_CURRENT_DIR_IDENT || name == PK2_PARENT_DIR_IDENT {
+            return Err(ChainLookupError::InvalidPath.into());
+        }
+        self.options.names().validate(name)?;
+        match self.block_manager.resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, to) {
+            // renaming an entry to a differently cased version of its own name is fine
+            Ok((chain, idx, _)) if (chain, idx) != (src_chain, src_idx) => {
+                return Err(io::ErrorKind::AlreadyExists.into())
+            }
+            Ok(_) => (),
+            Err(e) if e.is_lookup(ChainLookupError::NotFound) => (),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(moved_chain) = moved_chain {
+            if self.is_chain_ancestor_of(moved_chain, dst_chain) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot move a directory into itself",
+                ));
+            }
+        }
+
+        let blowfish = self.blowfish.as_ref();
+        let names = self.options.names();
+        let stream = self.stream.get_mut();
+        if src_chain == dst_chain {
+            let chain = self.block_manager.get_mut(src_chain).unwrap();
+            chain[src_idx].set_name(name);
+            return write_chain_entry(blowfish, names, stream, chain, src_idx);
+        }
+
+        let mut entry = self.block_manager.get(src_chain).unwrap()[src_idx].clone();
+        entry.set_name(name);
+        // insert the entry into its new parent first, so that a failure in between leaves us with
+        // a duplicate instead of a lost entry
+        let chain =
+            self.block_manager.get_mut(dst_chain).ok_or(ChainLookupError::InvalidChainIndex)?;
+        let dst_idx = Self::find_or_allocate_empty_entry(
+            &mut self.free_space,
+            blowfish,
+            names,
+            stream,
+            chain,
+        )?;
+        let slot = &mut chain[dst_idx];
+        entry.set_next_block(BlockOffset(slot.next_block().map_or(0, NonZeroU64::get)));
+        *slot = entry;
+        write_chain_entry(blowfish, names, &mut *stream, chain, dst_idx)?;
+
+        let chain = self.block_manager.get_mut(src_chain).unwrap();
+        chain[src_idx].clear();
+        write_chain_entry(blowfish, names, &mut *stream, chain, src_idx)?;
+
+        if let Some(moved_chain) = moved_chain {
+            let chain = self
+                .block_manager
+                .get_mut(moved_chain)
+                .ok_or(ChainLookupError::InvalidChainIndex)?;
+            let parent_link = chain.entries_mut().enumerate().find_map(|(idx, entry)| {
+                entry.as_directory_mut().filter(|dir| dir.is_parent_link()).map(|dir| (idx, dir))
+            });
+            if let Some((idx, dir)) = parent_link {
+                dir.pos_children = dst_chain;
+                write_chain_entry(blowfish, names, stream, chain, idx)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether `chain` is `ancestor` or one of its subdirectories by
+    /// following the parent links of `chain`.
+    fn is_chain_ancestor_of(&self, ancestor: ChainIndex, mut chain: ChainIndex) -> bool {
+        // guard against parent links forming a cycle in corrupted archives
+        let mut visited = std::collections::HashSet::new();
+        while chain != ancestor {
+            if !visited.insert(chain) {
+                return false;
+            }
+            match self
+                .get_chain(chain)
+                .map(|chain| chain.find_block_chain_index_of(PK2_PARENT_DIR_IDENT))
+            {
+                Some(Ok(parent)) => chain = parent,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> Result<FileMut<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) =
+            self.create_file_entry(path).context(Operation::CreateFile, path)?;
+        // the entry has to be written on flush even if no data gets written
+        Ok(FileMut::new(self, chain, entry_idx, true))
+    }
+
+    /// Creates a file whose data is written straight to the end of the stream
+    /// as it arrives instead of being buffered in memory, see
+    /// [`StreamingFileMut`].
+    pub fn create_file_streaming<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<StreamingFileMut<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) =
+            self.create_file_entry(path).context(Operation::CreateFile, path)?;
+        Ok(StreamingFileMut::new(self, chain, entry_idx))
+    }
+
+    /// Creates an empty directory along with all of its missing parents,
+    /// failing with [`AlreadyExists`](io::ErrorKind::AlreadyExists) if it
+    /// exists already.
+    pub fn create_directory<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.create_directory_impl(path).context(Operation::CreateDirectory, path)
+    }
+
+    fn create_directory_impl(&mut self, path: &Path) -> io::Result<()> {
+        let path = check_root(path)?;
+        let dir_name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .ok_or(ChainLookupError::InvalidPath)?;
+        let (chain, entry_idx) = Self::create_entry_at(
+            &mut self.block_manager,
+            &mut self.free_space,
+            self.blowfish.as_ref(),
+            self.options.names(),
+            self.stream.get_mut(),
+            PK2_ROOT_BLOCK,
+            path,
+        )?;
+        let block_chain = crate::io::allocate_new_block_chain(
+            self.blowfish.as_ref(),
+            self.options.names(),
+            self.stream.get_mut(),
+            &mut self.free_space,
+            self.block_manager.get_mut(chain).unwrap(),
+            dir_name,
+            entry_idx,
+        )?;
+        self.block_manager.insert(block_chain.chain_index(), block_chain);
+        Ok(())
+    }
+
+    fn create_file_entry(&mut self, path: &Path) -> io::Result<(ChainIndex, usize)> {
+        let path = check_root(path)?;
         let file_name = path
             .file_name()
             .and_then(std::ffi::OsStr::to_str)
             .ok_or(ChainLookupError::InvalidPath)?;
         let (chain, entry_idx) = Self::create_entry_at(
             &mut self.block_manager,
+            &mut self.free_space,
             self.blowfish.as_ref(),
-            &mut *self.stream.borrow_mut(),
+            self.options.names(),
+            self.stream.get_mut(),
             PK2_ROOT_BLOCK,
             path,
         )?;
         let entry = self.get_entry_mut(chain, entry_idx).unwrap();
         *entry = PackEntry::new_file(file_name, StreamOffset(0), 0, entry.next_block());
-        Ok(FileMut::new(self, chain, entry_idx))
+        Ok((chain, entry_idx))
     }
 
     /// This function traverses the whole path creating anything that does not
@@ -266,44 +880,46 @@ where
     /// existing path might still create new directories that arent actually being used.
     fn create_entry_at(
         block_manager: &mut BlockManager,
+        free_space: &mut FreeSpaceMap,
         blowfish: Option<&Blowfish>,
+        names: NameCodec,
         mut stream: &mut B,
         chain: ChainIndex,
         path: &Path,
     ) -> io::Result<(ChainIndex, usize)> {
-        use crate::io::{allocate_empty_block, allocate_new_block_chain, write_chain_entry};
+        use crate::io::allocate_new_block_chain;
         let (mut current_chain_index, mut components) = block_manager
             .validate_dir_path_until(chain, path)?
             .ok_or_else(|| io::Error::from(io::ErrorKind::AlreadyExists))?;
+        // validate all names before creating anything, so that an invalid name does not leave
+        // behind the directories in front of it
+        for component in components.clone() {
+            if let Component::Normal(p) = component {
+                names.validate(p.to_str().ok_or(ChainLookupError::InvalidPath)?)?;
+            }
+        }
         while let Some(component) = components.next() {
             match component {
                 Component::Normal(p) => {
                     let current_chain = block_manager
                         .get_mut(current_chain_index)
                         .ok_or(ChainLookupError::InvalidChainIndex)?;
-                    let empty_pos = current_chain.entries().position(PackEntry::is_empty);
-                    let chain_entry_idx = if let Some(idx) = empty_pos {
-                        idx
-                    } else {
-                        // current chain is full so create a new block and append it
-                        let (offset, block) = allocate_empty_block(blowfish, &mut stream)?;
-                        let chain_entry_idx = current_chain.num_entries();
-                        current_chain.push_and_link(offset, block);
-                        write_chain_entry(
-                            blowfish,
-                            &mut stream,
-                            current_chain,
-                            chain_entry_idx - 1,
-                        )?;
-                        chain_entry_idx
-                    };
+                    let chain_entry_idx = Self::find_or_allocate_empty_entry(
+                        free_space,
+                        blowfish,
+                        names,
+                        stream,
+                        current_chain,
+                    )?;
                     // Are we done after this? if not, create a new blockchain since this is a new
                     // directory
                     if components.peek().is_some() {
                         let dir_name = p.to_str().ok_or(ChainLookupError::InvalidPath)?;
                         let block_chain = allocate_new_block_chain(
                             blowfish,
+                            names,
                             &mut stream,
+                            free_space,
                             current_chain,
                             dir_name,
                             chain_entry_idx,
@@ -328,6 +944,32 @@ where
     }
 }
 
+impl<B> Pk2<B>
+where
+    B: io::Write + io::Seek,
+{
+    /// Returns the index of an empty entry in the given chain, appending a new
+    /// block to the chain if it is full.
+    fn find_or_allocate_empty_entry(
+        free_space: &mut FreeSpaceMap,
+        blowfish: Option<&Blowfish>,
+        names: NameCodec,
+        mut stream: &mut B,
+        chain: &mut PackBlockChain,
+    ) -> io::Result<usize> {
+        if let Some(idx) = chain.entries().position(PackEntry::is_empty) {
+            return Ok(idx);
+        }
+        // chain is full so create a new block and append it
+        let (offset, block) =
+            crate::io::allocate_empty_block(blowfish, names, &mut stream, free_space)?;
+        let chain_entry_idx = chain.num_entries();
+        chain.push_and_link(offset, block);
+        crate::io::write_chain_entry(blowfish, names, &mut stream, chain, chain_entry_idx - 1)?;
+        Ok(chain_entry_idx)
+    }
+}
+
 fn check_root(path: &Path) -> ChainLookupResult<&Path> {
     path.strip_prefix("/").map_err(|_| ChainLookupError::InvalidPath)
 }
@@ -335,13 +977,622 @@ fn check_root(path: &Path) -> ChainLookupResult<&Path> {
 #[cfg(test)]
 mod test {
     use std::io;
+
+    use crate::ErrorKind;
+
     #[test]
     fn create_already_existing() {
         let mut archive = super::Pk2::create_new_in_memory("").unwrap();
         archive.create_file("/test/foo.baz").unwrap();
         match archive.create_file("/test/foo.baz") {
-            Err(e) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            Err(e) => assert_eq!(e.io_kind(), io::ErrorKind::AlreadyExists),
             Ok(_) => panic!("file was created twice?"),
         };
     }
+
+    #[test]
+    fn reuse_freed_space() {
+        use std::io::{Seek, SeekFrom, Write};
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/test/foo.baz").unwrap().write_all(&[1; 4096]).unwrap();
+        archive.create_file("/test/bar.baz").unwrap().write_all(&[2; 1024]).unwrap();
+        let len = archive.stream.lock().get_ref().len();
+
+        archive.delete_file("/test/foo.baz").unwrap();
+        archive.create_file("/test/qux.baz").unwrap().write_all(&[3; 4000]).unwrap();
+        // rewriting with more data moves the file into the remaining hole
+        let mut file = archive.open_file_mut("/test/bar.baz").unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[2; 64]).unwrap();
+        drop(file);
+        assert_eq!(archive.stream.lock().get_ref().len(), len);
+        assert_eq!(archive.read("/test/qux.baz").unwrap(), [3; 4000]);
+        assert_eq!(archive.read("/test/bar.baz").unwrap(), [2; 1088]);
+
+        let archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        assert_eq!(archive.free_space.free_bytes(), 4096 + 1024 - 4000 - 1088);
+    }
+
+    #[test]
+    fn delete_file_past_end() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/file").unwrap().write_all(&[1; 100]).unwrap();
+        let len = archive.free_space.stream_len();
+        let (chain, idx, _) = archive.root_resolve_path_to_entry_and_parent("/file").unwrap();
+        let file = archive.get_chain_mut(chain).unwrap()[idx].as_file_mut().unwrap();
+        file.size = u32::MAX;
+        archive.delete_file("/file").unwrap();
+        assert_eq!(archive.free_space.stream_len(), len);
+        assert!(archive
+            .free_space
+            .holes()
+            .all(|(super::StreamOffset(offset), hole)| offset + hole <= len));
+    }
+
+    #[test]
+    fn remove_dir_all_clears_blocks() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("169841").unwrap();
+        archive.create_file("/gone/sub/secret").unwrap().write_all(&[1; 100]).unwrap();
+        let chains = ["/gone", "/gone/sub"].map(|path| {
+            super::BlockOffset(archive.open_directory(path).unwrap().metadata().pos_data())
+        });
+        archive.remove_dir_all("/gone").unwrap();
+        let names = archive.options.names();
+        for offset in chains {
+            let stream = &mut *archive.stream.lock();
+            let block =
+                crate::io::read_block_at(archive.blowfish.as_ref(), names, stream, offset).unwrap();
+            assert!(block.entries().all(super::PackEntry::is_empty));
+        }
+    }
+
+    #[test]
+    fn remove_dir_all_keeps_shared_chains() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 100]).unwrap();
+        archive.create_directory("/b").unwrap();
+        let a = archive.open_directory("/a").unwrap().metadata().pos_data();
+        // link `/b` to the chain of `/a` as well
+        let (chain, idx, _) = archive.root_resolve_path_to_entry_and_parent("/b").unwrap();
+        archive.get_chain_mut(chain).unwrap()[idx].as_directory_mut().unwrap().pos_children =
+            super::ChainIndex(a);
+        let names = archive.options.names();
+        let stream = archive.stream.get_mut();
+        crate::io::write_chain_entry(
+            None,
+            names,
+            stream,
+            archive.block_manager.get(chain).unwrap(),
+            idx,
+        )
+        .unwrap();
+
+        let mut archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        archive.remove_dir_all("/b").unwrap();
+        // nothing may be allocated in the space that `/a` still uses
+        archive.create_file("/c").unwrap().write_all(&[2; 5000]).unwrap();
+        assert_eq!(archive.read("/a/file").unwrap(), [1; 100]);
+        let archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        assert_eq!(archive.read("/a/file").unwrap(), [1; 100]);
+        assert_eq!(archive.read("/c").unwrap(), [2; 5000]);
+        assert!(archive.check().unwrap().is_ok());
+    }
+
+    #[test]
+    fn delete_directory() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/keep/file").unwrap().write_all(&[0; 100]).unwrap();
+        archive.create_file("/effect/a/b/file").unwrap().write_all(&[1; 100]).unwrap();
+        archive.create_file("/effect/c").unwrap().write_all(&[2; 100]).unwrap();
+        let len = archive.stream.lock().get_ref().len();
+
+        match archive.delete_directory("/effect") {
+            Err(e) => assert_eq!(e.io_kind(), io::ErrorKind::DirectoryNotEmpty),
+            Ok(_) => panic!("deleted a non-empty directory"),
+        }
+        archive.remove_dir_all("/effect").unwrap();
+        assert!(archive.open_directory("/effect").is_err());
+        assert!(archive.open_file("/effect/c").is_err());
+        assert_eq!(archive.read("/keep/file").unwrap(), [0; 100]);
+
+        archive.create_file("/effect/a/b/file").unwrap().write_all(&[1; 100]).unwrap();
+        archive.create_file("/effect/c").unwrap().write_all(&[2; 100]).unwrap();
+        assert_eq!(archive.stream.lock().get_ref().len(), len);
+
+        archive.delete_file("/effect/a/b/file").unwrap();
+        archive.delete_directory("/effect/a/b").unwrap();
+        assert!(archive.open_directory("/effect/a/b").is_err());
+        assert!(archive.open_directory("/effect/a").is_ok());
+    }
+
+    #[test]
+    fn positional_reads_from_threads() {
+        use std::io::{Read, Write};
+        fn assert_sync<T: Sync>(_: &T) {}
+
+        let path = std::env::temp_dir().join(format!("pk2_positional_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = super::Pk2::create_new(&path, "169841").unwrap();
+        for i in 0..8u8 {
+            archive.create_file(format!("/dir/{}", i)).unwrap().write_all(&[i; 5000]).unwrap();
+        }
+        drop(archive);
+
+        let archive = super::Pk2::open_positional(&path, "169841").unwrap();
+        assert_sync(&archive);
+        let dir = archive.open_directory("/dir").unwrap();
+        std::thread::scope(|s| {
+            for file in dir.files() {
+                s.spawn(move || {
+                    let mut file = file;
+                    let expected = file.name().parse::<u8>().unwrap();
+                    for _ in 0..50 {
+                        let mut buf = Vec::new();
+                        file.read_to_end(&mut buf).unwrap();
+                        assert_eq!(buf, [expected; 5000]);
+                        io::Seek::seek(&mut file, io::SeekFrom::Start(0)).unwrap();
+                    }
+                });
+            }
+        });
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_detect_key() {
+        let path = std::env::temp_dir().join(format!("pk2_detect_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(super::Pk2::create_new(&path, crate::keys::ISRO).unwrap());
+
+        let candidates = [&b""[..], b"wrong"].into_iter().chain(crate::keys::known());
+        let (archive, key) = super::Pk2::open_detect_key(&path, candidates).unwrap();
+        assert_eq!(key, crate::keys::ISRO);
+        assert_eq!(crate::keys::region_of(key), Some("iSRO"));
+        assert!(archive.open_directory("/").is_ok());
+        drop(archive);
+        assert!(matches!(
+            super::Pk2::open_detect_key(&path, ["wrong"]),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidKey)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_lazy() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("pk2_lazy_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = super::Pk2::create_new(&path, "169841").unwrap();
+        for i in 0..30u8 {
+            let path = format!("/dir{}/sub/{}", i % 3, i);
+            archive.create_file(path).unwrap().write_all(&[i; 1000]).unwrap();
+        }
+        archive.create_file("/dir0/gone").unwrap().write_all(&[0; 1000]).unwrap();
+        archive.delete_file("/dir0/gone").unwrap();
+        let names = |archive: &super::Pk2, path: &str| {
+            let dir = archive.open_directory(path).unwrap();
+            dir.entries().map(|entry| entry.name().to_owned()).collect::<Vec<_>>()
+        };
+        let eager_names = names(&archive, "/dir1/sub");
+        drop(archive);
+
+        let mut archive = super::Pk2::open_lazy(&path, "169841").unwrap();
+        assert!(archive.block_manager.is_lazy());
+        assert_eq!(names(&archive, "/dir1/sub"), eager_names);
+        assert_eq!(archive.read("/DIR2/sub/5").unwrap(), [5; 1000]);
+        assert!(archive.open_file("/dir2/sub/6").is_err());
+        assert!(archive.open_file("/dir3/sub/6").is_err());
+
+        archive.remove_dir_all("/dir2").unwrap();
+        assert!(archive.open_directory("/dir2").is_err());
+        archive.create_file("/dir2/sub/5").unwrap().write_all(&[7; 1000]).unwrap();
+        archive.open_file_mut("/dir0/sub/3").unwrap().write_all(&[9; 10]).unwrap();
+        let len = archive.compact().unwrap();
+        assert!(!archive.block_manager.is_lazy());
+        drop(archive);
+
+        let archive = super::Pk2::open(&path, "169841").unwrap();
+        assert_eq!(archive.free_space.stream_len(), len);
+        assert_eq!(archive.free_space.free_bytes(), 0);
+        assert_eq!(archive.read("/dir2/sub/5").unwrap(), [7; 1000]);
+        let data = archive.read("/dir0/sub/3").unwrap();
+        assert_eq!((&data[..10], &data[10..]), (&[9; 10][..], &[3; 990][..]));
+        assert_eq!(archive.read("/dir1/sub/4").unwrap(), [4; 1000]);
+        assert_eq!(names(&archive, "/dir1/sub"), eager_names);
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_lazy_corrupted_chain() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let path =
+            std::env::temp_dir().join(format!("pk2_lazy_corrupt_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = super::Pk2::create_new(&path, "").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 10]).unwrap();
+        let chain = archive.metadata("/a").unwrap().pos_data();
+        drop(archive);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(chain)).unwrap();
+        file.write_all(&[0xFF; 128]).unwrap();
+        drop(file);
+
+        let archive = super::Pk2::open_lazy(&path, "").unwrap();
+        // the failure is remembered instead of being turned into a missing entry
+        for _ in 0..2 {
+            let Err(e) = archive.open_file("/a/file") else { panic!("expected an error") };
+            assert!(matches!(e.kind(), ErrorKind::CorruptedFile));
+            assert_eq!(e.offset(), Some(chain));
+            assert_eq!(e.operation(), Some(crate::Operation::OpenFile));
+        }
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_as_bytes() {
+        use std::io::{Read, Write};
+        let path = std::env::temp_dir().join(format!("pk2_mmap_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = super::Pk2::create_new(&path, "169841").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 3000]).unwrap();
+        drop(archive);
+
+        let archive = unsafe { super::Pk2::open_mmap(&path, "169841") }.unwrap();
+        let mut file = archive.open_file("/a/file").unwrap();
+        assert_eq!(file.as_bytes().unwrap(), [1; 3000]);
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [1; 3000]);
+        drop(archive);
+
+        // cut off the end of the file data
+        let stream = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        stream.set_len(stream.metadata().unwrap().len() - 1).unwrap();
+        drop(stream);
+        let archive = unsafe { super::Pk2::open_mmap(&path, "169841") }.unwrap();
+        let file = archive.open_file("/a/file").unwrap();
+        let e = file.as_bytes().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_file_streaming() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/a/first").unwrap().write_all(&[1; 100]).unwrap();
+        archive.create_file("/a/last").unwrap().write_all(&[2; 1000]).unwrap();
+        archive.delete_file("/a/last").unwrap();
+        let len = archive.stream.lock().get_ref().len();
+
+        let mut file = archive.create_file_streaming("/a/streamed").unwrap();
+        for i in 0..10 {
+            file.write_all(&[i; 300]).unwrap();
+        }
+        file.flush_drop().unwrap();
+        archive.create_file_streaming("/a/empty").unwrap();
+        // the trailing hole left behind by the deleted file is reused
+        assert_eq!(archive.stream.lock().get_ref().len(), len - 1000 + 3000);
+        assert_eq!(archive.free_space.free_bytes(), 0);
+
+        let archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        let data = archive.read("/a/streamed").unwrap();
+        assert_eq!(data.len(), 3000);
+        assert!(data.chunks(300).enumerate().all(|(i, chunk)| chunk == [i as u8; 300]));
+        assert_eq!(archive.read("/a/empty").unwrap(), [0u8; 0]);
+        assert_eq!(archive.read("/a/first").unwrap(), [1; 100]);
+    }
+
+    #[test]
+    fn name_index() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.set_name_index(true);
+        for i in 0..200 {
+            archive.create_file(format!("/dir/File{}", i)).unwrap().write_all(&[i as u8]).unwrap();
+        }
+        for i in (0..200).step_by(3) {
+            archive.delete_file(format!("/dir/file{}", i)).unwrap();
+        }
+        archive.rename("/dir/file1", "/dir/renamed").unwrap();
+        archive.rename("/dir/file2", "/moved").unwrap();
+        archive.create_file("/dir/file0").unwrap().write_all(&[7]).unwrap();
+        for i in 3..200 {
+            let path = format!("/DIR/FILE{}", i);
+            assert_eq!(archive.open_file(&path).is_ok(), i % 3 != 0, "{}", path);
+        }
+        assert_eq!(archive.read("/dir/renamed").unwrap(), [1]);
+        assert_eq!(archive.read("/moved").unwrap(), [2]);
+        assert_eq!(archive.read("/dir/file0").unwrap(), [7]);
+        assert!(archive.open_file("/dir/file1").is_err());
+
+        archive.set_name_index(false);
+        assert!(archive.open_file("/dir/file4").is_ok());
+        assert!(archive.open_file("/dir/file2").is_err());
+    }
+
+    #[test]
+    fn rename() {
+        use std::io::Write;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/a/b/file").unwrap().write_all(&[1; 10]).unwrap();
+        archive.create_file("/c/file").unwrap().write_all(&[2; 10]).unwrap();
+        let len = archive.stream.lock().get_ref().len();
+
+        archive.rename("/a/b/file", "/a/b/FILE.txt").unwrap();
+        archive.rename("/a/b/FILE.txt", "/c/moved").unwrap();
+        archive.rename("/a/b", "/c/b").unwrap();
+        match archive.rename("/c/file", "/c/moved") {
+            Err(e) => assert_eq!(e.io_kind(), io::ErrorKind::AlreadyExists),
+            Ok(_) => panic!("renamed onto an existing file"),
+        }
+        match archive.rename("/c", "/c/b/c") {
+            Err(e) => assert_eq!(e.io_kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("moved a directory into itself"),
+        }
+        assert_eq!(archive.stream.lock().get_ref().len(), len);
+
+        let archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        assert!(archive.open_file("/a/b/file").is_err());
+        assert!(archive.open_directory("/a/b").is_err());
+        assert_eq!(archive.read("/c/moved").unwrap(), [1; 10]);
+        assert_eq!(archive.read("/c/b/../file").unwrap(), [2; 10]);
+    }
+
+    #[test]
+    fn format_profile() {
+        use super::{FormatProfile, Pk2Options};
+        use std::io::Write;
+
+        let mut signature = [0; 30];
+        signature[..11].copy_from_slice(b"Private PK2");
+        let profile = FormatProfile { salt: *b"0123456789", signature, ..FormatProfile::JOYMAX };
+        let options = Pk2Options::new().profile(profile);
+        let mut archive =
+            super::Pk2::create_new_in_with(io::Cursor::new(Vec::new()), "secret", &options)
+                .unwrap();
+        archive.create_file("/file").unwrap().write_all(&[1; 10]).unwrap();
+        let data = Vec::from(archive);
+
+        assert!(matches!(
+            super::Pk2::open_in(io::Cursor::new(data.clone()), "secret"),
+            Err(e) if matches!(e.kind(), ErrorKind::CorruptedFile)
+        ));
+        // the signature is ignored, but the salt still differs
+        let lenient = Pk2Options::new().profile(FormatProfile::LENIENT);
+        assert!(matches!(
+            super::Pk2::open_in_with(io::Cursor::new(data.clone()), "secret", &lenient),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidKey)
+        ));
+        let archive = super::Pk2::open_in_with(io::Cursor::new(data), "secret", &options).unwrap();
+        assert_eq!(archive.read("/file").unwrap(), [1; 10]);
+
+        let mut archive = super::Pk2::create_new_in_memory("secret").unwrap();
+        archive.create_file("/file").unwrap().write_all(&[2; 10]).unwrap();
+        let mut data = Vec::from(archive);
+        data[..11].copy_from_slice(b"Patched PK2");
+        assert!(super::Pk2::open_in(io::Cursor::new(data.clone()), "secret").is_err());
+        let archive = super::Pk2::open_in_with(io::Cursor::new(data), "secret", &lenient).unwrap();
+        assert_eq!(archive.read("/file").unwrap(), [2; 10]);
+    }
+
+    #[test]
+    fn format_profile_openers() {
+        use super::{FormatProfile, Pk2, Pk2Options};
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("pk2_profile_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let profile = FormatProfile { salt: *b"0123456789", ..FormatProfile::JOYMAX };
+        let options = Pk2Options::new().profile(profile);
+        let mut archive = Pk2::create_new_with(&path, "secret", &options).unwrap();
+        archive.create_file("/dir/file").unwrap().write_all(&[1; 10]).unwrap();
+        drop(archive);
+
+        assert!(Pk2::open_detect_key(&path, ["secret"]).is_err());
+        let (archive, _) = Pk2::open_detect_key_with(&path, ["secret"], &options).unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 10]);
+        assert!(Pk2::open_positional(&path, "secret").is_err());
+        let archive = Pk2::open_positional_with(&path, "secret", &options).unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 10]);
+        assert!(Pk2::open_lazy(&path, "secret").is_err());
+        let archive = Pk2::open_lazy_with(&path, "secret", &options).unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 10]);
+        assert!(Pk2::open_sorted(&path, "secret").is_err());
+        let archive = Pk2::open_sorted_with(&path, "secret", &options).unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 10]);
+        #[cfg(feature = "mmap")]
+        // SAFETY: the file is not modified while mapped
+        unsafe {
+            assert!(Pk2::open_mmap(&path, "secret").is_err());
+            let archive = Pk2::open_mmap_with(&path, "secret", &options).unwrap();
+            assert_eq!(archive.read("/dir/file").unwrap(), [1; 10]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn name_encoding() {
+        use super::Pk2Options;
+        use std::io::Write;
+
+        let gbk = Pk2Options::new().encoding(encoding_rs::GBK);
+        let mut archive =
+            super::Pk2::create_new_in_with(io::Cursor::new(Vec::new()), "", &gbk).unwrap();
+        archive.create_file("/文件").unwrap().write_all(&[1; 10]).unwrap();
+        let data = Vec::from(archive);
+        let archive = super::Pk2::open_in_with(io::Cursor::new(data.clone()), "", &gbk).unwrap();
+        assert_eq!(archive.read("/文件").unwrap(), [1; 10]);
+        let archive = super::Pk2::open_in(io::Cursor::new(data), "").unwrap();
+        assert!(archive.open_file("/文件").is_err());
+
+        // the name of the file is the second entry of the root block
+        let name_pos = super::PK2_ROOT_BLOCK.0 as usize + crate::constants::PK2_FILE_ENTRY_SIZE + 1;
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/ab").unwrap().write_all(&[1; 10]).unwrap();
+        let mut data = Vec::from(archive);
+        data[name_pos..name_pos + 2].copy_from_slice(&[0xff, 0xfe]);
+
+        let raw = Pk2Options::new().keep_raw_names(true);
+        let mut archive =
+            super::Pk2::open_in_with(io::Cursor::new(data.clone()), "", &raw).unwrap();
+        let root = archive.open_root_dir();
+        let file = root.files().next().unwrap();
+        assert_eq!(file.raw_name(), Some(&[0xff, 0xfe][..]));
+        archive.rekey("secret").unwrap();
+        let mut archive =
+            super::Pk2::open_in_with(io::Cursor::new(Vec::from(archive)), "secret", &raw).unwrap();
+        archive.rekey("").unwrap();
+        assert_eq!(Vec::from(archive)[name_pos..name_pos + 3], [0xff, 0xfe, 0]);
+
+        let mut archive = super::Pk2::open_in(io::Cursor::new(data), "").unwrap();
+        archive.rekey("secret").unwrap();
+        archive.rekey("").unwrap();
+        assert_ne!(Vec::from(archive)[name_pos..name_pos + 2], [0xff, 0xfe]);
+    }
+
+    #[test]
+    fn name_encoding_openers() {
+        use super::{Pk2, Pk2Options};
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("pk2_names_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let gbk = Pk2Options::new().encoding(encoding_rs::GBK);
+        let mut archive = Pk2::create_new_with(&path, "", &gbk).unwrap();
+        archive.create_file("/目录/文件").unwrap().write_all(&[1; 10]).unwrap();
+        drop(archive);
+
+        let (archive, _) = Pk2::open_detect_key_with(&path, [""], &gbk).unwrap();
+        assert_eq!(archive.read("/目录/文件").unwrap(), [1; 10]);
+        let archive = Pk2::open_positional_with(&path, "", &gbk).unwrap();
+        assert_eq!(archive.read("/目录/文件").unwrap(), [1; 10]);
+        let archive = Pk2::open_lazy_with(&path, "", &gbk).unwrap();
+        assert_eq!(archive.read("/目录/文件").unwrap(), [1; 10]);
+        let archive = Pk2::open_sorted_with(&path, "", &gbk).unwrap();
+        assert_eq!(archive.read("/目录/文件").unwrap(), [1; 10]);
+        #[cfg(feature = "mmap")]
+        // SAFETY: the file is not modified while mapped
+        unsafe {
+            let archive = Pk2::open_mmap_with(&path, "", &gbk).unwrap();
+            assert_eq!(archive.read("/目录/文件").unwrap(), [1; 10]);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        // raw names are also kept for chains that are loaded lazily
+        let mut archive = Pk2::create_new(&path, "").unwrap();
+        archive.create_file("/dir/ab").unwrap().write_all(&[1; 10]).unwrap();
+        let chain = archive.open_directory("/dir").unwrap().metadata().pos_data();
+        drop(archive);
+        // the name of the file is the third entry of the chain, after `.` and `..`
+        let name_pos = chain + 2 * crate::constants::PK2_FILE_ENTRY_SIZE as u64 + 1;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        io::Seek::seek(&mut file, io::SeekFrom::Start(name_pos)).unwrap();
+        file.write_all(&[0xff, 0xfe]).unwrap();
+        drop(file);
+        let raw = Pk2Options::new().keep_raw_names(true);
+        let archive = Pk2::open_lazy_with(&path, "", &raw).unwrap();
+        let dir = archive.open_directory("/dir").unwrap();
+        assert_eq!(dir.files().next().unwrap().raw_name(), Some(&[0xff, 0xfe][..]));
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_directory() {
+        use std::io::Write;
+
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_directory("/a/b").unwrap();
+        archive.create_directory("/a/c").unwrap();
+        let e = archive.create_directory("/A/b").err().unwrap();
+        assert_eq!(e.io_kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(e.operation(), Some(crate::Operation::CreateDirectory));
+        assert!(archive.create_directory("/").is_err());
+        archive.create_file("/a/b/file").unwrap().write_all(&[1; 10]).unwrap();
+
+        let archive = super::Pk2::open_in(io::Cursor::new(Vec::from(archive)), "").unwrap();
+        let a = archive.open_directory("/a").unwrap();
+        let mut names: Vec<_> = a.entries().map(|entry| entry.name().to_owned()).collect();
+        names.sort();
+        assert_eq!(names, ["b", "c"]);
+        assert_eq!(archive.open_directory("/a/c").unwrap().entries().count(), 0);
+        assert_eq!(archive.read("/a/b/file").unwrap(), [1; 10]);
+    }
+
+    #[test]
+    fn invalid_names() {
+        use crate::InvalidName;
+
+        fn reason(res: crate::Result<impl Sized>) -> InvalidName {
+            match res.err().expect("invalid name was accepted").kind() {
+                &ErrorKind::InvalidName(name) => name,
+                kind => panic!("unexpected error {kind}"),
+            }
+        }
+
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file(format!("/{}", "a".repeat(80))).unwrap();
+        let long = format!("/{}", "a".repeat(81));
+        assert_eq!(reason(archive.create_file(&long)), InvalidName::TooLong);
+        // every hangul syllable takes up two bytes in EUC-KR
+        let long = format!("/{}", "가".repeat(41));
+        assert_eq!(reason(archive.create_file(&long)), InvalidName::TooLong);
+        assert_eq!(reason(archive.create_file("/a\\b")), InvalidName::Separator);
+        assert_eq!(reason(archive.create_file("/new/dir/😀")), InvalidName::Unencodable);
+        assert!(archive.open_directory("/new").is_err());
+
+        archive.create_file("/file").unwrap();
+        assert_eq!(reason(archive.rename("/file", "/😀")), InvalidName::Unencodable);
+        assert_eq!(reason(archive.rename("/file", &long)), InvalidName::TooLong);
+        assert!(archive.open_file("/file").is_ok());
+    }
+
+    #[test]
+    fn error_context() {
+        use crate::Operation;
+        use std::io::Write;
+        use std::path::Path;
+
+        let mut archive = super::Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/dir/file").unwrap().write_all(&[1; 10]).unwrap();
+
+        let e = archive.create_file("/dir/a\\b").err().unwrap();
+        assert_eq!(e.operation(), Some(Operation::CreateFile));
+        assert_eq!(e.path(), Some(Path::new("/dir/a\\b")));
+        assert_eq!(e.io_kind(), io::ErrorKind::InvalidInput);
+
+        let e = archive.open_file("/dir/missing").err().unwrap();
+        assert!(matches!(e.kind(), ErrorKind::Lookup(crate::ChainLookupError::NotFound)));
+        assert_eq!(e.to_string(), "failed to open file \"/dir/missing\": entity not found");
+
+        // errors of the callback carry the path of the file relative to the archive root
+        let e = archive.for_each_file("/dir", |_, _| Err(io::ErrorKind::Other.into())).unwrap_err();
+        assert_eq!(e.operation(), Some(Operation::ForEachFile));
+        assert_eq!(e.path(), Some(Path::new("/dir/file")));
+
+        // the context survives a round trip through io::Error
+        let e = super::Error::from(io::Error::from(e));
+        assert_eq!(e.operation(), Some(Operation::ForEachFile));
+        assert_eq!(e.io_kind(), io::ErrorKind::Other);
+
+        // a garbage root block is reported with its offset
+        let mut data = Vec::from(archive);
+        data[256..256 + 2560].fill(0xff);
+        let e = super::Pk2::open_in(io::Cursor::new(data), "").err().unwrap();
+        assert!(matches!(e.kind(), ErrorKind::CorruptedFile));
+        assert_eq!(e.operation(), Some(Operation::Open));
+        assert_eq!(e.offset(), Some(256));
+    }
 }
@@ -0,0 +1,684 @@
+//! Asynchronous archives built on top of tokio.
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
+use tokio::sync::Mutex;
+
+use super::fs::{reserve_data, seek_impl};
+use super::transaction::{TransactionStream, Writes};
+use super::{check_root, Pk2};
+use crate::blowfish::Blowfish;
+use crate::constants::PK2_ROOT_BLOCK;
+use crate::error::{ChainLookupError, Context as _, Operation, Result};
+use crate::options::Pk2Options;
+use crate::raw::block_manager::BlockManager;
+use crate::raw::entry::{FileEntry, PackEntry};
+use crate::raw::free_space::FreeSpaceMap;
+use crate::raw::{ChainIndex, StreamOffset};
+
+/// Records the writes of the synchronous index code, so that they can be
+/// applied to the asynchronous stream afterwards.
+type Recorder<'a> = TransactionStream<'a, io::Empty>;
+
+type ReadFuture = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+type FlushFuture = Pin<Box<dyn Future<Output = (Vec<u8>, io::Result<()>)> + Send>>;
+
+/// An archive whose file data is read and written asynchronously.
+///
+/// The index is still parsed synchronously when the archive is opened, for
+/// path based archives this happens on tokio's blocking thread pool.
+pub struct AsyncPk2<B = tokio::fs::File> {
+    stream: Arc<Mutex<B>>,
+    blowfish: Option<Blowfish>,
+    block_manager: BlockManager,
+    free_space: FreeSpaceMap,
+    options: Pk2Options,
+}
+
+impl AsyncPk2<tokio::fs::File> {
+    /// Creates a new [`File`](tokio::fs::File) based archive at the given
+    /// path.
+    pub async fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::create_new_with(path, key, &Pk2Options::default()).await
+    }
+
+    /// Creates a new [`File`](tokio::fs::File) based archive at the given
+    /// path with the given options.
+    pub async fn create_new_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let (path, key) = (path.as_ref().to_owned(), key.as_ref().to_owned());
+        let options = options.clone();
+        Self::spawn_blocking(move || Pk2::create_new_with(path, key, &options)).await
+    }
+
+    /// Opens an archive at the given path, see [`Pk2::open`].
+    pub async fn open<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Result<Self> {
+        Self::open_with(path, key, &Pk2Options::default()).await
+    }
+
+    /// Opens an archive at the given path with the given options, see
+    /// [`Pk2::open_with`].
+    pub async fn open_with<P: AsRef<Path>, K: AsRef<[u8]>>(
+        path: P,
+        key: K,
+        options: &Pk2Options,
+    ) -> Result<Self> {
+        let (path, key) = (path.as_ref().to_owned(), key.as_ref().to_owned());
+        let options = options.clone();
+        Self::spawn_blocking(move || Pk2::open_with(path, key, &options)).await
+    }
+
+    async fn spawn_blocking(f: impl FnOnce() -> Result<Pk2> + Send + 'static) -> Result<Self> {
+        let Pk2 { stream, blowfish, block_manager, free_space, options, .. } =
+            tokio::task::spawn_blocking(f).await.map_err(io::Error::other)??;
+        Ok(AsyncPk2 {
+            stream: Arc::new(Mutex::new(tokio::fs::File::from_std(stream.into_inner()))),
+            blowfish,
+            block_manager,
+            free_space,
+            options,
+        })
+    }
+}
+
+/// Converts an archive whose stream also implements tokio's io traits, like
+/// [`io::Cursor`], into an asynchronous one.
+impl<B> From<Pk2<B>> for AsyncPk2<B> {
+    fn from(pk2: Pk2<B>) -> Self {
+        let Pk2 { stream, blowfish, block_manager, free_space, options, .. } = pk2;
+        AsyncPk2 {
+            stream: Arc::new(Mutex::new(stream.into_inner())),
+            blowfish,
+            block_manager,
+            free_space,
+            options,
+        }
+    }
+}
+
+impl<B> AsyncPk2<B> {
+    /// Enables or disables the name index, see [`Pk2::set_name_index`].
+    pub fn set_name_index(&mut self, enabled: bool) {
+        self.block_manager.set_name_index(enabled);
+    }
+
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> Result<AsyncFile<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) = self.resolve_file(path).context(Operation::OpenFile, path)?;
+        Ok(AsyncFile::new(self, chain, entry_idx))
+    }
+
+    fn resolve_file(&self, path: &Path) -> Result<(ChainIndex, usize)> {
+        let (chain, entry_idx, entry) = self
+            .block_manager
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, check_root(path)?)?;
+        Pk2::<B>::is_file(entry)?;
+        Ok((chain, entry_idx))
+    }
+
+    fn file_entry(&self, chain: ChainIndex, entry_index: usize) -> &FileEntry {
+        self.block_manager
+            .get(chain)
+            .and_then(|chain| chain.get(entry_index))
+            .and_then(PackEntry::as_file)
+            .expect("invalid file object")
+    }
+}
+
+impl<B> AsyncPk2<B>
+where
+    B: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    pub async fn read<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) = self.resolve_file(path).context(Operation::OpenFile, path)?;
+        let entry = self.file_entry(chain, entry_idx);
+        read_at(&self.stream, entry.pos_data(), entry.size() as usize)
+            .await
+            .offset(entry.pos_data().0)
+            .context(Operation::Read, path)
+    }
+}
+
+impl<B> AsyncPk2<B>
+where
+    B: AsyncRead + AsyncWrite + AsyncSeek + Unpin + Send + 'static,
+{
+    /// Opens a file for writing, reading its current contents into memory.
+    pub async fn open_file_mut<P: AsRef<Path>>(&mut self, path: P) -> Result<AsyncFileMut<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx) = self.resolve_file(path).context(Operation::OpenFile, path)?;
+        let entry = self.file_entry(chain, entry_idx);
+        let data = read_at(&self.stream, entry.pos_data(), entry.size() as usize)
+            .await
+            .offset(entry.pos_data().0)
+            .context(Operation::Read, path)?;
+        Ok(AsyncFileMut::new(self, chain, entry_idx, data, None))
+    }
+
+    pub async fn create_file<P: AsRef<Path>>(&mut self, path: P) -> Result<AsyncFileMut<'_, B>> {
+        let path = path.as_ref();
+        let (chain, entry_idx, entry) =
+            self.create_file_entry(path).await.context(Operation::CreateFile, path)?;
+        Ok(AsyncFileMut::new(self, chain, entry_idx, Vec::new(), Some(entry)))
+    }
+
+    /// Reserves an empty entry for the file, returning the entry to store in
+    /// it once the file has been written.
+    async fn create_file_entry(
+        &mut self,
+        path: &Path,
+    ) -> io::Result<(ChainIndex, usize, PackEntry)> {
+        let path = check_root(path)?;
+        let file_name = path
+            .file_name()
+            .and_then(std::ffi::OsStr::to_str)
+            .ok_or(ChainLookupError::InvalidPath)?;
+        let (chain, entry_idx) = self
+            .record(|this, stream| {
+                Pk2::<Recorder<'_>>::create_entry_at(
+                    &mut this.block_manager,
+                    &mut this.free_space,
+                    this.blowfish.as_ref(),
+                    this.options.names(),
+                    stream,
+                    PK2_ROOT_BLOCK,
+                    path,
+                )
+            })
+            .await?;
+        let slot = self.block_manager.get(chain).unwrap().get(entry_idx).unwrap();
+        let entry = PackEntry::new_file(file_name, StreamOffset(0), 0, slot.next_block());
+        Ok((chain, entry_idx, entry))
+    }
+
+    /// Replaces the entry with an empty one, releasing the space of its data
+    /// for reuse by later writes.
+    pub async fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.delete_file_impl(path).await.context(Operation::DeleteFile, path)
+    }
+
+    async fn delete_file_impl(&mut self, path: &Path) -> io::Result<()> {
+        let path = check_root(path)?;
+        self.record(|this, stream| {
+            let (chain_index, entry_idx, entry) =
+                this.block_manager.resolve_path_to_entry_and_parent_mut(PK2_ROOT_BLOCK, path)?;
+            Pk2::<B>::is_file(entry)?;
+            if let PackEntry::File(file) = entry.clear() {
+                this.free_space.free(file.pos_data(), file.size() as u64);
+            }
+            crate::io::write_chain_entry(
+                this.blowfish.as_ref(),
+                this.options.names(),
+                stream,
+                this.block_manager.get(chain_index).unwrap(),
+                entry_idx,
+            )
+        })
+        .await
+    }
+
+    /// Runs the index modification `f`, applying the writes it did to the
+    /// stream afterwards.
+    async fn record<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self, &mut Recorder<'_>) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut empty = io::empty();
+        let mut recorder = TransactionStream::new(&mut empty)?;
+        let res = f(self, &mut recorder)?;
+        let writes = recorder.into_writes();
+        apply(&mut *self.stream.lock().await, &writes).await?;
+        Ok(res)
+    }
+}
+
+async fn read_at<B>(stream: &Mutex<B>, offset: StreamOffset, len: usize) -> io::Result<Vec<u8>>
+where
+    B: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut buf = vec![0; len];
+    if len != 0 {
+        let mut stream = stream.lock().await;
+        stream.seek(SeekFrom::Start(offset.0)).await?;
+        stream.read_exact(&mut buf).await?;
+    }
+    Ok(buf)
+}
+
+async fn apply<B>(stream: &mut B, writes: &Writes) -> io::Result<()>
+where
+    B: AsyncWrite + AsyncSeek + Unpin,
+{
+    for (&offset, data) in writes {
+        stream.seek(SeekFrom::Start(offset)).await?;
+        stream.write_all(data).await?;
+    }
+    stream.flush().await
+}
+
+/// A read-only asynchronous file handle.
+pub struct AsyncFile<'pk2, B = tokio::fs::File> {
+    archive: &'pk2 AsyncPk2<B>,
+    // the chain this file resides in
+    chain: ChainIndex,
+    // the index of this file in the chain
+    entry_index: usize,
+    seek_pos: u64,
+    reading: Option<ReadFuture>,
+}
+
+impl<'pk2, B> AsyncFile<'pk2, B> {
+    fn new(archive: &'pk2 AsyncPk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
+        AsyncFile { archive, chain, entry_index, seek_pos: 0, reading: None }
+    }
+
+    pub fn modify_time(&self) -> Option<SystemTime> {
+        self.entry().modify_time()
+    }
+
+    pub fn access_time(&self) -> Option<SystemTime> {
+        self.entry().access_time()
+    }
+
+    pub fn create_time(&self) -> Option<SystemTime> {
+        self.entry().create_time()
+    }
+
+    pub fn size(&self) -> u32 {
+        self.entry().size
+    }
+
+    pub fn name(&self) -> &'pk2 str {
+        self.entry().name()
+    }
+
+    fn entry(&self) -> &'pk2 FileEntry {
+        self.archive.file_entry(self.chain, self.entry_index)
+    }
+}
+
+impl<B> AsyncRead for AsyncFile<'_, B>
+where
+    B: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let reading = match &mut this.reading {
+            Some(reading) => reading,
+            None => {
+                let entry = this.entry();
+                let len = buf.remaining().min((entry.size() as u64 - this.seek_pos) as usize);
+                if len == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                let offset = entry.pos_data() + StreamOffset(this.seek_pos);
+                let stream = this.archive.stream.clone();
+                this.reading.insert(Box::pin(async move { read_at(&stream, offset, len).await }))
+            }
+        };
+        let res = ready!(reading.as_mut().poll(cx));
+        this.reading = None;
+        let data = res?;
+        // the buffer passed in might be smaller than the one the read was started with
+        let n = data.len().min(buf.remaining());
+        buf.put_slice(&data[..n]);
+        this.seek_pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<B> AsyncSeek for AsyncFile<'_, B> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.reading = None;
+        this.seek_pos = seek_impl(position, this.seek_pos, this.entry().size() as u64)?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.seek_pos))
+    }
+}
+
+/// A write-able asynchronous file handle.
+///
+/// Unlike [`FileMut`](crate::fs::FileMut) the written data is not flushed on
+/// drop, so the handle has to be [flushed](AsyncWriteExt::flush) or
+/// [shut down](AsyncWriteExt::shutdown) before it is dropped.
+pub struct AsyncFileMut<'pk2, B = tokio::fs::File> {
+    archive: &'pk2 mut AsyncPk2<B>,
+    // the chain this file resides in
+    chain: ChainIndex,
+    // the index of this file in the chain
+    entry_index: usize,
+    data: Vec<u8>,
+    seek_pos: u64,
+    dirty: bool,
+    // the entry created by this handle, which only enters the index once it has been written
+    created: Option<PackEntry>,
+    flushing: Option<FlushFuture>,
+    // the entry and free space the index gets once the running flush succeeds
+    pending: Option<(PackEntry, FreeSpaceMap)>,
+}
+
+impl<'pk2, B> AsyncFileMut<'pk2, B> {
+    fn new(
+        archive: &'pk2 mut AsyncPk2<B>,
+        chain: ChainIndex,
+        entry_index: usize,
+        data: Vec<u8>,
+        created: Option<PackEntry>,
+    ) -> Self {
+        AsyncFileMut {
+            archive,
+            chain,
+            entry_index,
+            data,
+            seek_pos: 0,
+            // a created entry has to be written on flush even if no data gets written
+            dirty: created.is_some(),
+            created,
+            flushing: None,
+            pending: None,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.entry().size
+    }
+
+    pub fn name(&self) -> &str {
+        self.entry().name()
+    }
+
+    /// The entry as it is going to be once a running flush succeeded.
+    fn entry(&self) -> &FileEntry {
+        match self.pending.as_ref().map(|(entry, _)| entry).or(self.created.as_ref()) {
+            Some(entry) => entry.as_file().expect("invalid file object, this is a bug"),
+            None => self.archive.file_entry(self.chain, self.entry_index),
+        }
+    }
+
+    /// Drives a running flush to completion, handing the data back and
+    /// updating the index if the flush succeeded.
+    fn poll_flushing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(flushing) = &mut self.flushing else { return Poll::Ready(Ok(())) };
+        let (data, res) = ready!(flushing.as_mut().poll(cx));
+        self.flushing = None;
+        self.data = data;
+        let (entry, free_space) = self.pending.take().expect("flush without pending entry");
+        match res {
+            Ok(()) => {
+                let archive = &mut *self.archive;
+                let chain = archive.block_manager.get_mut(self.chain).expect("invalid chain");
+                *chain.get_mut(self.entry_index).expect("invalid entry") = entry;
+                archive.free_space = free_space;
+                self.created = None;
+            }
+            // the index is left untouched, so flushing again retries the whole write
+            Err(_) => self.dirty = true,
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<B> AsyncFileMut<'_, B>
+where
+    B: AsyncWrite + AsyncSeek + Unpin + Send + 'static,
+{
+    fn start_flush(&mut self) -> io::Result<()> {
+        let archive = &mut *self.archive;
+        let chain = archive.block_manager.get(self.chain).expect("invalid chain");
+        let entry_offset = chain.stream_offset_for_entry(self.entry_index).expect("invalid entry");
+        // the index is only updated once the data has been written, so that a failed or cancelled
+        // flush does not leave it pointing at data that is not there
+        let mut entry = match &self.created {
+            Some(entry) => entry.clone(),
+            None => chain.get(self.entry_index).expect("invalid entry").clone(),
+        };
+        let mut free_space = archive.free_space.clone();
+        let fentry = entry.as_file_mut().expect("invalid file object, this is a bug");
+        fentry.modify_time = SystemTime::now().into();
+        reserve_data(&mut free_space, fentry, self.data.len());
+        let StreamOffset(pos_data) = fentry.pos_data;
+
+        let mut empty = io::empty();
+        let mut recorder = TransactionStream::new(&mut empty)?;
+        crate::io::write_entry_at(
+            archive.blowfish.as_ref(),
+            archive.options.names(),
+            &mut recorder,
+            entry_offset,
+            &entry,
+        )?;
+        let writes = recorder.into_writes();
+        self.dirty = false;
+        self.pending = Some((entry, free_space));
+
+        let stream = archive.stream.clone();
+        let data = mem::take(&mut self.data);
+        self.flushing = Some(Box::pin(async move {
+            let res = async {
+                let mut stream = stream.lock().await;
+                // write the data before the entry pointing to it
+                if !data.is_empty() {
+                    stream.seek(SeekFrom::Start(pos_data)).await?;
+                    stream.write_all(&data).await?;
+                }
+                apply(&mut *stream, &writes).await
+            }
+            .await;
+            (data, res)
+        }));
+        Ok(())
+    }
+}
+
+impl<B> AsyncRead for AsyncFileMut<'_, B> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flushing(cx))?;
+        let start = (this.seek_pos as usize).min(this.data.len());
+        let n = buf.remaining().min(this.data.len() - start);
+        buf.put_slice(&this.data[start..start + n]);
+        this.seek_pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<B> AsyncSeek for AsyncFileMut<'_, B> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        // the data is handed to a running flush, whose pending entry has the new size
+        let size = this.data.len().max(this.entry().size() as usize) as u64;
+        this.seek_pos = seek_impl(position, this.seek_pos, size)?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.seek_pos))
+    }
+}
+
+impl<B> AsyncWrite for AsyncFileMut<'_, B>
+where
+    B: AsyncWrite + AsyncSeek + Unpin + Send + 'static,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_flushing(cx))?;
+        let start = this.seek_pos as usize;
+        // files can't be bigger than u32::MAX, so truncate what doesn't fit
+        let n = buf.len().min((u32::MAX as usize).saturating_sub(start));
+        let end = start + n;
+        if this.data.len() < end {
+            this.data.resize(end, 0);
+        }
+        this.data[start..end].copy_from_slice(&buf[..n]);
+        this.seek_pos = end as u64;
+        this.dirty |= n != 0;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.flushing.is_none() && this.dirty {
+            this.start_flush()?;
+        }
+        this.poll_flushing(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::SeekFrom;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::AsyncPk2;
+    use crate::{FormatProfile, Pk2Options};
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(f)
+    }
+
+    #[test]
+    fn async_read_write() {
+        let path = std::env::temp_dir().join(format!("pk2_async_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        block_on(async {
+            let mut archive = AsyncPk2::create_new(&path, "169841").await.unwrap();
+            let mut file = archive.create_file("/dir/file").await.unwrap();
+            file.write_all(&[1; 5000]).await.unwrap();
+            file.shutdown().await.unwrap();
+            archive.create_file("/dir/empty").await.unwrap().flush().await.unwrap();
+            let mut file = archive.create_file("/dir/deleted").await.unwrap();
+            file.write_all(&[3; 100]).await.unwrap();
+            file.flush().await.unwrap();
+            archive.delete_file("/dir/deleted").await.unwrap();
+
+            let mut file = archive.open_file_mut("/dir/file").await.unwrap();
+            file.seek(SeekFrom::End(0)).await.unwrap();
+            file.write_all(&[2; 100]).await.unwrap();
+            file.flush().await.unwrap();
+
+            let mut file = archive.open_file("/dir/file").unwrap();
+            file.seek(SeekFrom::Start(4990)).await.unwrap();
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf[..10], [1; 10]);
+            assert_eq!(buf[10..], [2; 100]);
+            assert_eq!(archive.read("/dir/empty").await.unwrap(), [0u8; 0]);
+        });
+
+        let archive = crate::Pk2::open(&path, "169841").unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap().len(), 5100);
+        assert_eq!(archive.read("/dir/empty").unwrap(), [0u8; 0]);
+        assert!(archive.open_file("/dir/deleted").is_err());
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn open_with_options() {
+        let path =
+            std::env::temp_dir().join(format!("pk2_async_options_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let profile = FormatProfile { salt: *b"0123456789", ..FormatProfile::JOYMAX };
+        let options = Pk2Options::new().profile(profile);
+        block_on(async {
+            let mut archive = AsyncPk2::create_new_with(&path, "secret", &options).await.unwrap();
+            let mut file = archive.create_file("/file").await.unwrap();
+            file.write_all(&[1; 10]).await.unwrap();
+            file.shutdown().await.unwrap();
+            drop(archive);
+
+            assert!(AsyncPk2::open(&path, "secret").await.is_err());
+            let archive = AsyncPk2::open_with(&path, "secret", &options).await.unwrap();
+            assert_eq!(archive.read("/file").await.unwrap(), [1; 10]);
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        let gbk = Pk2Options::new().encoding(encoding_rs::GBK);
+        block_on(async {
+            let mut archive = AsyncPk2::create_new_with(&path, "", &gbk).await.unwrap();
+            let mut file = archive.create_file("/目录/文件").await.unwrap();
+            file.write_all(&[2; 10]).await.unwrap();
+            file.shutdown().await.unwrap();
+            drop(archive);
+
+            let archive = AsyncPk2::open_with(&path, "", &gbk).await.unwrap();
+            assert_eq!(archive.read("/目录/文件").await.unwrap(), [2; 10]);
+        });
+        assert!(crate::Pk2::open(&path, "").unwrap().open_file("/目录/文件").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cancelled_flush_keeps_index() {
+        use std::future::Future;
+        use std::task::{Context, Waker};
+
+        let mut archive = crate::Pk2::create_new_in_memory("169841").unwrap();
+        std::io::Write::write_all(&mut archive.create_file("/file").unwrap(), &[1; 100]).unwrap();
+        let mut archive = AsyncPk2::from(archive);
+        block_on(async {
+            let stream = archive.stream.clone();
+            let free_space = format!("{:?}", archive.free_space);
+
+            let mut file = archive.open_file_mut("/file").await.unwrap();
+            file.write_all(&[2; 5000]).await.unwrap();
+            let guard = stream.lock().await;
+            let mut flush = Box::pin(file.flush());
+            assert!(flush.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+            drop(flush);
+            drop(file);
+            drop(guard);
+            assert_eq!(archive.open_file("/file").unwrap().size(), 100);
+            assert_eq!(format!("{:?}", archive.free_space), free_space);
+
+            // a created file that never got flushed does not exist
+            let mut file = archive.create_file("/dir/unflushed").await.unwrap();
+            file.write_all(&[3; 100]).await.unwrap();
+            drop(file);
+            assert!(archive.open_file("/dir/unflushed").is_err());
+            archive.create_file("/dir/flushed").await.unwrap().shutdown().await.unwrap();
+        });
+
+        let stream = archive.stream.try_lock().unwrap().clone();
+        let archive = crate::Pk2::open_in(stream, "169841").unwrap();
+        assert_eq!(archive.read("/file").unwrap(), [1; 100]);
+        assert!(archive.open_file("/dir/unflushed").is_err());
+        assert_eq!(archive.read("/dir/flushed").unwrap(), [0u8; 0]);
+    }
+}
@@ -0,0 +1,421 @@
+//! Integrity checking of archives.
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+
+use crate::archive::Pk2;
+use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_FILE_ENTRY_SIZE, PK2_ROOT_BLOCK};
+use crate::error::{Context, Operation, Result};
+use crate::raw::entry::{DirectoryEntry, PackEntry};
+use crate::raw::{BlockOffset, ChainIndex, StreamOffset};
+
+/// The kind of problem an [`Issue`] describes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The chain is the children chain of more than one directory, `first`
+    /// being the path it was reached through first.
+    SharedChain { first: PathBuf },
+    /// The blocks of the chain link back to one of its own blocks.
+    Cycle,
+    /// A block or the data of a file extends past the end of the stream.
+    PastEnd { stream_len: u64 },
+    /// A block could not be decoded.
+    CorruptedBlock,
+    /// The data of the file overlaps the data of another file.
+    OverlappingData { other: PathBuf, other_offset: u64 },
+    /// A block overlaps the data of a file or the other way around.
+    BlockOverlapsData { other: PathBuf, other_offset: u64 },
+    /// A block overlaps a block of another directory.
+    OverlappingBlocks { other: PathBuf, other_offset: u64 },
+    /// The directory has no `.` entry pointing at its own chain.
+    MissingCurrentDir,
+    /// The directory has no `..` entry pointing at its parent's chain.
+    MissingParentDir,
+    /// The name is already used by another entry of the same directory,
+    /// ignoring ASCII case.
+    DuplicateName { other_offset: u64 },
+}
+
+/// A single problem found by [`Pk2::check`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    kind: IssueKind,
+    offset: u64,
+    path: PathBuf,
+}
+
+impl Issue {
+    pub fn kind(&self) -> &IssueKind {
+        &self.kind
+    }
+
+    /// The stream offset the issue was found at. Depending on the kind of
+    /// issue this is the offset of a chain, a block, an entry or file data.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The path of the affected entry, or of the directory owning the
+    /// affected chain.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x} {}: ", self.offset, self.path.display())?;
+        match &self.kind {
+            IssueKind::SharedChain { first } => {
+                write!(f, "chain is also referenced by {}", first.display())
+            }
+            IssueKind::Cycle => write!(f, "chain links back to one of its blocks"),
+            IssueKind::PastEnd { stream_len } => {
+                write!(f, "extends past the end of the stream at {stream_len:#x}")
+            }
+            IssueKind::CorruptedBlock => write!(f, "block is corrupted"),
+            IssueKind::OverlappingData { other, other_offset } => {
+                write!(f, "data overlaps data of {} at {other_offset:#x}", other.display())
+            }
+            IssueKind::BlockOverlapsData { other, other_offset } => {
+                write!(f, "overlaps {} at {other_offset:#x}", other.display())
+            }
+            IssueKind::OverlappingBlocks { other, other_offset } => {
+                write!(f, "block overlaps a block of {} at {other_offset:#x}", other.display())
+            }
+            IssueKind::MissingCurrentDir => write!(f, "directory has no `.` entry"),
+            IssueKind::MissingParentDir => write!(f, "directory has no `..` entry"),
+            IssueKind::DuplicateName { other_offset } => {
+                write!(f, "name is already used by the entry at {other_offset:#x}")
+            }
+        }
+    }
+}
+
+/// The result of [`Pk2::check`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    issues: Vec<Issue>,
+    chains: usize,
+    files: usize,
+}
+
+impl CheckReport {
+    /// Whether no issues have been found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// All issues found, ordered by their offset.
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    /// The number of chains that have been checked.
+    pub fn chain_count(&self) -> usize {
+        self.chains
+    }
+
+    /// The number of files that have been checked.
+    pub fn file_count(&self) -> usize {
+        self.files
+    }
+
+    fn push(&mut self, kind: IssueKind, offset: u64, path: &Path) {
+        self.issues.push(Issue { kind, offset, path: path.to_owned() });
+    }
+}
+
+/// A used region of the stream.
+struct Region {
+    start: u64,
+    end: u64,
+    is_block: bool,
+    path: PathBuf,
+}
+
+impl<B> Pk2<B>
+where
+    B: io::Read + io::Seek,
+{
+    /// Validates the whole index of the archive as it is stored in the stream,
+    /// reporting every inconsistency instead of stopping at the first one.
+    /// Only io errors of the stream itself are returned as errors.
+    pub fn check(&self) -> Result<CheckReport> {
+        self.check_impl().operation(Operation::Check)
+    }
+
+    fn check_impl(&self) -> Result<CheckReport> {
+        let (bf, names) = (self.blowfish.as_ref(), self.options.names());
+        let mut stream = self.stream.lock();
+        let stream_len = crate::io::stream_len(&mut *stream)?;
+        let mut report = CheckReport::default();
+        let mut regions = Vec::new();
+        let mut reached = HashMap::<ChainIndex, PathBuf>::new();
+        // chains to check together with the path of their directory and the chain of its parent
+        let mut chains = vec![(PK2_ROOT_BLOCK, PathBuf::from("/"), None)];
+        while let Some((chain, path, parent)) = chains.pop() {
+            if let Some(first) = reached.get(&chain) {
+                report.push(IssueKind::SharedChain { first: first.clone() }, chain.0, &path);
+                continue;
+            }
+            reached.insert(chain, path.clone());
+            report.chains += 1;
+
+            let mut entries = Vec::new();
+            let mut visited = HashSet::new();
+            let mut offset = BlockOffset::from(chain);
+            loop {
+                let BlockOffset(start) = offset;
+                if !visited.insert(offset) {
+                    report.push(IssueKind::Cycle, start, &path);
+                    break;
+                }
+                let Some(end) =
+                    start.checked_add(PK2_FILE_BLOCK_SIZE as u64).filter(|&end| end <= stream_len)
+                else {
+                    report.push(IssueKind::PastEnd { stream_len }, start, &path);
+                    break;
+                };
+                let block = match crate::io::read_block_at(bf, names, &mut *stream, offset) {
+                    Ok(block) => block,
+                    Err(e) if e.io_kind() != io::ErrorKind::InvalidData => return Err(e),
+                    Err(_) => {
+                        report.push(IssueKind::CorruptedBlock, start, &path);
+                        break;
+                    }
+                };
+                regions.push(Region { start, end, is_block: true, path: path.clone() });
+                entries.extend(block.entries().enumerate().map(|(idx, entry)| {
+                    (start + (idx * PK2_FILE_ENTRY_SIZE) as u64, entry.clone())
+                }));
+                match block.entries().last().and_then(PackEntry::next_block) {
+                    Some(next) => offset = BlockOffset(next.get()),
+                    None => break,
+                }
+            }
+
+            let links = |is_link: fn(&DirectoryEntry) -> bool| {
+                entries
+                    .iter()
+                    .filter_map(|(_, entry)| entry.as_directory())
+                    .filter(move |dir| is_link(dir))
+                    .map(|dir| dir.children_position())
+            };
+            if !links(|dir| dir.is_current_link()).any(|pos| pos == chain) {
+                report.push(IssueKind::MissingCurrentDir, chain.0, &path);
+            }
+            if let Some(parent) = parent {
+                if !links(|dir| dir.is_parent_link()).any(|pos| pos == parent) {
+                    report.push(IssueKind::MissingParentDir, chain.0, &path);
+                }
+            }
+
+            let mut names = HashMap::new();
+            let mut children = Vec::new();
+            for (entry_offset, entry) in &entries {
+                let Some(name) = entry.name() else { continue };
+                if entry.as_directory().is_some_and(|dir| !dir.is_normal_link()) {
+                    continue;
+                }
+                let entry_path = path.join(name);
+                if let Some(&other_offset) = names.get(&name.to_ascii_lowercase()) {
+                    let kind = IssueKind::DuplicateName { other_offset };
+                    report.push(kind, *entry_offset, &entry_path);
+                } else {
+                    names.insert(name.to_ascii_lowercase(), *entry_offset);
+                }
+                match entry {
+                    PackEntry::File(file) => {
+                        report.files += 1;
+                        let (StreamOffset(start), size) = (file.pos_data(), file.size() as u64);
+                        match start.checked_add(size).filter(|&end| end <= stream_len) {
+                            None => {
+                                let kind = IssueKind::PastEnd { stream_len };
+                                report.push(kind, start, &entry_path);
+                            }
+                            Some(end) if size > 0 => {
+                                let path = entry_path;
+                                regions.push(Region { start, end, is_block: false, path });
+                            }
+                            Some(_) => (),
+                        }
+                    }
+                    PackEntry::Directory(dir) => {
+                        children.push((dir.children_position(), entry_path, Some(chain)));
+                    }
+                    PackEntry::Empty(_) => (),
+                }
+            }
+            // visit the children in the order they are stored in
+            chains.extend(children.into_iter().rev());
+        }
+
+        // sweep over all regions, comparing each one against the region reaching the furthest
+        regions.sort_by_key(|region| region.start);
+        let mut furthest: Option<&Region> = None;
+        for region in &regions {
+            if let Some(prev) = furthest.filter(|prev| region.start < prev.end) {
+                let (other, other_offset) = (prev.path.clone(), prev.start);
+                let kind = match (prev.is_block, region.is_block) {
+                    (false, false) => IssueKind::OverlappingData { other, other_offset },
+                    (true, true) => IssueKind::OverlappingBlocks { other, other_offset },
+                    _ => IssueKind::BlockOverlapsData { other, other_offset },
+                };
+                report.push(kind, region.start, &region.path);
+            }
+            if furthest.is_none_or(|prev| region.end > prev.end) {
+                furthest = Some(region);
+            }
+        }
+        report.issues.sort_by_key(Issue::offset);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::IssueKind;
+    use crate::raw::entry::PackEntry;
+    use crate::raw::{ChainIndex, StreamOffset};
+    use crate::Pk2;
+
+    fn archive() -> Pk2<std::io::Cursor<Vec<u8>>> {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 100]).unwrap();
+        archive.create_file("/a/other").unwrap().write_all(&[2; 100]).unwrap();
+        archive.create_file("/b/file").unwrap().write_all(&[3; 100]).unwrap();
+        archive
+    }
+
+    /// Modifies the entry at `path` and writes its chain back to the stream.
+    fn corrupt(
+        archive: &mut Pk2<std::io::Cursor<Vec<u8>>>,
+        path: &str,
+        f: impl FnOnce(&mut PackEntry),
+    ) {
+        let (chain, idx, _) = archive.root_resolve_path_to_entry_and_parent(path).unwrap();
+        f(&mut archive.get_chain_mut(chain).unwrap()[idx]);
+        write_chain(archive, chain);
+    }
+
+    fn write_chain(archive: &mut Pk2<std::io::Cursor<Vec<u8>>>, chain: ChainIndex) {
+        let chain = archive.block_manager.get(chain).unwrap();
+        for (offset, block) in chain.blocks() {
+            let names = archive.options.names();
+            crate::io::write_block(None, names, &mut *archive.stream.lock(), offset, block)
+                .unwrap();
+        }
+    }
+
+    fn kinds(archive: &Pk2<std::io::Cursor<Vec<u8>>>) -> Vec<(IssueKind, String)> {
+        let report = archive.check().unwrap();
+        report
+            .issues()
+            .iter()
+            .map(|issue| (issue.kind().clone(), issue.path().to_str().unwrap().to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let report = archive().check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues());
+        assert_eq!(report.chain_count(), 3);
+        assert_eq!(report.file_count(), 3);
+    }
+
+    #[test]
+    fn duplicate_name_and_overlap() {
+        let mut archive = archive();
+        let file = archive.open_file("/a/file").unwrap().metadata();
+        corrupt(&mut archive, "/a/other", |entry| {
+            entry.set_name("FILE");
+            let file_entry = entry.as_file_mut().unwrap();
+            file_entry.pos_data = StreamOffset(file.pos_data() + 50);
+        });
+        let other_offset = file.entry_offset().unwrap();
+        let other = "/a/file".into();
+        assert_eq!(
+            kinds(&archive),
+            [
+                (IssueKind::DuplicateName { other_offset }, "/a/FILE".to_owned()),
+                (
+                    IssueKind::OverlappingData { other, other_offset: file.pos_data() },
+                    "/a/FILE".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn past_end_and_block_overlap() {
+        let mut archive = archive();
+        let stream_len = archive.stream.lock().get_ref().len() as u64;
+        corrupt(&mut archive, "/a/file", |entry| entry.as_file_mut().unwrap().size = u32::MAX);
+        let root = crate::constants::PK2_ROOT_BLOCK.0;
+        corrupt(&mut archive, "/b/file", |entry| {
+            entry.as_file_mut().unwrap().pos_data = StreamOffset(root + 10)
+        });
+        let issues = archive.check().unwrap();
+        let issues = issues.issues();
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].offset(), root + 10);
+        assert_eq!(
+            issues[0].kind(),
+            &IssueKind::BlockOverlapsData { other: "/".into(), other_offset: root }
+        );
+        assert_eq!(issues[0].path(), std::path::Path::new("/b/file"));
+        assert_eq!(issues[1].kind(), &IssueKind::PastEnd { stream_len });
+        assert_eq!(issues[1].path(), std::path::Path::new("/a/file"));
+    }
+
+    #[test]
+    fn past_end_overflow() {
+        let mut archive = archive();
+        let stream_len = archive.stream.lock().get_ref().len() as u64;
+        let far = u64::MAX - 1;
+        corrupt(&mut archive, "/a/file", |entry| {
+            entry.as_file_mut().unwrap().pos_data = StreamOffset(far)
+        });
+        corrupt(&mut archive, "/b", |entry| {
+            entry.as_directory_mut().unwrap().pos_children = ChainIndex(far)
+        });
+        let past_end = IssueKind::PastEnd { stream_len };
+        let report = archive.check().unwrap();
+        // the unreadable chain of `/b` is also missing its `.` and `..` entries
+        let issues: Vec<_> =
+            report.issues().iter().filter(|issue| issue.kind() == &past_end).collect();
+        assert_eq!(issues.len(), 2);
+        for (issue, path) in issues.into_iter().zip(["/a/file", "/b"]) {
+            assert_eq!(issue.offset(), far);
+            assert_eq!(issue.path(), std::path::Path::new(path));
+        }
+    }
+
+    #[test]
+    fn broken_chains() {
+        let mut archive = archive();
+        let a = ChainIndex(archive.open_directory("/a").unwrap().metadata().pos_data());
+        // `/b` now shares the chain of `/a`, orphaning its own
+        corrupt(&mut archive, "/b", |entry| {
+            entry.as_directory_mut().unwrap().pos_children = a;
+        });
+        let chain = archive.get_chain_mut(a).unwrap();
+        // the `..` entry of `/a` no longer points at the root
+        chain[1].as_directory_mut().unwrap().pos_children = a;
+        // and the last block of `/a` links back to its first one
+        chain.last_entry_mut().set_next_block(a.into());
+        write_chain(&mut archive, a);
+        assert_eq!(
+            kinds(&archive),
+            [
+                (IssueKind::Cycle, "/a".to_owned()),
+                (IssueKind::MissingParentDir, "/a".to_owned()),
+                (IssueKind::SharedChain { first: "/a".into() }, "/b".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! In-place defragmentation of an archive.
+use std::io;
+
+use crate::archive::Pk2;
+use crate::error::{Context, Operation, Result};
+use crate::io::SetLen;
+use crate::raw::free_space::FreeSpaceMap;
+use crate::raw::StreamOffset;
+
+/// The size of the buffer used to move data inside of the stream.
+const MOVE_BUFFER_SIZE: usize = 64 * 1024;
+
+impl<B> Pk2<B>
+where
+    B: io::Read + io::Write + io::Seek + SetLen,
+{
+    /// Defragments the archive in place by moving all file data and blocks
+    /// towards the front of the stream, closing every unused gap, and then
+    /// truncating the stream. Returns the new length of the stream.
+    ///
+    /// The archive should not be interrupted while compacting, as data is
+    /// moved before the index pointing to it is rewritten.
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compact_with_progress(|_, _| ())
+    }
+
+    /// Like [`Pk2::compact`], but invokes `progress` with the amount of bytes
+    /// processed so far and the total amount of bytes that have to be
+    /// processed.
+    pub fn compact_with_progress(&mut self, progress: impl FnMut(u64, u64)) -> Result<u64> {
+        self.compact_impl(progress).operation(Operation::Compact)
+    }
+
+    fn compact_impl(&mut self, mut progress: impl FnMut(u64, u64)) -> Result<u64> {
+        // the holes of the archive are only known once the whole index has been loaded
+        self.load_full_index()?;
+        // every hole together with the amount of free space that lies in front of it
+        let mut shift = 0;
+        let holes = self
+            .free_space
+            .holes()
+            .map(|(StreamOffset(offset), len)| {
+                let hole = (offset, len, shift);
+                shift += len;
+                hole
+            })
+            .collect::<Vec<_>>();
+        let stream_len = self.free_space.stream_len();
+        let new_len = stream_len - shift;
+        if holes.is_empty() {
+            progress(stream_len, stream_len);
+            return Ok(stream_len);
+        }
+
+        // move every used region between two holes down by the free space preceding it
+        {
+            let stream = self.stream.get_mut();
+            let mut buf = vec![0; MOVE_BUFFER_SIZE];
+            let (first_hole, ..) = holes[0];
+            let total = stream_len - first_hole;
+            let mut done = 0;
+            for (i, &(offset, len, shift)) in holes.iter().enumerate() {
+                done += len;
+                let start = offset + len;
+                let end = holes.get(i + 1).map_or(stream_len, |&(next, ..)| next);
+                let shift = shift + len;
+                let mut pos = start;
+                while pos < end {
+                    let n = buf.len().min((end - pos) as usize);
+                    crate::io::read_exact_at(&mut *stream, StreamOffset(pos), &mut buf[..n])?;
+                    crate::io::write_data_at(&mut *stream, StreamOffset(pos - shift), &buf[..n])?;
+                    pos += n as u64;
+                    done += n as u64;
+                    progress(done, total);
+                }
+            }
+            progress(done, total);
+        }
+
+        // now rewrite the index to point at the new locations
+        self.block_manager.relocate(|offset| {
+            let idx = holes.partition_point(|&(hole, ..)| hole < offset);
+            match idx.checked_sub(1).map(|idx| holes[idx]) {
+                // offsets pointing into a hole are invalid, so just clamp them to its end
+                Some((hole, len, shift)) => offset.max(hole + len) - shift - len,
+                None => offset,
+            }
+        });
+        let (blowfish, names) = (self.blowfish.as_ref(), self.options.names());
+        let stream = self.stream.get_mut();
+        for chain in self.block_manager.chains() {
+            for (offset, block) in chain.blocks() {
+                crate::io::write_block(blowfish, names, &mut *stream, offset, block)?;
+            }
+        }
+        stream.set_len(new_len)?;
+        stream.flush()?;
+        self.free_space = FreeSpaceMap::new(&self.block_manager, new_len);
+        Ok(new_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+
+    use crate::Pk2;
+
+    #[test]
+    fn compact() {
+        let mut archive = Pk2::create_new_in_memory("169841").unwrap();
+        for i in 0..30 {
+            let data = vec![i as u8; 1000 + i];
+            archive
+                .create_file(format!("/dir{}/file{}", i % 3, i))
+                .unwrap()
+                .write_all(&data)
+                .unwrap();
+        }
+        for i in (0..30).step_by(4) {
+            archive.delete_file(format!("/dir{}/file{}", i % 3, i)).unwrap();
+        }
+        archive.remove_dir_all("/dir1").unwrap();
+        let len = archive.stream.lock().get_ref().len() as u64;
+        let free = archive.free_space.free_bytes();
+        assert_ne!(free, 0);
+
+        let mut last_progress = None;
+        let new_len = archive
+            .compact_with_progress(|done, total| last_progress = Some((done, total)))
+            .unwrap();
+        assert_eq!(new_len, len - free);
+        assert!(matches!(last_progress, Some((done, total)) if done == total));
+        assert_eq!(archive.free_space.free_bytes(), 0);
+
+        let archive = Pk2::open_in(io::Cursor::new(Vec::from(archive)), "169841").unwrap();
+        assert_eq!(archive.stream.lock().get_ref().len() as u64, new_len);
+        assert_eq!(archive.free_space.free_bytes(), 0);
+        assert!(archive.open_directory("/dir1").is_err());
+        for i in (0..30).filter(|i| i % 4 != 0 && i % 3 != 1) {
+            assert_eq!(
+                archive.read(format!("/dir{}/file{}", i % 3, i)).unwrap(),
+                vec![i as u8; 1000 + i]
+            );
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Comparing the contents of two archives.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::archive::fs::{DirEntry, Directory, File};
+use crate::archive::Pk2;
+use crate::error::{Context, Operation, Result};
+use crate::io::ReadStream;
+
+/// What [`diff_with`] compares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffOptions {
+    contents: bool,
+    hashes: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { contents: true, hashes: false }
+    }
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether files of the same size are compared byte by byte, enabled by
+    /// default. Otherwise only their sizes are compared.
+    pub fn compare_contents(mut self, compare: bool) -> Self {
+        self.contents = compare;
+        self
+    }
+
+    /// Compares files by a 64 bit FNV-1a hash of their contents instead,
+    /// recording the hashes of all changed files in the diff. Disabled by
+    /// default.
+    pub fn hash_contents(mut self, hash: bool) -> Self {
+        self.hashes = hash;
+        self
+    }
+}
+
+/// The timestamps of a file or directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EntryTimes {
+    pub access_time: Option<SystemTime>,
+    pub create_time: Option<SystemTime>,
+    pub modify_time: Option<SystemTime>,
+}
+
+/// The kind of a [`Change`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum ChangeKind {
+    AddedFile {
+        size: u32,
+        hash: Option<u64>,
+    },
+    RemovedFile {
+        size: u32,
+        hash: Option<u64>,
+    },
+    ModifiedFile {
+        old_size: u32,
+        new_size: u32,
+        old_hash: Option<u64>,
+        new_hash: Option<u64>,
+    },
+    AddedDirectory,
+    RemovedDirectory,
+    /// The file or directory is unchanged apart from its timestamps.
+    TimesChanged {
+        old: EntryTimes,
+        new: EntryTimes,
+    },
+}
+
+/// A single difference found by [`diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Change {
+    path: PathBuf,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    kind: ChangeKind,
+}
+
+impl Change {
+    /// The path of the entry, as named in the new archive unless it was
+    /// removed.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn kind(&self) -> &ChangeKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.path.display())?;
+        match &self.kind {
+            ChangeKind::AddedFile { size, .. } => write!(f, "added file of {size} bytes"),
+            ChangeKind::RemovedFile { size, .. } => write!(f, "removed file of {size} bytes"),
+            ChangeKind::ModifiedFile { old_size, new_size, .. } if old_size == new_size => {
+                write!(f, "modified contents")
+            }
+            ChangeKind::ModifiedFile { old_size, new_size, .. } => {
+                write!(f, "modified, {old_size} -> {new_size} bytes")
+            }
+            ChangeKind::AddedDirectory => write!(f, "added directory"),
+            ChangeKind::RemovedDirectory => write!(f, "removed directory"),
+            ChangeKind::TimesChanged { .. } => write!(f, "timestamps changed"),
+        }
+    }
+}
+
+/// The result of [`diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveDiff {
+    changes: Vec<Change>,
+}
+
+impl ArchiveDiff {
+    /// Whether both archives have the same contents.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// All changes ordered by path, with the contents of added directories
+    /// listed after and those of removed directories before the directory
+    /// itself, so that applying them in order is always possible.
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+}
+
+/// Compares the trees of both archives, see [`diff_with`].
+pub fn diff<A: ReadStream, B: ReadStream>(old: &Pk2<A>, new: &Pk2<B>) -> Result<ArchiveDiff> {
+    diff_with(old, new, &DiffOptions::default())
+}
+
+/// Compares the trees of both archives, reporting every file and directory
+/// that has been added, removed or modified in `new`. Names are compared
+/// ignoring ASCII case like paths are resolved, and a file that turned into a
+/// directory or the other way around is reported as removed and added.
+pub fn diff_with<A: ReadStream, B: ReadStream>(
+    old: &Pk2<A>,
+    new: &Pk2<B>,
+    options: &DiffOptions,
+) -> Result<ArchiveDiff> {
+    let mut differ = Differ { options, changes: Vec::new() };
+    differ.directory(old.open_root_dir(), new.open_root_dir(), Path::new("/"))?;
+    Ok(ArchiveDiff { changes: differ.changes })
+}
+
+struct Differ<'a> {
+    options: &'a DiffOptions,
+    changes: Vec<Change>,
+}
+
+impl Differ<'_> {
+    fn push(&mut self, path: PathBuf, kind: ChangeKind) {
+        self.changes.push(Change { path, kind });
+    }
+
+    fn directory<A: ReadStream, B: ReadStream>(
+        &mut self,
+        old: Directory<'_, A>,
+        new: Directory<'_, B>,
+        path: &Path,
+    ) -> Result<()> {
+        let mut old_entries = entries_by_name(old);
+        let mut new_entries = entries_by_name(new);
+        let mut names = old_entries.keys().chain(new_entries.keys()).cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            match (old_entries.remove(&name), new_entries.remove(&name)) {
+                (Some(DirEntry::File(old)), Some(DirEntry::File(new))) => {
+                    self.file(old, new, path.join(new.name()))?
+                }
+                (Some(DirEntry::Directory(old)), Some(DirEntry::Directory(new))) => {
+                    let path = path.join(new.name());
+                    let (old_times, new_times) = (EntryTimes::of(&old), EntryTimes::of(&new));
+                    if old_times != new_times {
+                        let kind = ChangeKind::TimesChanged { old: old_times, new: new_times };
+                        self.push(path.clone(), kind);
+                    }
+                    self.directory(old, new, &path)?;
+                }
+                (old, new) => {
+                    if let Some(old) = old {
+                        self.removed(old, path.join(old.name()))?;
+                    }
+                    if let Some(new) = new {
+                        self.added(new, path.join(new.name()))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn file<A: ReadStream, B: ReadStream>(
+        &mut self,
+        old: File<'_, A>,
+        new: File<'_, B>,
+        path: PathBuf,
+    ) -> Result<()> {
+        let (old_size, new_size) = (old.size(), new.size());
+        let (old_hash, new_hash) = (self.hash(old, &path)?, self.hash(new, &path)?);
+        let modified = match (old_hash, new_hash) {
+            (Some(old_hash), Some(new_hash)) => old_size != new_size || old_hash != new_hash,
+            _ if old_size != new_size => true,
+            _ if self.options.contents => {
+                let old_data = read(old, &path)?;
+                old_data != read(new, &path)?
+            }
+            _ => false,
+        };
+        if modified {
+            self.push(path, ChangeKind::ModifiedFile { old_size, new_size, old_hash, new_hash });
+        } else {
+            let (old_times, new_times) = (EntryTimes::of(&old), EntryTimes::of(&new));
+            if old_times != new_times {
+                self.push(path, ChangeKind::TimesChanged { old: old_times, new: new_times });
+            }
+        }
+        Ok(())
+    }
+
+    fn added<B: ReadStream>(&mut self, entry: DirEntry<'_, B>, path: PathBuf) -> Result<()> {
+        match entry {
+            DirEntry::File(file) => {
+                let hash = self.hash(file, &path)?;
+                self.push(path, ChangeKind::AddedFile { size: file.size(), hash });
+            }
+            DirEntry::Directory(dir) => {
+                self.push(path.clone(), ChangeKind::AddedDirectory);
+                for (_, entry) in entries_by_name(dir) {
+                    self.added(entry, path.join(entry.name()))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn removed<A: ReadStream>(&mut self, entry: DirEntry<'_, A>, path: PathBuf) -> Result<()> {
+        match entry {
+            DirEntry::File(file) => {
+                let hash = self.hash(file, &path)?;
+                self.push(path, ChangeKind::RemovedFile { size: file.size(), hash });
+            }
+            DirEntry::Directory(dir) => {
+                for (_, entry) in entries_by_name(dir) {
+                    self.removed(entry, path.join(entry.name()))?;
+                }
+                self.push(path, ChangeKind::RemovedDirectory);
+            }
+        }
+        Ok(())
+    }
+
+    fn hash<B: ReadStream>(&self, file: File<'_, B>, path: &Path) -> Result<Option<u64>> {
+        if !self.options.hashes {
+            return Ok(None);
+        }
+        crate::hash::hash_reader(file).map(Some).context(Operation::Read, path)
+    }
+}
+
+impl EntryTimes {
+    fn of<E: HasTimes>(entry: &E) -> Self {
+        let (access_time, create_time, modify_time) = entry.times();
+        EntryTimes { access_time, create_time, modify_time }
+    }
+}
+
+trait HasTimes {
+    fn times(&self) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>);
+}
+
+impl<B> HasTimes for File<'_, B> {
+    fn times(&self) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>) {
+        (self.access_time(), self.create_time(), self.modify_time())
+    }
+}
+
+impl<B> HasTimes for Directory<'_, B> {
+    fn times(&self) -> (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>) {
+        (self.access_time(), self.create_time(), self.modify_time())
+    }
+}
+
+/// The entries of the directory keyed and ordered by their lowercased name.
+pub(super) fn entries_by_name<B>(dir: Directory<'_, B>) -> BTreeMap<String, DirEntry<'_, B>> {
+    dir.entries().map(|entry| (entry.name().to_ascii_lowercase(), entry)).collect()
+}
+
+fn read<B: ReadStream>(mut file: File<'_, B>, path: &Path) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut buf).context(Operation::Read, path)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+
+    use super::{diff, diff_with, ChangeKind, DiffOptions};
+    use crate::Pk2;
+
+    fn changes(diff: &super::ArchiveDiff) -> Vec<(&Path, &ChangeKind)> {
+        diff.changes().iter().map(|change| (change.path(), change.kind())).collect()
+    }
+
+    #[test]
+    fn diff_trees() {
+        let mut old = Pk2::create_new_in_memory("").unwrap();
+        for (path, data) in [
+            ("/same", &[1; 10][..]),
+            ("/resized", &[2; 10]),
+            ("/rewritten", &[3; 10]),
+            ("/touched", &[4; 10]),
+            ("/removed/a", &[5; 10]),
+            ("/removed/sub/b", &[6; 10]),
+            ("/Dir/file", &[7; 10]),
+            ("/kind", &[8; 10]),
+        ] {
+            old.create_file(path).unwrap().write_all(data).unwrap();
+        }
+        let mut new = Pk2::open_in(Cursor::new(old.stream.lock().get_ref().clone()), "").unwrap();
+        assert!(diff(&old, &new).unwrap().is_empty());
+
+        new.open_file_mut("/resized").unwrap().write_all(&[2; 20]).unwrap();
+        new.open_file_mut("/rewritten").unwrap().write_all(&[9; 10]).unwrap();
+        new.open_file_mut("/touched").unwrap().write_all(&[4; 10]).unwrap();
+        new.remove_dir_all("/removed").unwrap();
+        new.create_file("/added/c").unwrap().write_all(&[1; 3]).unwrap();
+        new.delete_file("/kind").unwrap();
+        new.create_file("/KIND/d").unwrap().write_all(&[1; 3]).unwrap();
+        // names are compared ignoring case
+        new.rename("/Dir", "/dir").unwrap();
+
+        let diff = diff(&old, &new).unwrap();
+        let kinds = changes(&diff)
+            .into_iter()
+            .map(|(path, kind)| {
+                let kind = match kind {
+                    ChangeKind::AddedFile { .. } => "added file",
+                    ChangeKind::RemovedFile { .. } => "removed file",
+                    ChangeKind::ModifiedFile { .. } => "modified file",
+                    ChangeKind::AddedDirectory => "added directory",
+                    ChangeKind::RemovedDirectory => "removed directory",
+                    ChangeKind::TimesChanged { .. } => "times changed",
+                };
+                (path.to_str().unwrap(), kind)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("/added", "added directory"),
+                ("/added/c", "added file"),
+                ("/kind", "removed file"),
+                ("/KIND", "added directory"),
+                ("/KIND/d", "added file"),
+                ("/removed/a", "removed file"),
+                ("/removed/sub/b", "removed file"),
+                ("/removed/sub", "removed directory"),
+                ("/removed", "removed directory"),
+                ("/resized", "modified file"),
+                ("/rewritten", "modified file"),
+                ("/touched", "times changed"),
+            ]
+        );
+        assert_eq!(
+            diff.changes()[9].kind(),
+            &ChangeKind::ModifiedFile {
+                old_size: 10,
+                new_size: 20,
+                old_hash: None,
+                new_hash: None
+            }
+        );
+        assert_eq!(diff.changes()[9].to_string(), "/resized: modified, 10 -> 20 bytes");
+
+        // without comparing contents the rewritten file only differs in its timestamps
+        let options = DiffOptions::new().compare_contents(false);
+        let diff = diff_with(&old, &new, &options).unwrap();
+        assert!(matches!(changes(&diff)[10], (_, ChangeKind::TimesChanged { .. })));
+
+        let options = DiffOptions::new().hash_contents(true);
+        let diff = diff_with(&old, &new, &options).unwrap();
+        let ChangeKind::ModifiedFile { old_hash: Some(old_hash), new_hash: Some(new_hash), .. } =
+            *changes(&diff)[10].1
+        else {
+            panic!("rewritten file was not hashed");
+        };
+        assert_eq!(old_hash, crate::hash::hash_reader(&[3; 10][..]).unwrap());
+        assert_eq!(new_hash, crate::hash::hash_reader(&[9; 10][..]).unwrap());
+    }
+}
@@ -1,14 +1,21 @@
 //! File structs representing file entries inside a pk2 archive.
+mod glob;
+mod metadata;
+pub use self::glob::Glob;
+pub use self::metadata::{EntryKind, Metadata};
+
+use std::cmp::Ordering;
 use std::hash::Hash;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use crate::archive::Pk2;
-use crate::error::{ChainLookupError, ChainLookupResult};
+use crate::error::{ChainLookupError, Context, Operation, Result};
+use crate::io::ReadStream;
 use crate::raw::block_chain::PackBlockChain;
 use crate::raw::entry::{DirectoryEntry, FileEntry, PackEntry};
+use crate::raw::free_space::FreeSpaceMap;
 use crate::raw::{ChainIndex, StreamOffset};
 
 /// A read-only file handle.
@@ -53,7 +60,20 @@ impl<'pk2, B> File<'pk2, B> {
         self.entry().name()
     }
 
-    fn entry(&self) -> &'pk2 FileEntry {
+    /// The bytes the name was read from, if the archive has been opened with
+    /// [`Pk2Options::keep_raw_names`](crate::Pk2Options::keep_raw_names).
+    pub fn raw_name(&self) -> Option<&'pk2 [u8]> {
+        self.entry().raw_name()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(
+            self.archive.get_chain(self.chain).expect("invalid file object"),
+            self.entry_index,
+        )
+    }
+
+    pub(super) fn entry(&self) -> &'pk2 FileEntry {
         self.archive
             .get_entry(self.chain, self.entry_index)
             .and_then(PackEntry::as_file)
@@ -65,29 +85,48 @@ impl<'pk2, B> File<'pk2, B> {
     }
 }
 
+#[cfg(feature = "mmap")]
+impl<'pk2> File<'pk2, crate::io::MmapFile> {
+    /// Returns the contents of this file, borrowed straight from the memory
+    /// mapping of the archive without copying.
+    ///
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the data of the file
+    /// reaches past the end of the archive.
+    pub fn as_bytes(&self) -> io::Result<&'pk2 [u8]> {
+        let entry = self.entry();
+        let stream = self.archive.stream.read();
+        let data = stream.slice(entry.pos_data(), entry.size() as usize);
+        if data.len() != entry.size() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file data past end of archive",
+            ));
+        }
+        // SAFETY: MmapFile is read-only, so the mapping can only be unmapped or replaced through a
+        // mutable reference to the archive, which cannot exist while it is borrowed for `'pk2`.
+        Ok(unsafe { std::slice::from_raw_parts(data.as_ptr(), data.len()) })
+    }
+}
+
 impl<B> Seek for File<'_, B> {
     fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
         let size = self.entry().size() as u64;
-        seek_impl(seek, self.seek_pos, size).map(|new_pos| {
+        seek_impl(seek, self.seek_pos, size).inspect(|&new_pos| {
             self.seek_pos = new_pos;
-            new_pos
         })
     }
 }
 
 impl<B> Read for File<'_, B>
 where
-    B: Read + Seek,
+    B: ReadStream,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let pos_data = self.entry().pos_data();
         let rem_len = self.remaining_len();
         let len = buf.len().min(rem_len);
-        let n = crate::io::read_at(
-            &mut *self.archive.stream.borrow_mut(),
-            pos_data + StreamOffset(self.seek_pos),
-            &mut buf[..len],
-        )?;
+        let n =
+            self.archive.stream.read_at(pos_data + StreamOffset(self.seek_pos), &mut buf[..len])?;
         self.seek(SeekFrom::Current(n as i64))?;
         Ok(n)
     }
@@ -98,11 +137,9 @@ where
         if buf.len() < rem_len {
             Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
         } else {
-            crate::io::read_at(
-                &mut *self.archive.stream.borrow_mut(),
-                pos_data + StreamOffset(self.seek_pos),
-                &mut buf[..rem_len],
-            )?;
+            self.archive
+                .stream
+                .read_exact_at(pos_data + StreamOffset(self.seek_pos), &mut buf[..rem_len])?;
             self.seek_pos += rem_len as u64;
             Ok(())
         }
@@ -127,14 +164,21 @@ where
     // the index of this file in the chain
     entry_index: usize,
     data: Cursor<Vec<u8>>,
+    // whether the entry has to be written on flush even if there is no data
+    dirty: bool,
 }
 
 impl<'pk2, B> FileMut<'pk2, B>
 where
     B: Read + Write + Seek,
 {
-    pub(super) fn new(archive: &'pk2 mut Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
-        FileMut { archive, chain, entry_index, data: Cursor::new(Vec::new()) }
+    pub(super) fn new(
+        archive: &'pk2 mut Pk2<B>,
+        chain: ChainIndex,
+        entry_index: usize,
+        dirty: bool,
+    ) -> Self {
+        FileMut { archive, chain, entry_index, data: Cursor::new(Vec::new()), dirty }
     }
 
     pub fn modify_time(&self) -> Option<SystemTime> {
@@ -173,8 +217,8 @@ where
         self.entry().size
     }
 
-    pub fn flush_drop(mut self) -> io::Result<()> {
-        let res = self.flush();
+    pub fn flush_drop(mut self) -> Result<()> {
+        let res = self.flush().operation(Operation::Write);
         std::mem::forget(self);
         res
     }
@@ -201,11 +245,7 @@ where
         let pos_data = self.entry().pos_data();
         let size = self.entry().size();
         self.data.get_mut().resize(size as usize, 0);
-        crate::io::read_exact_at(
-            &mut *self.archive.stream.borrow_mut(),
-            pos_data,
-            self.data.get_mut(),
-        )
+        crate::io::read_exact_at(self.archive.stream.get_mut(), pos_data, self.data.get_mut())
     }
 
     fn try_fetch_data(&mut self) -> io::Result<()> {
@@ -223,9 +263,8 @@ where
 {
     fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
         let size = self.data.get_ref().len().max(self.entry().size() as usize) as u64;
-        seek_impl(seek, self.data.position(), size).map(|new_pos| {
+        seek_impl(seek, self.data.position(), size).inspect(|&new_pos| {
             self.data.set_position(new_pos);
-            new_pos
         })
     }
 }
@@ -247,7 +286,7 @@ where
     fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
         let len = buf.len();
         let size = self.data.get_ref().len().max(self.entry().size() as usize);
-        buf.resize(len + size as usize, 0);
+        buf.resize(len + size, 0);
         self.read_exact(&mut buf[len..]).map(|()| size)
     }
 }
@@ -270,9 +309,10 @@ where
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if self.data.get_ref().is_empty() {
+        if self.data.get_ref().is_empty() && !self.dirty {
             return Ok(()); // nothing to write
         }
+        self.dirty = false;
         self.set_modify_time(SystemTime::now());
         let chain = self.archive.block_manager.get_mut(self.chain).expect("invalid chain");
         let entry_offset = chain.stream_offset_for_entry(self.entry_index).expect("invalid entry");
@@ -280,21 +320,18 @@ where
         let entry = chain.get_mut(self.entry_index).expect("invalid entry");
         let fentry = entry.as_file_mut().expect("invalid file object, this is a bug");
 
-        let stream = &mut *self.archive.stream.borrow_mut();
+        let stream = self.archive.stream.get_mut();
         let data = &self.data.get_ref()[..];
-        debug_assert!(data.len() <= !0u32 as usize);
-        let data_len = data.len() as u32;
-        // new unwritten file/more data than what fits, so use a new block
-        if data_len > fentry.size {
-            // FIXME reuse previous buffer somehow?
-            fentry.pos_data = crate::io::append_data(&mut *stream, data)?;
-        // data fits into the previous buffer space
-        } else {
-            crate::io::write_data_at(&mut *stream, fentry.pos_data, data)?;
-        }
-        fentry.size = data_len;
-
-        crate::io::write_entry_at(self.archive.blowfish.as_ref(), stream, entry_offset, entry)
+        reserve_data(&mut self.archive.free_space, fentry, data.len());
+        crate::io::write_data_at(&mut *stream, fentry.pos_data, data)?;
+
+        crate::io::write_entry_at(
+            self.archive.blowfish.as_ref(),
+            self.archive.options.names(),
+            stream,
+            entry_offset,
+            entry,
+        )
     }
 }
 
@@ -307,7 +344,124 @@ where
     }
 }
 
-fn seek_impl(seek: SeekFrom, seek_pos: u64, size: u64) -> io::Result<u64> {
+/// A write-only file handle that writes its data straight to the end of the
+/// stream instead of buffering it, fixing up the size and position of the
+/// entry once flushed.
+///
+/// This keeps the memory usage independent of the file size, at the expense
+/// of not being able to seek or to reuse holes in the middle of the stream.
+pub struct StreamingFileMut<'pk2, B = std::fs::File>
+where
+    B: Read + Write + Seek,
+{
+    archive: &'pk2 mut Pk2<B>,
+    // the chain this file resides in
+    chain: ChainIndex,
+    // the index of this file in the chain
+    entry_index: usize,
+    pos_data: StreamOffset,
+    // the amount of bytes written so far
+    written: u32,
+    // the amount of written bytes that have been reserved in the free space map
+    reserved: u32,
+}
+
+impl<'pk2, B> StreamingFileMut<'pk2, B>
+where
+    B: Read + Write + Seek,
+{
+    pub(super) fn new(archive: &'pk2 mut Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
+        let pos_data = archive.free_space.tail();
+        StreamingFileMut { archive, chain, entry_index, pos_data, written: 0, reserved: 0 }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.written
+    }
+
+    pub fn name(&self) -> &str {
+        self.archive
+            .get_entry(self.chain, self.entry_index)
+            .and_then(PackEntry::as_file)
+            .expect("invalid file object")
+            .name()
+    }
+
+    pub fn flush_drop(mut self) -> Result<()> {
+        let res = self.flush().operation(Operation::Write);
+        std::mem::forget(self);
+        res
+    }
+}
+
+impl<B> Write for StreamingFileMut<'_, B>
+where
+    B: Read + Write + Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // files can't be bigger than u32::MAX, so truncate what doesn't fit
+        let len = buf.len().min((u32::MAX - self.written) as usize);
+        crate::io::write_data_at(
+            self.archive.stream.get_mut(),
+            self.pos_data + StreamOffset(self.written as u64),
+            &buf[..len],
+        )?;
+        self.written += len as u32;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let reserved = self.archive.free_space.append((self.written - self.reserved) as u64);
+        debug_assert_eq!(reserved, self.pos_data + StreamOffset(self.reserved as u64));
+        self.reserved = self.written;
+
+        let chain = self.archive.block_manager.get_mut(self.chain).expect("invalid chain");
+        let entry_offset = chain.stream_offset_for_entry(self.entry_index).expect("invalid entry");
+        let entry = chain.get_mut(self.entry_index).expect("invalid entry");
+        let fentry = entry.as_file_mut().expect("invalid file object, this is a bug");
+        fentry.modify_time = SystemTime::now().into();
+        fentry.pos_data = self.pos_data;
+        fentry.size = self.written;
+
+        let stream = self.archive.stream.get_mut();
+        crate::io::write_entry_at(
+            self.archive.blowfish.as_ref(),
+            self.archive.options.names(),
+            &mut *stream,
+            entry_offset,
+            entry,
+        )?;
+        stream.flush()
+    }
+}
+
+impl<B> Drop for StreamingFileMut<'_, B>
+where
+    B: Write + Read + Seek,
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Moves the data of `fentry` to a place that fits `data_len` bytes and sets
+/// its size accordingly, releasing the space that is no longer needed.
+pub(super) fn reserve_data(free_space: &mut FreeSpaceMap, fentry: &mut FileEntry, data_len: usize) {
+    debug_assert!(data_len <= !0u32 as usize);
+    let data_len = data_len as u32;
+    // new unwritten file/more data than what fits, so move it into the best fitting hole
+    if data_len > fentry.size {
+        free_space.free(fentry.pos_data, fentry.size as u64);
+        fentry.pos_data = free_space.allocate(data_len as u64);
+    // data fits into the previous buffer space, release what is left over
+    } else {
+        free_space
+            .free(fentry.pos_data + StreamOffset(data_len as u64), (fentry.size - data_len) as u64);
+    }
+    fentry.size = data_len;
+}
+
+pub(super) fn seek_impl(seek: SeekFrom, seek_pos: u64, size: u64) -> io::Result<u64> {
     let (base_pos, offset) = match seek {
         SeekFrom::Start(n) => {
             return Ok(n);
@@ -342,6 +496,27 @@ impl<'pk2, B> Clone for DirEntry<'pk2, B> {
 }
 
 impl<'pk2, B> DirEntry<'pk2, B> {
+    pub fn name(&self) -> &'pk2 str {
+        match self {
+            DirEntry::Directory(dir) => dir.name(),
+            DirEntry::File(file) => file.name(),
+        }
+    }
+
+    pub fn raw_name(&self) -> Option<&'pk2 [u8]> {
+        match self {
+            DirEntry::Directory(dir) => dir.raw_name(),
+            DirEntry::File(file) => file.raw_name(),
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            DirEntry::Directory(dir) => dir.metadata(),
+            DirEntry::File(file) => file.metadata(),
+        }
+    }
+
     fn from(
         entry: &PackEntry,
         archive: &'pk2 Pk2<B>,
@@ -387,6 +562,11 @@ impl<'pk2, B> Directory<'pk2, B> {
             .expect("invalid file object")
     }
 
+    // returns the index of the chain holding the children of this folder
+    fn children(&self) -> ChainIndex {
+        self.entry().children_position()
+    }
+
     // returns the chain this folder represents
     fn dir_chain(&self, chain: ChainIndex) -> &'pk2 PackBlockChain {
         self.archive.get_chain(chain).expect("invalid dir object")
@@ -396,6 +576,20 @@ impl<'pk2, B> Directory<'pk2, B> {
         self.entry().name()
     }
 
+    /// The bytes the name was read from, if the archive has been opened with
+    /// [`Pk2Options::keep_raw_names`](crate::Pk2Options::keep_raw_names).
+    pub fn raw_name(&self) -> Option<&'pk2 [u8]> {
+        self.entry().raw_name()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(
+            self.archive.get_chain(self.chain).expect("invalid dir object"),
+            self.entry_index,
+        )
+        .with_entry_count(self.entries().count())
+    }
+
     pub fn modify_time(&self) -> Option<SystemTime> {
         self.entry().modify_time()
     }
@@ -408,74 +602,83 @@ impl<'pk2, B> Directory<'pk2, B> {
         self.entry().create_time()
     }
 
-    pub fn open_file(&self, path: impl AsRef<Path>) -> ChainLookupResult<File<'pk2, B>> {
+    pub fn open_file(&self, path: impl AsRef<Path>) -> Result<File<'pk2, B>> {
+        let path = path.as_ref();
         let (chain, entry_idx, entry) = self
             .archive
             .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
-        Pk2::<B>::is_file(entry).map(|_| File::new(self.archive, chain, entry_idx))
+            .resolve_path_to_entry_and_parent(self.children(), path)
+            .context(Operation::OpenFile, path)?;
+        Pk2::<B>::is_file(entry)
+            .map(|_| File::new(self.archive, chain, entry_idx))
+            .context(Operation::OpenFile, path)
     }
 
-    pub fn open_directory(&self, path: impl AsRef<Path>) -> ChainLookupResult<Directory<'pk2, B>> {
+    pub fn open_directory(&self, path: impl AsRef<Path>) -> Result<Directory<'pk2, B>> {
+        let path = path.as_ref();
         let (chain, entry_idx, entry) = self
             .archive
             .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
+            .resolve_path_to_entry_and_parent(self.children(), path)
+            .context(Operation::OpenDirectory, path)?;
 
         if entry.as_directory().map(DirectoryEntry::is_normal_link).unwrap_or(false) {
             Ok(Directory::new(self.archive, chain, entry_idx))
         } else {
-            Err(ChainLookupError::NotFound)
+            Err(ChainLookupError::NotFound).context(Operation::OpenDirectory, path)
         }
     }
 
-    pub fn open(&self, path: impl AsRef<Path>) -> ChainLookupResult<DirEntry<'pk2, B>> {
-        let (chain, entry_idx, entry) = self
-            .archive
-            .block_manager
-            .resolve_path_to_entry_and_parent(self.chain, path.as_ref())?;
-        DirEntry::from(entry, self.archive, chain, entry_idx).ok_or(ChainLookupError::NotFound)
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<DirEntry<'pk2, B>> {
+        let path = path.as_ref();
+        self.open_impl(path).context(Operation::OpenEntry, path)
+    }
+
+    pub(super) fn open_impl(&self, path: &Path) -> Result<DirEntry<'pk2, B>> {
+        let (chain, entry_idx, entry) =
+            self.archive.block_manager.resolve_path_to_entry_and_parent(self.children(), path)?;
+        Ok(DirEntry::from(entry, self.archive, chain, entry_idx)
+            .ok_or(ChainLookupError::NotFound)?)
     }
 
     /// Invokes cb on every file in this directory and its children
     /// The callback gets invoked with its relative path to `base` and the file object.
-    // Todo, replace this with a file_paths iterator once generators are stable
-    pub fn for_each_file(
+    ///
+    /// Errors returned by `cb` carry the path of the file.
+    pub fn for_each_file(&self, cb: impl FnMut(&Path, File<B>) -> io::Result<()>) -> Result<()> {
+        self.for_each_file_in(Path::new(""), cb)
+    }
+
+    /// [`Directory::for_each_file`], with `base` being the path of this
+    /// directory that is put in front of the paths of failing files.
+    pub(super) fn for_each_file_in(
         &self,
+        base: &Path,
         mut cb: impl FnMut(&Path, File<B>) -> io::Result<()>,
-    ) -> io::Result<()> {
-        let mut path = std::path::PathBuf::new();
-        let mut stack = vec![*self];
-        let mut first_iteration = true;
-
-        while let Some(dir) = stack.pop() {
-            if !mem::take(&mut first_iteration) {
-                path.push(dir.name());
-            };
-            let mut dir_done = true;
-            for entry in dir.entries() {
-                match entry {
-                    DirEntry::Directory(dir) => {
-                        stack.push(dir);
-                        dir_done = false;
-                    }
-                    DirEntry::File(file) => {
-                        path.push(file.name());
-                        cb(&path, file)?;
-                        path.pop();
-                    }
-                }
-            }
-            if dir_done {
-                path.pop();
+    ) -> Result<()> {
+        for entry in self.walk() {
+            if let DirEntry::File(file) = entry.entry() {
+                cb(entry.path(), file).context(Operation::ForEachFile, base.join(entry.path()))?;
             }
         }
         Ok(())
     }
 
+    /// Returns a lazy recursive iterator over this directory and everything
+    /// inside of it, see [`Walk`] for its options.
+    pub fn walk(&self) -> Walk<'pk2, B> {
+        Walk::new(*self)
+    }
+
+    /// Returns an iterator over all entries below this directory whose path
+    /// relative to it matches the glob `pattern`, see [`Glob`].
+    pub fn glob(&self, pattern: &str) -> Glob<'pk2, B> {
+        Glob::new(*self, pattern)
+    }
+
     /// Returns an iterator over all files in this directory.
     pub fn files(&self) -> impl Iterator<Item = File<'pk2, B>> {
-        let chain = self.entry().children_position();
+        let chain = self.children();
         let archive = self.archive;
         self.dir_chain(chain)
             .entries()
@@ -486,7 +689,7 @@ impl<'pk2, B> Directory<'pk2, B> {
     /// Returns an iterator over all items in this directory excluding `.` and
     /// `..`.
     pub fn entries(&self) -> impl Iterator<Item = DirEntry<'pk2, B>> {
-        let chain = self.entry().children_position();
+        let chain = self.children();
         let archive = self.archive;
         self.dir_chain(chain)
             .entries()
@@ -495,6 +698,174 @@ impl<'pk2, B> Directory<'pk2, B> {
     }
 }
 
+/// An entry yielded by [`Walk`].
+pub struct WalkEntry<'pk2, B = std::fs::File> {
+    path: PathBuf,
+    depth: usize,
+    entry: DirEntry<'pk2, B>,
+}
+
+impl<'pk2, B> WalkEntry<'pk2, B> {
+    /// The path of this entry relative to the directory the walk started at,
+    /// which itself has an empty path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+
+    /// The depth of this entry relative to the directory the walk started at,
+    /// which itself has a depth of 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn entry(&self) -> DirEntry<'pk2, B> {
+        self.entry
+    }
+}
+
+type Sorter<'pk2, B> = Box<dyn FnMut(&DirEntry<'pk2, B>, &DirEntry<'pk2, B>) -> Ordering + 'pk2>;
+
+/// A lazy recursive iterator over a [`Directory`], yielding the directory
+/// itself first.
+///
+/// The options have to be set before the iteration starts.
+pub struct Walk<'pk2, B = std::fs::File> {
+    root: Option<Directory<'pk2, B>>,
+    min_depth: usize,
+    max_depth: usize,
+    contents_first: bool,
+    sorter: Option<Sorter<'pk2, B>>,
+    // the directories currently being walked, the innermost one last
+    stack: Vec<WalkLevel<'pk2, B>>,
+    // a directory yielded by skip_current_dir in contents first mode
+    pending: Option<WalkEntry<'pk2, B>>,
+}
+
+struct WalkLevel<'pk2, B> {
+    path: PathBuf,
+    entries: std::vec::IntoIter<DirEntry<'pk2, B>>,
+    // the directory of this level if it is yielded after its contents
+    deferred: Option<WalkEntry<'pk2, B>>,
+}
+
+impl<'pk2, B> Walk<'pk2, B> {
+    fn new(root: Directory<'pk2, B>) -> Self {
+        Walk {
+            root: Some(root),
+            min_depth: 0,
+            max_depth: usize::MAX,
+            contents_first: false,
+            sorter: None,
+            stack: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Only yields entries at or below the given depth.
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Does not descend into directories deeper than the given depth.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Yields the contents of directories before the directories themselves.
+    pub fn contents_first(mut self, yes: bool) -> Self {
+        self.contents_first = yes;
+        self
+    }
+
+    /// Sorts the entries of each directory with the given comparator before
+    /// yielding them.
+    pub fn sort_by<F>(mut self, cmp: F) -> Self
+    where
+        F: FnMut(&DirEntry<'pk2, B>, &DirEntry<'pk2, B>) -> Ordering + 'pk2,
+    {
+        self.sorter = Some(Box::new(cmp));
+        self
+    }
+
+    /// Sorts the entries of each directory by their name.
+    pub fn sort_by_name(self) -> Self {
+        self.sort_by(|a, b| a.name().cmp(b.name()))
+    }
+
+    /// Skips the remaining contents of the current directory. If the last
+    /// yielded entry was a directory this skips its contents, otherwise the
+    /// remaining entries of its parent directory are skipped.
+    pub fn skip_current_dir(&mut self) {
+        if let Some(level) = self.stack.pop() {
+            self.pending = level.deferred;
+        }
+    }
+
+    /// Descends into the entry if it is a directory, returning it if it is to
+    /// be yielded right away.
+    fn handle_entry(&mut self, entry: WalkEntry<'pk2, B>) -> Option<WalkEntry<'pk2, B>> {
+        if let DirEntry::Directory(dir) = entry.entry {
+            if entry.depth < self.max_depth {
+                let mut entries = dir.entries().collect::<Vec<_>>();
+                if let Some(sorter) = &mut self.sorter {
+                    entries.sort_by(|a, b| sorter(a, b));
+                }
+                let path = entry.path.clone();
+                let entries = entries.into_iter();
+                if self.contents_first {
+                    self.stack.push(WalkLevel { path, entries, deferred: Some(entry) });
+                    return None;
+                }
+                self.stack.push(WalkLevel { path, entries, deferred: None });
+            }
+        }
+        (entry.depth >= self.min_depth).then_some(entry)
+    }
+}
+
+impl<'pk2, B> Iterator for Walk<'pk2, B> {
+    type Item = WalkEntry<'pk2, B>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            let entry =
+                WalkEntry { path: PathBuf::new(), depth: 0, entry: DirEntry::Directory(root) };
+            if let Some(entry) = self.handle_entry(entry) {
+                return Some(entry);
+            }
+        }
+        if let Some(entry) = self.pending.take().filter(|entry| entry.depth >= self.min_depth) {
+            return Some(entry);
+        }
+        loop {
+            let depth = self.stack.len();
+            let level = self.stack.last_mut()?;
+            match level.entries.next() {
+                Some(entry) => {
+                    let path = level.path.join(entry.name());
+                    if let Some(entry) = self.handle_entry(WalkEntry { path, depth, entry }) {
+                        return Some(entry);
+                    }
+                }
+                None => {
+                    let level = self.stack.pop().unwrap();
+                    if let Some(entry) =
+                        level.deferred.filter(|entry| entry.depth >= self.min_depth)
+                    {
+                        return Some(entry);
+                    }
+                }
+            }
+        }
+    }
+}
+
 impl<B> Hash for Directory<'_, B> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         state.write_u64(self.chain.0);
@@ -515,3 +886,124 @@ impl<B: Read + Write + Seek> Hash for FileMut<'_, B> {
         state.write_usize(self.entry_index);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    use super::DirEntry;
+    use crate::Pk2;
+
+    fn walk_archive() -> Pk2<std::io::Cursor<Vec<u8>>> {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        for path in ["/a/b/file1", "/a/file2", "/c/d/e/file3", "/file4"] {
+            archive.create_file_streaming(path).unwrap();
+        }
+        archive
+    }
+
+    #[test]
+    fn walk() {
+        let archive = walk_archive();
+        let root = archive.open_root_dir();
+        let paths = |walk: super::Walk<'_, _>| {
+            walk.map(|entry| (entry.path().to_str().unwrap().to_owned(), entry.depth()))
+                .collect::<Vec<_>>()
+        };
+        let expected = [
+            ("", 0),
+            ("a", 1),
+            ("a/b", 2),
+            ("a/b/file1", 3),
+            ("a/file2", 2),
+            ("c", 1),
+            ("c/d", 2),
+            ("c/d/e", 3),
+            ("c/d/e/file3", 4),
+            ("file4", 1),
+        ];
+        let expected = expected.map(|(path, depth)| (path.to_owned(), depth));
+        assert_eq!(paths(root.walk().sort_by_name()), expected);
+
+        let walked = paths(root.walk().sort_by_name().min_depth(1).max_depth(2));
+        let expected_depths = expected.iter().filter(|(_, depth)| (1..=2).contains(depth));
+        assert_eq!(walked.iter().collect::<Vec<_>>(), expected_depths.collect::<Vec<_>>());
+
+        // contents first yields every directory after everything inside of it
+        let walked = paths(root.walk().contents_first(true));
+        assert_eq!(walked.len(), expected.len());
+        assert_eq!(walked.last().unwrap(), &("".to_owned(), 0));
+        for (idx, (path, _)) in walked.iter().enumerate() {
+            let prefix = format!("{}/", path);
+            assert!(walked[idx..].iter().all(|(other, _)| !other.starts_with(&prefix)));
+        }
+
+        let mut walk = archive.open_directory("/c").unwrap().walk();
+        let mut files = Vec::new();
+        while let Some(entry) = walk.next() {
+            match entry.entry() {
+                DirEntry::Directory(dir) if dir.name() == "d" => walk.skip_current_dir(),
+                DirEntry::Directory(_) => (),
+                DirEntry::File(_) => files.push(entry.into_path()),
+            }
+        }
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn for_each_file() {
+        let archive = walk_archive();
+        let mut files = Vec::new();
+        archive
+            .for_each_file("/a", |path, file| {
+                assert_eq!(path.file_name().unwrap(), file.name());
+                files.push(path.to_owned());
+                Ok(())
+            })
+            .unwrap();
+        files.sort();
+        assert_eq!(files, [Path::new("b/file1"), Path::new("file2")]);
+    }
+
+    #[test]
+    fn file_mut_flushes_created_empty_files() {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/empty").unwrap();
+        archive.create_file("/file").unwrap().write_all(&[1; 10]).unwrap();
+        let modified = archive.open_file("/file").unwrap().modify_time();
+        // opened files without any writes are left alone
+        let mut file = archive.open_file_mut("/file").unwrap();
+        file.set_modify_time(SystemTime::UNIX_EPOCH);
+        drop(file);
+
+        let archive = Pk2::open_in(std::io::Cursor::new(Vec::from(archive)), "").unwrap();
+        let empty = archive.open_file("/empty").unwrap();
+        assert_eq!(empty.size(), 0);
+        assert!(empty.modify_time().is_some());
+        assert_eq!(archive.open_file("/file").unwrap().modify_time(), modified);
+    }
+
+    #[test]
+    fn directory_open_is_relative_to_itself() {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        for (path, data) in [("/file", 1), ("/a/file", 2), ("/a/b/file", 3)] {
+            archive.create_file(path).unwrap().write_all(&[data]).unwrap();
+        }
+        let dir = archive.open_directory("/a").unwrap();
+        let read = |mut file: super::File<'_, _>| {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).unwrap();
+            buf
+        };
+        assert_eq!(read(dir.open_file("file").unwrap()), [2]);
+        assert_eq!(read(dir.open_file("b/file").unwrap()), [3]);
+        assert_eq!(read(dir.open_file("../file").unwrap()), [1]);
+        assert_eq!(dir.open_directory("b").unwrap().name(), "b");
+        assert!(dir.open_directory("a").is_err());
+        assert!(matches!(dir.open("b/file").unwrap(), DirEntry::File(file) if read(file) == [3]));
+        let e = dir.open("missing").err().unwrap();
+        assert_eq!(e.operation(), Some(crate::Operation::OpenEntry));
+    }
+}
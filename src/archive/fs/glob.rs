@@ -0,0 +1,205 @@
+//! Glob pattern matching over archive paths.
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use super::{DirEntry, Directory, WalkEntry};
+
+enum Segment {
+    /// `**`, matching any number of directories
+    AnyDirs,
+    /// a name without wildcards, looked up directly instead of listing the directory
+    Literal(String),
+    /// a name containing `*` or `?` wildcards
+    Pattern(Vec<char>),
+}
+
+/// An iterator over all entries matching a glob pattern, created by
+/// [`Directory::glob`] or [`Pk2::glob`](crate::Pk2::glob).
+///
+/// Patterns consist of `/` separated components that are matched against
+/// names ignoring ASCII case. In a component `*` matches any sequence of
+/// characters and `?` matches a single character, while a `**` component
+/// matches any number of nested directories. Directories are only descended
+/// into if they match the pattern, so only the parts of the archive that can
+/// contain matches are visited.
+pub struct Glob<'pk2, B = std::fs::File> {
+    segments: Vec<Segment>,
+    // entries that matched the first `n` segments
+    stack: Vec<(WalkEntry<'pk2, B>, usize)>,
+    // the paths yielded so far, if multiple `**` can reach the same entry in different ways
+    seen: Option<HashSet<PathBuf>>,
+}
+
+impl<'pk2, B> Glob<'pk2, B> {
+    pub(super) fn new(root: Directory<'pk2, B>, pattern: &str) -> Self {
+        let mut segments = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment {
+                "**" => Segment::AnyDirs,
+                _ if segment.contains(['*', '?']) => Segment::Pattern(segment.chars().collect()),
+                _ => Segment::Literal(segment.to_owned()),
+            })
+            .collect::<Vec<_>>();
+        // consecutive `**` match the same as a single one
+        segments.dedup_by(|a, b| matches!((a, b), (Segment::AnyDirs, Segment::AnyDirs)));
+        let any_dirs = segments.iter().filter(|segment| matches!(segment, Segment::AnyDirs));
+        let seen = (any_dirs.count() > 1).then(HashSet::new);
+        let root = WalkEntry { path: PathBuf::new(), depth: 0, entry: DirEntry::Directory(root) };
+        Glob { segments, stack: vec![(root, 0)], seen }
+    }
+
+    fn push_child(&mut self, parent: &WalkEntry<'pk2, B>, entry: DirEntry<'pk2, B>, seg: usize) {
+        let child =
+            WalkEntry { path: parent.path.join(entry.name()), depth: parent.depth + 1, entry };
+        self.stack.push((child, seg));
+    }
+}
+
+impl<'pk2, B> Iterator for Glob<'pk2, B> {
+    type Item = WalkEntry<'pk2, B>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((entry, seg)) = self.stack.pop() {
+            let Some(segment) = self.segments.get(seg) else {
+                if self.seen.as_mut().is_some_and(|seen| !seen.insert(entry.path.clone())) {
+                    continue;
+                }
+                return Some(entry);
+            };
+            let dir = match entry.entry {
+                DirEntry::Directory(dir) => Some(dir),
+                DirEntry::File(_) => None,
+            };
+            match segment {
+                Segment::AnyDirs => {
+                    // children are pushed first so that the entry itself is matched first
+                    if let Some(dir) = dir {
+                        let children = dir.entries().collect::<Vec<_>>();
+                        children
+                            .into_iter()
+                            .rev()
+                            .for_each(|child| self.push_child(&entry, child, seg));
+                    }
+                    self.stack.push((entry, seg + 1));
+                }
+                Segment::Literal(name) => {
+                    if let Some(child) = dir.and_then(|dir| dir.open(name).ok()) {
+                        self.push_child(&entry, child, seg + 1);
+                    }
+                }
+                Segment::Pattern(pattern) => {
+                    if let Some(dir) = dir {
+                        let children = dir
+                            .entries()
+                            .filter(|child| matches(pattern, child.name()))
+                            .collect::<Vec<_>>();
+                        children
+                            .into_iter()
+                            .rev()
+                            .for_each(|child| self.push_child(&entry, child, seg + 1));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Matches `name` against a pattern of `*` and `?` wildcards, ignoring ASCII
+/// case.
+fn matches(pattern: &[char], name: &str) -> bool {
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // the position of the last `*` and the name position it is currently matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` consume one more character
+                Some((star, star_n)) => {
+                    backtrack = Some((star, star_n + 1));
+                    p = star + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+    use crate::Pk2;
+
+    fn pat(pattern: &str) -> Vec<char> {
+        pattern.chars().collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(&pat("*.ddj"), "stone.DDJ"));
+        assert!(matches(&pat("*"), ""));
+        assert!(matches(&pat("a*b*c"), "aXbYbZc"));
+        assert!(matches(&pat("?at"), "cat"));
+        assert!(!matches(&pat("?at"), "at"));
+        assert!(!matches(&pat("*.ddj"), "stone.dds"));
+        assert!(!matches(&pat("a*b"), "aXbY"));
+    }
+
+    #[test]
+    fn glob() {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        for path in [
+            "/prim/mtrl/a.ddj",
+            "/prim/mtrl/sub/B.DDJ",
+            "/prim/mtrl/sub/deep/c.ddj",
+            "/prim/mtrl/sub/c.dds",
+            "/prim/mesh/d.ddj",
+            "/e.ddj",
+        ] {
+            archive.create_file_streaming(path).unwrap();
+        }
+        let glob = |pattern: &str| {
+            let mut paths = archive
+                .glob(pattern)
+                .unwrap()
+                .map(|entry| entry.path().to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            glob("/PRIM/mtrl/**/*.ddj"),
+            ["prim/mtrl/a.ddj", "prim/mtrl/sub/B.DDJ", "prim/mtrl/sub/deep/c.ddj"]
+        );
+        assert_eq!(glob("/prim/*/?.ddj"), ["prim/mesh/d.ddj", "prim/mtrl/a.ddj"]);
+        assert_eq!(glob("/**/c.*"), ["prim/mtrl/sub/c.dds", "prim/mtrl/sub/deep/c.ddj"]);
+        assert_eq!(glob("/prim/m*"), ["prim/mesh", "prim/mtrl"]);
+        assert_eq!(glob("/prim/missing/**"), Vec::<String>::new());
+        let all = glob("/**");
+        assert_eq!(all.len(), 12);
+        assert_eq!(all[0], "");
+        assert_eq!(glob("/**/**"), all);
+        assert_eq!(glob("/**/*/**"), all[1..]);
+        assert!(archive.glob("prim").is_err());
+
+        let dir = archive.open_directory("/prim/mtrl").unwrap();
+        let mut paths = dir.glob("**/*.dd?").map(|entry| entry.into_path()).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["a.ddj", "sub/B.DDJ", "sub/c.dds", "sub/deep/c.ddj"].map(std::path::PathBuf::from)
+        );
+    }
+}
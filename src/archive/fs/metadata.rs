@@ -0,0 +1,167 @@
+use std::time::SystemTime;
+
+use crate::constants::PK2_ROOT_BLOCK_VIRTUAL;
+use crate::raw::block_chain::PackBlockChain;
+use crate::raw::entry::PackEntry;
+use crate::raw::{ChainIndex, EntryOffset, StreamOffset};
+
+/// The type of an entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// Information about an entry and its position inside of the archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    kind: EntryKind,
+    size: u32,
+    access_time: Option<SystemTime>,
+    create_time: Option<SystemTime>,
+    modify_time: Option<SystemTime>,
+    pos_data: u64,
+    // the chain the entry resides in, its index in it and its stream offset
+    location: Option<(u64, usize, u64)>,
+}
+
+impl Metadata {
+    pub(in crate::archive) fn new(chain: &PackBlockChain, entry_index: usize) -> Self {
+        let ChainIndex(chain_index) = chain.chain_index();
+        // the root directory has no entry of its own, it only lives in the virtual root chain
+        let location = (chain.chain_index() != PK2_ROOT_BLOCK_VIRTUAL).then(|| {
+            let EntryOffset(offset) =
+                chain.stream_offset_for_entry(entry_index).expect("invalid entry");
+            (chain_index, entry_index, offset)
+        });
+        match &chain[entry_index] {
+            PackEntry::File(file) => {
+                let StreamOffset(pos_data) = file.pos_data();
+                Metadata {
+                    kind: EntryKind::File,
+                    size: file.size(),
+                    access_time: file.access_time(),
+                    create_time: file.create_time(),
+                    modify_time: file.modify_time(),
+                    pos_data,
+                    location,
+                }
+            }
+            PackEntry::Directory(dir) => Metadata {
+                kind: EntryKind::Directory,
+                size: 0,
+                access_time: dir.access_time(),
+                create_time: dir.create_time(),
+                modify_time: dir.modify_time(),
+                pos_data: dir.children_position().0,
+                location,
+            },
+            PackEntry::Empty(_) => panic!("invalid entry object"),
+        }
+    }
+
+    pub(in crate::archive) fn with_entry_count(mut self, entries: usize) -> Self {
+        debug_assert!(self.is_dir());
+        self.size = entries as u32;
+        self
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    /// The size of the file's data. For directories this is the number of
+    /// entries they contain, not counting `.` and `..`.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn access_time(&self) -> Option<SystemTime> {
+        self.access_time
+    }
+
+    pub fn create_time(&self) -> Option<SystemTime> {
+        self.create_time
+    }
+
+    pub fn modify_time(&self) -> Option<SystemTime> {
+        self.modify_time
+    }
+
+    /// The raw position field of the entry. For files this is the stream
+    /// offset of their data, for directories the offset of the chain holding
+    /// their children.
+    pub fn pos_data(&self) -> u64 {
+        self.pos_data
+    }
+
+    /// The index of the chain the entry resides in, which is the stream
+    /// offset of the chain's first block. This is `None` for the root
+    /// directory, as it has no entry of its own.
+    pub fn chain_index(&self) -> Option<u64> {
+        self.location.map(|(chain, ..)| chain)
+    }
+
+    /// The index of the entry inside of its chain.
+    pub fn entry_index(&self) -> Option<usize> {
+        self.location.map(|(_, idx, _)| idx)
+    }
+
+    /// The stream offset of the entry itself.
+    pub fn entry_offset(&self) -> Option<u64> {
+        self.location.map(|(.., offset)| offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::EntryKind;
+    use crate::constants::{PK2_FILE_ENTRY_SIZE, PK2_ROOT_BLOCK};
+    use crate::Pk2;
+
+    #[test]
+    fn metadata() {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/dir/file").unwrap().write_all(&[1; 123]).unwrap();
+        archive.create_file("/dir/sub/file").unwrap();
+
+        let root = archive.metadata("/").unwrap();
+        assert!(root.is_dir());
+        assert_eq!(root.pos_data(), PK2_ROOT_BLOCK.0);
+        assert_eq!(root.chain_index(), None);
+
+        let dir = archive.metadata("/DIR").unwrap();
+        assert_eq!(dir, archive.open_directory("/dir").unwrap().metadata());
+        assert_eq!(dir.kind(), EntryKind::Directory);
+        // the directory contains `file` and `sub`
+        assert_eq!(dir.size(), 2);
+        assert_eq!(root.size(), 1);
+        assert_eq!(dir.chain_index(), Some(PK2_ROOT_BLOCK.0));
+        // the root chain starts with its `.` entry
+        assert_eq!(dir.entry_index(), Some(1));
+        assert_eq!(dir.entry_offset(), Some(PK2_ROOT_BLOCK.0 + PK2_FILE_ENTRY_SIZE as u64));
+
+        let file = archive.metadata("/dir/file").unwrap();
+        assert_eq!(file, archive.open_file("/dir/file").unwrap().metadata());
+        assert!(file.is_file());
+        assert_eq!(file.size(), 123);
+        assert!(file.modify_time().is_some());
+        assert_eq!(file.chain_index(), Some(dir.pos_data()));
+        // `.` and `..` come first in the chain of a directory
+        assert_eq!(file.entry_index(), Some(2));
+        assert_eq!(archive.read("/dir/file").unwrap().len(), 123);
+        let data = Vec::from(archive);
+        let pos = file.pos_data() as usize;
+        assert_eq!(data[pos..pos + 123], [1; 123]);
+    }
+}
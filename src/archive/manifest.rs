@@ -0,0 +1,428 @@
+//! Serializable dumps of the index of an archive.
+use std::collections::HashSet;
+use std::fs as stdfs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archive::fs::reserve_data;
+use crate::archive::Pk2;
+use crate::constants::{PK2_CURRENT_DIR_IDENT, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK};
+use crate::error::{ChainLookupError, Context, Error, ErrorKind, Operation, Result};
+use crate::filetime::FILETIME;
+use crate::options::Pk2Options;
+use crate::raw::entry::PackEntry;
+use crate::raw::{BlockOffset, ChainIndex, StreamOffset};
+
+/// A dump of the whole index of an archive, see [`Pk2::export_index`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexManifest {
+    /// The chain of the root directory.
+    pub root: ManifestChain,
+}
+
+/// The chain of blocks holding the entries of a directory.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestChain {
+    /// The index of the chain, which is the offset of its first block.
+    pub chain: u64,
+    /// The offsets of all blocks of the chain in order.
+    pub blocks: Vec<u64>,
+    /// The files and directories of the chain in the order they are stored
+    /// in, leaving out empty entries as well as the `.` and `..` links.
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ManifestEntry {
+    Directory(ManifestDirectory),
+    File(ManifestFile),
+}
+
+impl ManifestEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            ManifestEntry::Directory(dir) => &dir.name,
+            ManifestEntry::File(file) => &file.name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestDirectory {
+    pub name: String,
+    #[serde(flatten)]
+    pub times: ManifestTimes,
+    pub children: ManifestChain,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub name: String,
+    #[serde(flatten)]
+    pub times: ManifestTimes,
+    pub size: u32,
+    pub pos_data: u64,
+}
+
+/// The timestamps of an entry as stored in the archive, counting the 100
+/// nanosecond intervals since 1601-01-01 like windows' `FILETIME`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestTimes {
+    pub access_time: u64,
+    pub create_time: u64,
+    pub modify_time: u64,
+}
+
+impl ManifestTimes {
+    fn new(access_time: FILETIME, create_time: FILETIME, modify_time: FILETIME) -> Self {
+        ManifestTimes {
+            access_time: access_time.ticks(),
+            create_time: create_time.ticks(),
+            modify_time: modify_time.ticks(),
+        }
+    }
+
+    fn filetimes(&self) -> [FILETIME; 3] {
+        [self.access_time, self.create_time, self.modify_time].map(FILETIME::from_ticks)
+    }
+}
+
+impl<B> Pk2<B> {
+    /// Dumps the whole index of the archive, including the position of every
+    /// chain, block and file data, for example to compare the layout of two
+    /// archives. For lazily opened archives this loads every chain.
+    ///
+    /// The archive can be rebuilt from the dump and the data of its files with
+    /// a [`ManifestBuilder`].
+    pub fn export_index(&self) -> Result<IndexManifest> {
+        let root = self.export_chain(PK2_ROOT_BLOCK, &mut HashSet::new());
+        root.map(|root| IndexManifest { root }).operation(Operation::ExportIndex)
+    }
+
+    fn export_chain(
+        &self,
+        chain: ChainIndex,
+        ancestors: &mut HashSet<ChainIndex>,
+    ) -> Result<ManifestChain> {
+        let ChainIndex(offset) = chain;
+        // a directory containing itself would make the tree infinite
+        if !ancestors.insert(chain) {
+            return Err(Error::from(ErrorKind::CorruptedFile).with_offset(offset));
+        }
+        let pack_chain =
+            self.get_chain(chain).ok_or(ChainLookupError::InvalidChainIndex).offset(offset)?;
+        let mut entries = Vec::new();
+        for entry in pack_chain.entries() {
+            match entry {
+                PackEntry::File(file) => entries.push(ManifestEntry::File(ManifestFile {
+                    name: file.name().to_owned(),
+                    times: ManifestTimes::new(file.access_time, file.create_time, file.modify_time),
+                    size: file.size(),
+                    pos_data: file.pos_data().0,
+                })),
+                PackEntry::Directory(dir) if dir.is_normal_link() => {
+                    entries.push(ManifestEntry::Directory(ManifestDirectory {
+                        name: dir.name().to_owned(),
+                        times: ManifestTimes::new(
+                            dir.access_time,
+                            dir.create_time,
+                            dir.modify_time,
+                        ),
+                        children: self.export_chain(dir.children_position(), ancestors)?,
+                    }))
+                }
+                _ => (),
+            }
+        }
+        ancestors.remove(&chain);
+        Ok(ManifestChain {
+            chain: offset,
+            blocks: pack_chain.block_offsets().map(|BlockOffset(offset)| offset).collect(),
+            entries,
+        })
+    }
+}
+
+/// Rebuilds an archive from an [`IndexManifest`] and a directory holding the
+/// data of its files.
+///
+/// The tree, the names, the order of the entries and their timestamps are
+/// taken from the manifest, while the data of every file is read from the
+/// file at the same relative path below the directory. Chains, blocks and
+/// file data are laid out anew, so the loose files may differ in size from
+/// what the manifest records.
+pub struct ManifestBuilder<'a> {
+    manifest: &'a IndexManifest,
+    files: PathBuf,
+    options: Pk2Options,
+}
+
+impl<'a> ManifestBuilder<'a> {
+    pub fn new(manifest: &'a IndexManifest, files: impl Into<PathBuf>) -> Self {
+        ManifestBuilder { manifest, files: files.into(), options: Pk2Options::default() }
+    }
+
+    /// Sets the options the archive is created with.
+    pub fn options(mut self, options: Pk2Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Builds a new archive at the given path.
+    pub fn build<P: AsRef<Path>, K: AsRef<[u8]>>(&self, path: P, key: K) -> Result<Pk2> {
+        let mut archive = Pk2::create_new_with(path, key, &self.options)?;
+        self.fill_chain(&mut archive, PK2_ROOT_BLOCK, Path::new("/"), &self.manifest.root)?;
+        Ok(archive)
+    }
+
+    /// Builds a new archive in the given stream.
+    pub fn build_in<B, K>(&self, stream: B, key: K) -> Result<Pk2<B>>
+    where
+        B: Read + Write + Seek,
+        K: AsRef<[u8]>,
+    {
+        let mut archive = Pk2::create_new_in_with(stream, key, &self.options)?;
+        self.fill_chain(&mut archive, PK2_ROOT_BLOCK, Path::new("/"), &self.manifest.root)?;
+        Ok(archive)
+    }
+
+    fn fill_chain<B: Read + Write + Seek>(
+        &self,
+        archive: &mut Pk2<B>,
+        chain: ChainIndex,
+        path: &Path,
+        manifest: &ManifestChain,
+    ) -> Result<()> {
+        for entry in &manifest.entries {
+            let path = path.join(entry.name());
+            match entry {
+                ManifestEntry::Directory(dir) => {
+                    let children = Self::add_directory(archive, chain, dir)
+                        .context(Operation::CreateDirectory, &path)?;
+                    self.fill_chain(archive, children, &path, &dir.children)?;
+                }
+                ManifestEntry::File(file) => self
+                    .add_file(archive, chain, &path, file)
+                    .context(Operation::CreateFile, &path)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn add_directory<B: Read + Write + Seek>(
+        archive: &mut Pk2<B>,
+        chain: ChainIndex,
+        dir: &ManifestDirectory,
+    ) -> io::Result<ChainIndex> {
+        let entry_idx = Self::add_entry(archive, chain, &dir.name)?;
+        let (blowfish, names) = (archive.blowfish.as_ref(), archive.options.names());
+        let stream = archive.stream.get_mut();
+        let current_chain = archive.block_manager.get_mut(chain).unwrap();
+        let new_chain = crate::io::allocate_new_block_chain(
+            blowfish,
+            names,
+            &mut *stream,
+            &mut archive.free_space,
+            current_chain,
+            &dir.name,
+            entry_idx,
+        )?;
+        let entry = current_chain[entry_idx].as_directory_mut().unwrap();
+        [entry.access_time, entry.create_time, entry.modify_time] = dir.times.filetimes();
+        crate::io::write_chain_entry(blowfish, names, stream, current_chain, entry_idx)?;
+
+        let children = new_chain.chain_index();
+        archive.block_manager.insert(children, new_chain);
+        Ok(children)
+    }
+
+    fn add_file<B: Read + Write + Seek>(
+        &self,
+        archive: &mut Pk2<B>,
+        chain: ChainIndex,
+        path: &Path,
+        file: &ManifestFile,
+    ) -> io::Result<()> {
+        // the name decides which loose file is read, so it has to be checked beforehand
+        Self::validate_name(archive, &file.name)?;
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(ChainLookupError::InvalidPath.into());
+        }
+        let data = stdfs::read(self.files.join(relative))?;
+        if data.len() > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "file is too large"));
+        }
+        let entry_idx = Self::add_entry(archive, chain, &file.name)?;
+        let (blowfish, names) = (archive.blowfish.as_ref(), archive.options.names());
+        let stream = archive.stream.get_mut();
+        let current_chain = archive.block_manager.get_mut(chain).unwrap();
+        let entry = &mut current_chain[entry_idx];
+        *entry = PackEntry::new_file(&*file.name, StreamOffset(0), 0, entry.next_block());
+        let fentry = entry.as_file_mut().unwrap();
+        if !data.is_empty() {
+            reserve_data(&mut archive.free_space, fentry, data.len());
+            crate::io::write_data_at(&mut *stream, fentry.pos_data, &data)?;
+        }
+        [fentry.access_time, fentry.create_time, fentry.modify_time] = file.times.filetimes();
+        crate::io::write_chain_entry(blowfish, names, stream, current_chain, entry_idx)
+    }
+
+    /// Makes sure `name` is a valid name for a new entry of the archive.
+    fn validate_name<B>(archive: &Pk2<B>, name: &str) -> io::Result<()> {
+        if name == PK2_CURRENT_DIR_IDENT || name == PK2_PARENT_DIR_IDENT {
+            return Err(ChainLookupError::InvalidPath.into());
+        }
+        archive.options.names().validate(name)?;
+        Ok(())
+    }
+
+    /// Returns the index of an empty entry in `chain` for an entry called
+    /// `name`, making sure the name is valid and not taken yet.
+    fn add_entry<B: Read + Write + Seek>(
+        archive: &mut Pk2<B>,
+        chain: ChainIndex,
+        name: &str,
+    ) -> io::Result<usize> {
+        Self::validate_name(archive, name)?;
+        let current_chain =
+            archive.block_manager.get_mut(chain).ok_or(ChainLookupError::InvalidChainIndex)?;
+        if current_chain.find_entry(name).is_some() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        Pk2::<B>::find_or_allocate_empty_entry(
+            &mut archive.free_space,
+            archive.blowfish.as_ref(),
+            archive.options.names(),
+            archive.stream.get_mut(),
+            current_chain,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Write};
+    use std::time::{Duration, SystemTime};
+
+    use super::{IndexManifest, ManifestBuilder, ManifestChain, ManifestEntry};
+    use crate::Pk2;
+
+    /// The manifest with all positions zeroed out.
+    fn tree(chain: &ManifestChain) -> ManifestChain {
+        let entries = chain
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                ManifestEntry::Directory(dir) => {
+                    let mut dir = dir.clone();
+                    dir.children = tree(&dir.children);
+                    ManifestEntry::Directory(dir)
+                }
+                ManifestEntry::File(file) => {
+                    ManifestEntry::File(super::ManifestFile { pos_data: 0, ..file.clone() })
+                }
+            })
+            .collect();
+        ManifestChain { chain: 0, blocks: Vec::new(), entries }
+    }
+
+    #[test]
+    fn export_and_rebuild() {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/b/file").unwrap().write_all(&[1; 100]).unwrap();
+        for i in 0..25u8 {
+            archive.create_file(format!("/a/{i}")).unwrap().write_all(&[i; 10]).unwrap();
+        }
+        let mut file = archive.create_file("/a/sub/empty").unwrap();
+        file.set_create_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        file.flush_drop().unwrap();
+
+        let manifest = archive.export_index().unwrap();
+        assert_eq!(manifest.root.chain, 256);
+        assert_eq!(manifest.root.entries.len(), 2);
+        let ManifestEntry::Directory(a) = &manifest.root.entries[1] else { panic!() };
+        assert_eq!(a.name, "a");
+        // the 25 files and the subdirectory don't fit into a single block next to . and ..
+        assert_eq!(a.children.blocks.len(), 2);
+        assert_eq!(a.children.blocks[0], a.children.chain);
+        let ManifestEntry::File(file) = &a.children.entries[3] else { panic!() };
+        assert_eq!((&*file.name, file.size), ("3", 10));
+        assert_eq!(
+            file.pos_data,
+            archive.metadata("/a/3").unwrap().pos_data(),
+            "positions are dumped as they are"
+        );
+
+        let json = serde_json::to_string_pretty(&manifest).unwrap();
+        let manifest = serde_json::from_str::<IndexManifest>(&json).unwrap();
+        assert_eq!(manifest, archive.export_index().unwrap());
+
+        let dir = std::env::temp_dir().join(format!("pk2_manifest_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        archive
+            .for_each_file("/", |path, mut file| {
+                let path = dir.join(path);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::io::copy(&mut file, &mut std::fs::File::create(path)?).map(drop)
+            })
+            .unwrap();
+        std::fs::write(dir.join("b/file"), [2; 5000]).unwrap();
+
+        let rebuilt = ManifestBuilder::new(&manifest, &dir).build_in(Cursor::new(Vec::new()), "");
+        let rebuilt = Pk2::open_in(Cursor::new(Vec::from(rebuilt.unwrap())), "").unwrap();
+        assert_eq!(rebuilt.read("/a/24").unwrap(), [24; 10]);
+        assert_eq!(rebuilt.read("/b/file").unwrap(), [2; 5000]);
+        assert_eq!(rebuilt.read("/a/sub/empty").unwrap(), [0u8; 0]);
+        assert_eq!(
+            rebuilt.metadata("/a/sub/empty").unwrap().create_time(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000))
+        );
+        let mut expected = tree(&manifest.root);
+        let ManifestEntry::Directory(b) = &mut expected.entries[0] else { panic!() };
+        let ManifestEntry::File(file) = &mut b.children.entries[0] else { panic!() };
+        file.size = 5000;
+        assert_eq!(tree(&rebuilt.export_index().unwrap().root), expected);
+
+        std::fs::remove_file(dir.join("a/3")).unwrap();
+        let err = ManifestBuilder::new(&manifest, &dir)
+            .build_in(Cursor::new(Vec::new()), "")
+            .err()
+            .unwrap();
+        assert_eq!(err.path(), Some(std::path::Path::new("/a/3")));
+        assert_eq!(err.io_kind(), std::io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_do_not_escape_the_directory() {
+        use crate::{ChainLookupError, ErrorKind, InvalidName};
+
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        archive.create_file("/dir/file").unwrap().write_all(&[1; 10]).unwrap();
+        let manifest = archive.export_index().unwrap();
+
+        let files =
+            std::env::temp_dir().join(format!("pk2_manifest_escape_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&files);
+        // reading either of these directories would fail with an io error
+        std::fs::create_dir_all(files.join("dir")).unwrap();
+        std::fs::create_dir_all(files.join("outside")).unwrap();
+        for name in ["../outside", ".."] {
+            let mut manifest = manifest.clone();
+            let ManifestEntry::Directory(dir) = &mut manifest.root.entries[0] else { panic!() };
+            let ManifestEntry::File(file) = &mut dir.children.entries[0] else { panic!() };
+            file.name = name.to_owned();
+            let res = ManifestBuilder::new(&manifest, &files).build_in(Cursor::new(Vec::new()), "");
+            match res.err().unwrap().kind() {
+                ErrorKind::InvalidName(InvalidName::Separator) => assert_eq!(name, "../outside"),
+                ErrorKind::Lookup(ChainLookupError::InvalidPath) => assert_eq!(name, ".."),
+                kind => panic!("unexpected error {kind}"),
+            }
+        }
+        std::fs::remove_dir_all(&files).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//! Self-contained patches that turn one version of an archive into another.
+//!
+//! A [`PatchFile`] is created from two archives with [`create`] and carries
+//! the contents of every added or replaced file, so it can be shipped on its
+//! own and applied to a copy of the old archive with [`apply`].
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::archive::diff::{diff, entries_by_name, ChangeKind};
+use crate::archive::fs::{DirEntry, Directory};
+use crate::archive::Pk2;
+use crate::error::{Context, Error, ErrorKind, Operation, Result};
+use crate::hash::{ContentHasher, Hashed};
+use crate::io::{ReadStream, SyncData};
+
+const PATCH_MAGIC: &[u8; 8] = b"PK2PTCH1";
+
+const OP_CREATE_DIRECTORY: u8 = 0;
+const OP_ADD_FILE: u8 = 1;
+const OP_REPLACE_FILE: u8 = 2;
+const OP_DELETE: u8 = 3;
+
+/// A single step of a [`PatchFile`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchOp {
+    CreateDirectory {
+        path: PathBuf,
+    },
+    AddFile {
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+    ReplaceFile {
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+    /// Removes a file, or a directory along with all of its contents.
+    Delete {
+        path: PathBuf,
+    },
+}
+
+impl PatchOp {
+    pub fn path(&self) -> &Path {
+        match self {
+            PatchOp::CreateDirectory { path }
+            | PatchOp::AddFile { path, .. }
+            | PatchOp::ReplaceFile { path, .. }
+            | PatchOp::Delete { path } => path,
+        }
+    }
+}
+
+/// The operations turning one archive into another, along with the
+/// [`state_hash`] of the archive they apply to.
+///
+/// Timestamps are not part of a patch, files written by it get the time they
+/// have been written at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatchFile {
+    source_hash: u64,
+    ops: Vec<PatchOp>,
+}
+
+impl PatchFile {
+    /// The [`state_hash`] an archive has to have for the patch to apply.
+    pub fn source_hash(&self) -> u64 {
+        self.source_hash
+    }
+
+    /// The operations in the order they are applied in.
+    pub fn ops(&self) -> &[PatchOp] {
+        &self.ops
+    }
+
+    /// Writes the patch in its binary format, which ends with a checksum so
+    /// that truncated or damaged patches are rejected when reading them.
+    ///
+    /// Like the [`state_hash`] the checksum is a 64 bit FNV-1a hash. It
+    /// catches accidental damage, but not deliberate tampering, so patches
+    /// from untrusted sources have to be authenticated by other means.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<()> {
+        self.write_to_impl(writer).operation(Operation::WritePatch)
+    }
+
+    fn write_to_impl<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = Hashed::new(writer);
+        writer.write_all(PATCH_MAGIC)?;
+        writer.write_u64::<LE>(self.source_hash)?;
+        writer.write_u64::<LE>(self.ops.len() as u64)?;
+        for op in &self.ops {
+            let (tag, data) = match op {
+                PatchOp::CreateDirectory { .. } => (OP_CREATE_DIRECTORY, None),
+                PatchOp::AddFile { data, .. } => (OP_ADD_FILE, Some(data)),
+                PatchOp::ReplaceFile { data, .. } => (OP_REPLACE_FILE, Some(data)),
+                PatchOp::Delete { .. } => (OP_DELETE, None),
+            };
+            let path = op.path().to_str().ok_or(io::ErrorKind::InvalidInput)?;
+            writer.write_u8(tag)?;
+            writer
+                .write_u16::<LE>(path.len().try_into().map_err(|_| io::ErrorKind::InvalidInput)?)?;
+            writer.write_all(path.as_bytes())?;
+            if let Some(data) = data {
+                writer.write_u32::<LE>(
+                    data.len().try_into().map_err(|_| io::ErrorKind::InvalidInput)?,
+                )?;
+                writer.write_all(data)?;
+            }
+        }
+        let (checksum, mut writer) = writer.finish();
+        writer.write_u64::<LE>(checksum)?;
+        writer.flush()
+    }
+
+    /// Reads a patch written by [`PatchFile::write_to`], failing with
+    /// [`ErrorKind::CorruptedFile`] if it is damaged.
+    pub fn read_from<R: Read>(reader: R) -> Result<Self> {
+        match Self::read_from_impl(reader) {
+            // truncated or malformed input
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+                ) =>
+            {
+                Err(Error::from(ErrorKind::CorruptedFile).with_operation(Operation::ReadPatch))
+            }
+            res => res.operation(Operation::ReadPatch),
+        }
+    }
+
+    fn read_from_impl<R: Read>(reader: R) -> io::Result<Self> {
+        let corrupted = || io::Error::from(io::ErrorKind::InvalidData);
+        let mut reader = Hashed::new(reader);
+        let mut magic = [0; PATCH_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != *PATCH_MAGIC {
+            return Err(corrupted());
+        }
+        let source_hash = reader.read_u64::<LE>()?;
+        let mut ops = Vec::new();
+        for _ in 0..reader.read_u64::<LE>()? {
+            let tag = reader.read_u8()?;
+            let mut path = vec![0; reader.read_u16::<LE>()? as usize];
+            reader.read_exact(&mut path)?;
+            let path = PathBuf::from(String::from_utf8(path).map_err(|_| corrupted())?);
+            let mut data = || {
+                let len = reader.read_u32::<LE>()? as u64;
+                // the length is not trusted to size the buffer up front
+                let mut data = Vec::new();
+                if (&mut reader).take(len).read_to_end(&mut data)? as u64 != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                Ok(data)
+            };
+            ops.push(match tag {
+                OP_CREATE_DIRECTORY => PatchOp::CreateDirectory { path },
+                OP_ADD_FILE => PatchOp::AddFile { data: data()?, path },
+                OP_REPLACE_FILE => PatchOp::ReplaceFile { data: data()?, path },
+                OP_DELETE => PatchOp::Delete { path },
+                _ => return Err(corrupted()),
+            });
+        }
+        let (checksum, mut reader) = reader.finish();
+        if reader.read_u64::<LE>()? != checksum || reader.read(&mut [0])? != 0 {
+            return Err(corrupted());
+        }
+        Ok(PatchFile { source_hash, ops })
+    }
+}
+
+/// Creates a patch that turns `old` into `new` when applied to `old`.
+pub fn create<A: ReadStream, B: ReadStream>(old: &Pk2<A>, new: &Pk2<B>) -> Result<PatchFile> {
+    create_impl(old, new).operation(Operation::CreatePatch)
+}
+
+fn create_impl<A: ReadStream, B: ReadStream>(old: &Pk2<A>, new: &Pk2<B>) -> Result<PatchFile> {
+    let source_hash = state_hash(old)?;
+    let mut ops = Vec::new();
+    // the changes are ordered so that applying them one after another always works
+    for change in diff(old, new)?.changes() {
+        let path = change.path().to_owned();
+        ops.push(match change.kind() {
+            ChangeKind::AddedDirectory => PatchOp::CreateDirectory { path },
+            ChangeKind::AddedFile { .. } => PatchOp::AddFile { data: new.read(&path)?, path },
+            ChangeKind::ModifiedFile { .. } => {
+                PatchOp::ReplaceFile { data: new.read(&path)?, path }
+            }
+            ChangeKind::RemovedFile { .. } | ChangeKind::RemovedDirectory => {
+                PatchOp::Delete { path }
+            }
+            ChangeKind::TimesChanged { .. } => continue,
+        });
+    }
+    Ok(PatchFile { source_hash, ops })
+}
+
+/// Applies the patch to `archive` in a single [transaction](Pk2::transaction),
+/// so that either all of its operations take effect or none of them.
+///
+/// Fails with [`ErrorKind::SourceMismatch`] without touching the archive if
+/// its [`state_hash`] differs from the one the patch was created from.
+pub fn apply<B>(archive: &mut Pk2<B>, patch: PatchFile) -> Result<()>
+where
+    B: Read + Write + Seek + SyncData,
+{
+    apply_impl(archive, patch).operation(Operation::ApplyPatch)
+}
+
+fn apply_impl<B>(archive: &mut Pk2<B>, patch: PatchFile) -> Result<()>
+where
+    B: Read + Write + Seek + SyncData,
+{
+    if state_hash(archive)? != patch.source_hash {
+        return Err(Error::from(ErrorKind::SourceMismatch));
+    }
+    archive.transaction(|tx| {
+        for op in patch.ops {
+            match op {
+                PatchOp::CreateDirectory { path } => tx.create_directory(path)?,
+                PatchOp::AddFile { path, data } => {
+                    let mut file = tx.create_file(&path)?;
+                    file.write_all(&data).context(Operation::Write, &path)?;
+                    file.flush_drop().context(Operation::Write, &path)?;
+                }
+                PatchOp::ReplaceFile { path, data } => {
+                    tx.delete_file(&path)?;
+                    let mut file = tx.create_file(&path)?;
+                    file.write_all(&data).context(Operation::Write, &path)?;
+                    file.flush_drop().context(Operation::Write, &path)?;
+                }
+                PatchOp::Delete { path } => {
+                    if tx.metadata(&path)?.is_dir() {
+                        tx.remove_dir_all(path)?;
+                    } else {
+                        tx.delete_file(path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Hashes the tree of the archive, covering the names, kinds and sizes of all
+/// entries as well as the contents of all files but not their timestamps.
+/// Names are hashed lowercased as paths are resolved ignoring ASCII case.
+///
+/// The hash is a 64 bit FNV-1a, which is fast and tells apart archives that
+/// differ by accident, but is no cryptographic digest: an archive can be
+/// crafted to match any given hash.
+pub fn state_hash<B: ReadStream>(archive: &Pk2<B>) -> Result<u64> {
+    let mut hasher = ContentHasher::new();
+    hash_directory(&mut hasher, archive.open_root_dir(), Path::new("/"))?;
+    Ok(hasher.finish())
+}
+
+fn hash_directory<B: ReadStream>(
+    hasher: &mut ContentHasher,
+    dir: Directory<'_, B>,
+    path: &Path,
+) -> Result<()> {
+    let entries = entries_by_name(dir);
+    hasher.update(&(entries.len() as u64).to_le_bytes());
+    for (name, entry) in entries {
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        match entry {
+            DirEntry::File(mut file) => {
+                hasher.update(&[OP_ADD_FILE]);
+                hasher.update(&file.size().to_le_bytes());
+                io::copy(&mut file, hasher).context(Operation::Read, path.join(file.name()))?;
+            }
+            DirEntry::Directory(dir) => {
+                hasher.update(&[OP_CREATE_DIRECTORY]);
+                let path = path.join(dir.name());
+                hash_directory(hasher, dir, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::{apply, create, state_hash, PatchFile, PatchOp};
+    use crate::archive::diff::{diff, ChangeKind};
+    use crate::{ErrorKind, Operation, Pk2};
+
+    fn archive(files: &[(&str, &[u8])]) -> Pk2<std::io::Cursor<Vec<u8>>> {
+        let mut archive = Pk2::create_new_in_memory("").unwrap();
+        for &(path, data) in files {
+            archive.create_file(path).unwrap().write_all(data).unwrap();
+        }
+        archive
+    }
+
+    #[test]
+    fn create_and_apply() {
+        let old_files: &[(&str, &[u8])] = &[
+            ("/same", &[1; 10]),
+            ("/replaced", &[2; 10]),
+            ("/removed/a", &[3; 10]),
+            ("/removed/sub/b", &[4; 10]),
+            ("/kind", &[5; 10]),
+        ];
+        let old = archive(old_files);
+        let mut new = archive(&[
+            ("/same", &[1; 10]),
+            ("/replaced", &[6; 20]),
+            ("/added/c", &[7; 10]),
+            ("/empty", &[]),
+            ("/KIND/d", &[8; 10]),
+        ]);
+        new.create_directory("/added/sub").unwrap();
+
+        let patch = create(&old, &new).unwrap();
+        assert!(patch.ops().contains(&PatchOp::Delete { path: "/kind".into() }));
+        let mut buf = Vec::new();
+        patch.write_to(&mut buf).unwrap();
+        assert_eq!(PatchFile::read_from(&buf[..]).unwrap(), patch);
+        let last = buf.len() - 1;
+        let mut damaged = [buf.clone(), buf[..last].to_vec(), [&buf[..], &[0]].concat()];
+        damaged[0][last] ^= 1;
+        for data in damaged {
+            let e = PatchFile::read_from(&data[..]).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::CorruptedFile));
+            assert_eq!(e.operation(), Some(Operation::ReadPatch));
+        }
+
+        let mut target = archive(old_files);
+        assert_eq!(state_hash(&target).unwrap(), patch.source_hash());
+        apply(&mut target, patch.clone()).unwrap();
+        assert_eq!(state_hash(&target).unwrap(), state_hash(&new).unwrap());
+        let changes = diff(&target, &new).unwrap();
+        assert!(changes
+            .changes()
+            .iter()
+            .all(|change| matches!(change.kind(), ChangeKind::TimesChanged { .. })));
+        assert_eq!(target.read("/empty").unwrap(), [0u8; 0]);
+
+        // the archive no longer matches the source, so applying again changes nothing
+        let before = target.stream.lock().get_ref().clone();
+        let err = apply(&mut target, patch).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::SourceMismatch));
+        assert_eq!(*target.stream.lock().get_ref(), before);
+    }
+}
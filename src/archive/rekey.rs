@@ -0,0 +1,135 @@
+//! Changing the key of an archive in place.
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::archive::transaction::{apply, Writes};
+use crate::archive::Pk2;
+use crate::blowfish::Blowfish;
+use crate::constants::PK2_FILE_BLOCK_SIZE;
+use crate::error::{Context, Operation, Result};
+use crate::io::{RawIo, SyncData};
+use crate::raw::header::PackHeader;
+use crate::raw::BlockOffset;
+
+impl<B> Pk2<B> {
+    /// Whether the index of the archive is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.blowfish.is_some()
+    }
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Write + Seek + SyncData,
+{
+    /// Re-encrypts the index of the archive with `new_key`. An empty key
+    /// turns the encryption off, while a non-empty key turns it on for
+    /// unencrypted archives.
+    ///
+    /// Only the blocks of the index and the header are rewritten, as file
+    /// data is never encrypted. The blocks are written before the header, and
+    /// for archives opened from a path all writes go through the same journal
+    /// as [transactions](Pk2::transaction), so an interrupted rekey is
+    /// completed by the next [`Pk2::open`].
+    pub fn rekey<K: AsRef<[u8]>>(&mut self, new_key: K) -> Result<()> {
+        self.rekey_impl(new_key.as_ref()).operation(Operation::Rekey)
+    }
+
+    fn rekey_impl(&mut self, new_key: &[u8]) -> Result<()> {
+        let profile = *self.options.get_profile();
+        let blowfish =
+            (!new_key.is_empty()).then(|| Blowfish::new(new_key, &profile.salt)).transpose()?;
+        self.block_manager.load_all()?;
+
+        let stream = self.stream.get_mut();
+        stream.seek(SeekFrom::Start(0))?;
+        let mut header = PackHeader::from_reader(&mut *stream)?;
+        header.set_encryption(blowfish.as_ref(), &profile);
+        let mut header_buf = Vec::new();
+        header.to_writer(&mut header_buf)?;
+
+        let mut writes = Writes::new();
+        for chain in self.block_manager.chains() {
+            for (BlockOffset(offset), block) in chain.blocks() {
+                let mut buf = io::Cursor::new(Vec::with_capacity(PK2_FILE_BLOCK_SIZE));
+                let names = self.options.names();
+                crate::io::write_block(blowfish.as_ref(), names, &mut buf, BlockOffset(0), block)?;
+                writes.insert(offset, buf.into_inner());
+            }
+        }
+        match &self.journal {
+            Some(journal) => {
+                writes.insert(0, header_buf);
+                journal.commit(stream, &writes)?;
+            }
+            None => {
+                apply(stream, &writes)?;
+                apply(stream, &Writes::from([(0, header_buf)]))?;
+            }
+        }
+        self.blowfish = blowfish;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::keys::ISRO;
+    use crate::{ErrorKind, Pk2};
+
+    #[test]
+    fn rekey_in_memory() {
+        let mut archive = Pk2::create_new_in_memory(ISRO).unwrap();
+        archive.create_file("/dir/file").unwrap().write_all(&[1; 3000]).unwrap();
+        let before = archive.stream.lock().get_ref().clone();
+        let pos = archive.metadata("/dir/file").unwrap().pos_data() as usize;
+        archive.rekey("other").unwrap();
+        assert!(archive.is_encrypted());
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 3000]);
+
+        let data = Vec::from(archive);
+        assert_eq!(data.len(), before.len());
+        assert_eq!(data[pos..pos + 3000], before[pos..pos + 3000]);
+        assert!(matches!(
+            Pk2::open_in(std::io::Cursor::new(data.clone()), ISRO),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidKey)
+        ));
+        let mut archive = Pk2::open_in(std::io::Cursor::new(data), "other").unwrap();
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 3000]);
+
+        archive.rekey("").unwrap();
+        assert!(!archive.is_encrypted());
+        let archive = Pk2::open_in(std::io::Cursor::new(Vec::from(archive)), "").unwrap();
+        assert!(!archive.is_encrypted());
+        assert_eq!(archive.read("/dir/file").unwrap(), [1; 3000]);
+    }
+
+    #[test]
+    fn rekey_file() {
+        let path = std::env::temp_dir().join(format!("pk2_rekey_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = Pk2::create_new(&path, "").unwrap();
+        for i in 0..30u8 {
+            archive.create_file(format!("/dir/{i}")).unwrap().write_all(&[i; 100]).unwrap();
+        }
+        drop(archive);
+
+        let mut archive = Pk2::open_lazy(&path, "").unwrap();
+        archive.rekey(ISRO).unwrap();
+        drop(archive);
+        assert!(!std::path::Path::new(&format!("{}.journal", path.display())).exists());
+        assert!(matches!(
+            Pk2::open(&path, "other"),
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidKey)
+        ));
+        let archive = Pk2::open(&path, ISRO).unwrap();
+        assert!(archive.is_encrypted());
+        for i in 0..30u8 {
+            assert_eq!(archive.read(format!("/dir/{i}")).unwrap(), [i; 100]);
+        }
+        assert!(archive.check().unwrap().is_ok());
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
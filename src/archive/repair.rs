@@ -0,0 +1,446 @@
+//! Salvaging of damaged archives.
+use std::collections::{BTreeMap, HashSet};
+use std::fs as stdfs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::archive::Pk2;
+use crate::blowfish::Blowfish;
+use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_FILE_ENTRY_SIZE, PK2_ROOT_BLOCK};
+use crate::error::{Context, Operation, Result};
+use crate::io::RawIo;
+use crate::options::Pk2Options;
+use crate::raw::block_chain::PackBlock;
+use crate::raw::entry::{NameCodec, PackEntry};
+use crate::raw::header::PackHeader;
+use crate::raw::StreamOffset;
+
+/// The size of the chunks unreferenced parts of the stream are scanned in.
+const SCAN_CHUNK_SIZE: usize = 1024 * 1024;
+/// The directory orphaned chains are put into.
+const LOST_AND_FOUND: &str = "/lost+found";
+
+/// The result of [`repair`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    blocks: usize,
+    files: usize,
+    skipped_files: usize,
+    lost_chains: usize,
+}
+
+impl RepairReport {
+    /// The number of valid blocks found in the damaged archive.
+    pub fn block_count(&self) -> usize {
+        self.blocks
+    }
+
+    /// The number of files that have been recovered.
+    pub fn file_count(&self) -> usize {
+        self.files
+    }
+
+    /// The number of files whose entry was found but that could not be
+    /// written to the new archive, as their name collided with another file
+    /// or could not be stored.
+    pub fn skipped_file_count(&self) -> usize {
+        self.skipped_files
+    }
+
+    /// The number of chains that were not reachable from the root directory
+    /// and have been put under `/lost+found`.
+    pub fn lost_chain_count(&self) -> usize {
+        self.lost_chains
+    }
+}
+
+/// Salvages as much as possible of the damaged archive at `src` into a new
+/// archive at `dst`, encrypted with the same `key`.
+///
+/// The index of the damaged archive is followed from its root where possible.
+/// If any of its blocks cannot be read, all parts of the stream that are not
+/// referenced by anything are scanned for blocks that decode to valid entries
+/// as well. The unreferenced parts of an intact index only hold deleted
+/// entries, so they are left alone. The directory tree is
+/// rebuilt from all blocks found and the data of every recoverable file is
+/// copied over. Chains that are not reachable from the root directory are put
+/// into `/lost+found`, named after their offset in the damaged archive.
+///
+/// Empty directories are not recreated.
+pub fn repair<P: AsRef<Path>, Q: AsRef<Path>, K: AsRef<[u8]>>(
+    src: P,
+    dst: Q,
+    key: K,
+) -> Result<RepairReport> {
+    repair_with(src, dst, key, &Pk2Options::default())
+}
+
+/// Like [`repair`], but for archives of the format variant described by
+/// `options`, which the new archive is created with as well.
+pub fn repair_with<P: AsRef<Path>, Q: AsRef<Path>, K: AsRef<[u8]>>(
+    src: P,
+    dst: Q,
+    key: K,
+    options: &Pk2Options,
+) -> Result<RepairReport> {
+    let src = src.as_ref();
+    repair_impl(src, dst.as_ref(), key.as_ref(), options).context(Operation::Repair, src)
+}
+
+fn repair_impl(src: &Path, dst: &Path, key: &[u8], options: &Pk2Options) -> Result<RepairReport> {
+    let profile = options.get_profile();
+    let mut src = stdfs::File::open(src)?;
+    let stream_len = crate::io::stream_len(&mut src)?;
+    src.seek(SeekFrom::Start(0))?;
+    // trust the header if it is intact, otherwise assume the archive is encrypted if a key is given
+    let encrypted = match PackHeader::from_reader(&mut src) {
+        Ok(header) if header.validate_sig(profile).is_ok() => header.encrypted,
+        _ => !key.is_empty(),
+    };
+    let blowfish = encrypted.then(|| Blowfish::new(key, &profile.salt)).transpose()?;
+    let mut scanner = Scanner {
+        src,
+        blowfish,
+        names: options.names(),
+        stream_len,
+        blocks: BTreeMap::new(),
+        tried: HashSet::new(),
+    };
+    if !scanner.follow(vec![PK2_ROOT_BLOCK.0])? {
+        scanner.scan_unreferenced()?;
+    }
+
+    let mut dst = Pk2::create_new_with(dst, key, options)?;
+    let mut report = RepairReport { blocks: scanner.blocks.len(), ..RepairReport::default() };
+    let mut used = HashSet::new();
+    if scanner.blocks.contains_key(&PK2_ROOT_BLOCK.0) {
+        scanner.restore(&mut dst, &mut report, &mut used, PK2_ROOT_BLOCK.0, PathBuf::from("/"))?;
+    }
+    // chains nothing refers to are the roots of orphaned trees, everything else that is left
+    // over can only be part of reference cycles
+    let referenced = scanner
+        .blocks
+        .values()
+        .flat_map(|block| block_references(block).collect::<Vec<_>>())
+        .collect::<HashSet<_>>();
+    let heads = scanner.blocks.keys().copied().collect::<Vec<_>>();
+    let (unreferenced, rest) =
+        heads.into_iter().partition::<Vec<_>, _>(|offset| !referenced.contains(offset));
+    for offset in unreferenced.into_iter().chain(rest) {
+        if !used.contains(&offset) {
+            report.lost_chains += 1;
+            let path = Path::new(LOST_AND_FOUND).join(format!("{offset:#x}"));
+            scanner.restore(&mut dst, &mut report, &mut used, offset, path)?;
+        }
+    }
+    Ok(report)
+}
+
+struct Scanner {
+    src: stdfs::File,
+    blowfish: Option<Blowfish>,
+    names: NameCodec,
+    stream_len: u64,
+    blocks: BTreeMap<u64, PackBlock>,
+    // offsets that have already been looked at
+    tried: HashSet<u64>,
+}
+
+impl Scanner {
+    /// Reads the blocks at the given offsets and all blocks they refer to,
+    /// returning whether all of them were valid.
+    fn follow(&mut self, mut pending: Vec<u64>) -> io::Result<bool> {
+        let mut intact = true;
+        while let Some(offset) = pending.pop() {
+            if !self.tried.insert(offset) {
+                continue;
+            }
+            if offset < PK2_ROOT_BLOCK.0
+                || offset.saturating_add(PK2_FILE_BLOCK_SIZE as u64) > self.stream_len
+            {
+                intact = false;
+                continue;
+            }
+            let mut buf = [0; PK2_FILE_BLOCK_SIZE];
+            crate::io::read_exact_at(&mut self.src, StreamOffset(offset), &mut buf)?;
+            match self.parse_block(&mut buf) {
+                Some(block) => {
+                    pending.extend(block_references(&block));
+                    self.blocks.insert(offset, block);
+                }
+                None => intact = false,
+            }
+        }
+        Ok(intact)
+    }
+
+    /// Scans every part of the stream that is neither a known block nor the
+    /// data of a known file for blocks.
+    fn scan_unreferenced(&mut self) -> io::Result<()> {
+        let mut used = vec![(0, PK2_ROOT_BLOCK.0)];
+        for (&offset, block) in &self.blocks {
+            used.push((offset, offset + PK2_FILE_BLOCK_SIZE as u64));
+            used.extend(block.entries().filter_map(PackEntry::as_file).map(|file| {
+                let StreamOffset(pos) = file.pos_data();
+                (pos, pos + file.size() as u64)
+            }));
+        }
+        used.sort_unstable();
+        let mut gaps = Vec::new();
+        let mut cursor = 0;
+        for (start, end) in used.into_iter().chain([(self.stream_len, self.stream_len)]) {
+            if start > cursor {
+                gaps.push((cursor, start));
+            }
+            cursor = cursor.max(end);
+        }
+        gaps.into_iter().try_for_each(|(start, end)| self.scan_gap(start, end))
+    }
+
+    fn scan_gap(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let mut buf = vec![0; SCAN_CHUNK_SIZE + PK2_FILE_BLOCK_SIZE];
+        let mut pos = start;
+        while pos + PK2_FILE_BLOCK_SIZE as u64 <= end {
+            let len = buf.len().min((end - pos) as usize);
+            crate::io::read_exact_at(&mut self.src, StreamOffset(pos), &mut buf[..len])?;
+            let mut i = 0;
+            while i + PK2_FILE_BLOCK_SIZE <= len {
+                let offset = pos + i as u64;
+                if !self.tried.contains(&offset) && self.may_be_block(&buf[i..i + 8]) {
+                    let mut block = [0; PK2_FILE_BLOCK_SIZE];
+                    block.copy_from_slice(&buf[i..i + PK2_FILE_BLOCK_SIZE]);
+                    if let Some(block) = self.parse_block(&mut block) {
+                        self.tried.insert(offset);
+                        let references = block_references(&block).collect();
+                        self.blocks.insert(offset, block);
+                        // the stream is scanned already, so broken references change nothing
+                        self.follow(references)?;
+                        i += PK2_FILE_BLOCK_SIZE;
+                        continue;
+                    }
+                }
+                i += 1;
+            }
+            pos += i as u64;
+        }
+        Ok(())
+    }
+
+    /// A cheap check of the first 8 bytes of a possible block.
+    fn may_be_block(&self, head: &[u8]) -> bool {
+        let mut head = <[u8; 8]>::try_from(head).unwrap();
+        if let Some(bf) = &self.blowfish {
+            bf.decrypt(&mut head);
+        }
+        match head[0] {
+            0 => true,
+            1 | 2 => head[1] != 0,
+            _ => false,
+        }
+    }
+
+    /// Decrypts and parses a block, returning `None` if any of its entries is
+    /// invalid or points outside of the stream.
+    fn parse_block(&self, buf: &mut [u8; PK2_FILE_BLOCK_SIZE]) -> Option<PackBlock> {
+        if let Some(bf) = &self.blowfish {
+            bf.decrypt(buf);
+        }
+        let in_stream = |offset: u64, len: u64| offset.checked_add(len) <= Some(self.stream_len);
+        let is_block = |offset: u64| {
+            offset >= PK2_ROOT_BLOCK.0 && in_stream(offset, PK2_FILE_BLOCK_SIZE as u64)
+        };
+        let mut used_entries = 0;
+        for entry in buf.chunks_exact(PK2_FILE_ENTRY_SIZE) {
+            let u64_at = |at: usize| u64::from_le_bytes(entry[at..at + 8].try_into().unwrap());
+            let (position, next_block) = (u64_at(106), u64_at(118));
+            let size = u32::from_le_bytes(entry[114..118].try_into().unwrap());
+            let valid = match entry[0] {
+                0 => true,
+                ty @ (1 | 2) => {
+                    used_entries += 1;
+                    let name_valid = entry[1] != 0;
+                    name_valid
+                        && if ty == 1 {
+                            is_block(position)
+                        } else {
+                            in_stream(position, size.into())
+                        }
+                }
+                _ => false,
+            };
+            if !valid || (next_block != 0 && !is_block(next_block)) {
+                return None;
+            }
+        }
+        if used_entries == 0 {
+            return None;
+        }
+        PackBlock::read_with(&buf[..], self.names).ok()
+    }
+
+    /// Recreates the chain starting at `head` and everything below it at
+    /// `path` in the new archive.
+    fn restore(
+        &mut self,
+        dst: &mut Pk2,
+        report: &mut RepairReport,
+        used: &mut HashSet<u64>,
+        head: u64,
+        path: PathBuf,
+    ) -> io::Result<()> {
+        let mut chains = vec![(head, path)];
+        while let Some((head, path)) = chains.pop() {
+            let mut offset = Some(head);
+            while let Some(block) =
+                offset.filter(|&o| used.insert(o)).and_then(|o| self.blocks.get(&o))
+            {
+                for entry in block.entries() {
+                    let Some(name) = entry.name() else { continue };
+                    match entry {
+                        PackEntry::File(file) => {
+                            let path = path.join(sanitize(name));
+                            let Ok(mut out) = dst.create_file_streaming(&path) else {
+                                report.skipped_files += 1;
+                                continue;
+                            };
+                            self.src.seek(SeekFrom::Start(file.pos_data().0))?;
+                            io::copy(&mut (&mut self.src).take(file.size().into()), &mut out)?;
+                            out.flush_drop()?;
+                            report.files += 1;
+                        }
+                        PackEntry::Directory(dir) if dir.is_normal_link() => {
+                            let child = dir.children_position().0;
+                            if self.blocks.contains_key(&child) && !used.contains(&child) {
+                                chains.push((child, path.join(sanitize(name))));
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                offset = block.entries().last().and_then(PackEntry::next_block).map(|nb| nb.get());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The offsets of all blocks a block refers to, the children of its
+/// directories and its next block.
+fn block_references(block: &PackBlock) -> impl Iterator<Item = u64> + '_ {
+    block
+        .entries()
+        .filter_map(PackEntry::as_directory)
+        .filter(|dir| dir.is_normal_link())
+        .map(|dir| dir.children_position().0)
+        .chain(block.entries().last().and_then(PackEntry::next_block).map(|nb| nb.get()))
+}
+
+/// Turns a name read from a damaged archive into a valid path component.
+fn sanitize(name: &str) -> String {
+    match name {
+        "." | ".." => name.replace('.', "_"),
+        _ => name.replace(['/', '\\', '\0', char::REPLACEMENT_CHARACTER], "_"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK};
+    use crate::Pk2;
+
+    fn paths(name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let (src, dst) =
+            (dir.join(format!("{name}_{id}.pk2")), dir.join(format!("{name}_{id}_out.pk2")));
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&dst);
+        (src, dst)
+    }
+
+    fn create(path: &std::path::Path) {
+        let mut archive = Pk2::create_new(path, "169841").unwrap();
+        archive.create_file("/a/1").unwrap().write_all(&[1; 3000]).unwrap();
+        archive.create_file("/a/2").unwrap().write_all(&[2; 100]).unwrap();
+        archive.create_file("/b/c/3").unwrap().write_all(&[3; 100]).unwrap();
+        archive.create_file("/4").unwrap().write_all(&[4; 100]).unwrap();
+    }
+
+    #[test]
+    fn repair_intact() {
+        let (src, dst) = paths("pk2_repair_intact");
+        create(&src);
+        let report = super::repair(&src, &dst, "169841").unwrap();
+        assert_eq!(report.file_count(), 4);
+        assert_eq!(report.lost_chain_count(), 0);
+        let archive = Pk2::open(&dst, "169841").unwrap();
+        assert_eq!(archive.read("/a/1").unwrap(), [1; 3000]);
+        assert_eq!(archive.read("/b/c/3").unwrap(), [3; 100]);
+        assert!(archive.check().unwrap().is_ok());
+        drop(archive);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+    }
+
+    #[test]
+    fn repair_ignores_deleted_chains() {
+        let (src, dst) = paths("pk2_repair_deleted");
+        create(&src);
+        let mut archive = Pk2::open(&src, "169841").unwrap();
+        archive.create_file("/gone/secret").unwrap().write_all(&[5; 100]).unwrap();
+        let gone = archive.open_directory("/gone").unwrap().metadata().pos_data();
+        drop(archive);
+        let mut block = [0; PK2_FILE_BLOCK_SIZE];
+        let mut file = std::fs::File::open(&src).unwrap();
+        file.seek(SeekFrom::Start(gone)).unwrap();
+        file.read_exact(&mut block).unwrap();
+        drop(file);
+        let mut archive = Pk2::open(&src, "169841").unwrap();
+        archive.remove_dir_all("/gone").unwrap();
+        drop(archive);
+        // leave the deleted block behind like tools that do not clear them
+        let mut file = std::fs::OpenOptions::new().write(true).open(&src).unwrap();
+        file.seek(SeekFrom::Start(gone)).unwrap();
+        file.write_all(&block).unwrap();
+        drop(file);
+        assert!(Pk2::open(&src, "169841").unwrap().check().unwrap().is_ok());
+
+        let report = super::repair(&src, &dst, "169841").unwrap();
+        assert_eq!(report.file_count(), 4);
+        assert_eq!(report.lost_chain_count(), 0);
+        let archive = Pk2::open(&dst, "169841").unwrap();
+        assert!(archive.open_directory("/lost+found").is_err());
+        drop(archive);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+    }
+
+    #[test]
+    fn repair_broken_root() {
+        let (src, dst) = paths("pk2_repair_root");
+        create(&src);
+        let archive = Pk2::open(&src, "169841").unwrap();
+        let a = archive.open_directory("/a").unwrap().metadata().pos_data();
+        let b = archive.open_directory("/b").unwrap().metadata().pos_data();
+        drop(archive);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&src).unwrap();
+        file.seek(SeekFrom::Start(PK2_ROOT_BLOCK.0)).unwrap();
+        file.write_all(&[0xFF; PK2_FILE_BLOCK_SIZE]).unwrap();
+        drop(file);
+        assert!(Pk2::open(&src, "169841").is_err());
+
+        let report = super::repair(&src, &dst, "169841").unwrap();
+        assert_eq!(report.lost_chain_count(), 2);
+        assert_eq!(report.file_count(), 3);
+        let archive = Pk2::open(&dst, "169841").unwrap();
+        assert_eq!(archive.read(format!("/lost+found/{a:#x}/1")).unwrap(), [1; 3000]);
+        assert_eq!(archive.read(format!("/lost+found/{a:#x}/2")).unwrap(), [2; 100]);
+        assert_eq!(archive.read(format!("/lost+found/{b:#x}/c/3")).unwrap(), [3; 100]);
+        assert!(archive.open_file("/4").is_err());
+        drop(archive);
+        std::fs::remove_file(&src).unwrap();
+        std::fs::remove_file(&dst).unwrap();
+    }
+}
@@ -0,0 +1,454 @@
+//! Atomic, journaled application of a group of archive modifications.
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::{fs as stdfs, mem};
+
+use crate::archive::Pk2;
+use crate::error::{Context, Operation, Result};
+use crate::hash::hash;
+use crate::io::{StreamCell, SyncData};
+use crate::raw::block_manager::BlockManager;
+use crate::raw::free_space::FreeSpaceMap;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"PK2JRNL1";
+
+/// The sidecar journal of a file based archive. Committed transactions are
+/// written to it in full before they are applied to the archive, so that an
+/// interrupted commit can be replayed the next time the archive is opened.
+pub(super) struct Journal {
+    archive: PathBuf,
+    path: PathBuf,
+}
+
+impl Journal {
+    pub(super) fn new(archive: &Path) -> Self {
+        let mut path = OsString::from(archive.as_os_str());
+        path.push(".journal");
+        Journal { archive: archive.to_owned(), path: path.into() }
+    }
+
+    /// Replays a complete journal left behind by an interrupted commit, or
+    /// discards an incomplete one whose transaction never touched the archive.
+    pub(super) fn recover(&self) -> io::Result<()> {
+        let data = match stdfs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if let Some(writes) = Self::parse(&data) {
+            let mut archive = stdfs::OpenOptions::new().write(true).open(&self.archive)?;
+            apply(&mut archive, &writes)?;
+            archive.sync_data()?;
+        }
+        stdfs::remove_file(&self.path)
+    }
+
+    pub(super) fn commit<B: Write + Seek + SyncData>(
+        &self,
+        stream: &mut B,
+        writes: &Writes,
+    ) -> io::Result<()> {
+        let mut journal = stdfs::File::create(&self.path)?;
+        journal.write_all(&Self::serialize(writes)?)?;
+        journal.sync_all()?;
+        drop(journal);
+
+        apply(stream, writes)?;
+        stream.sync_data()?;
+        stdfs::remove_file(&self.path)
+    }
+
+    fn serialize(writes: &Writes) -> io::Result<Vec<u8>> {
+        let mut buf = JOURNAL_MAGIC.to_vec();
+        buf.write_u64::<LE>(writes.len() as u64)?;
+        for (&offset, data) in writes {
+            buf.write_u64::<LE>(offset)?;
+            buf.write_u64::<LE>(data.len() as u64)?;
+            buf.extend_from_slice(data);
+        }
+        let checksum = hash(&buf);
+        buf.write_u64::<LE>(checksum)?;
+        Ok(buf)
+    }
+
+    /// Parses a journal, returning `None` if it is incomplete or damaged.
+    fn parse(data: &[u8]) -> Option<Writes> {
+        let (body, mut checksum) = data.split_at(data.len().checked_sub(8)?);
+        if !body.starts_with(JOURNAL_MAGIC) || checksum.read_u64::<LE>().ok()? != hash(body) {
+            return None;
+        }
+        let mut r = &body[JOURNAL_MAGIC.len()..];
+        let mut writes = Writes::new();
+        for _ in 0..r.read_u64::<LE>().ok()? {
+            let offset = r.read_u64::<LE>().ok()?;
+            let len = usize::try_from(r.read_u64::<LE>().ok()?).ok()?;
+            let data = r.get(..len)?;
+            writes.insert(offset, data.to_vec());
+            r = &r[len..];
+        }
+        r.is_empty().then_some(writes)
+    }
+}
+
+/// Non-overlapping pending writes keyed by their stream offset.
+pub(super) type Writes = BTreeMap<u64, Vec<u8>>;
+
+pub(super) fn apply<B: Write + Seek>(stream: &mut B, writes: &Writes) -> io::Result<()> {
+    for (&offset, data) in writes {
+        stream.seek(SeekFrom::Start(offset))?;
+        stream.write_all(data)?;
+    }
+    stream.flush()
+}
+
+/// The stream of an archive inside of a [`Pk2::transaction`]. Writes are
+/// buffered in memory and only reach the underlying stream once the
+/// transaction commits, while reads observe the pending writes.
+pub struct TransactionStream<'a, B> {
+    inner: &'a mut B,
+    inner_len: u64,
+    len: u64,
+    pos: u64,
+    writes: Writes,
+}
+
+impl<'a, B: Seek> TransactionStream<'a, B> {
+    pub(super) fn new(inner: &'a mut B) -> io::Result<Self> {
+        let inner_len = inner.seek(SeekFrom::End(0))?;
+        Ok(TransactionStream { inner, inner_len, len: inner_len, pos: 0, writes: Writes::new() })
+    }
+}
+
+impl<B> TransactionStream<'_, B> {
+    /// Consumes the stream, returning its pending writes.
+    pub(super) fn into_writes(self) -> Writes {
+        self.writes
+    }
+
+    /// Returns the offsets of all pending writes that overlap the given range.
+    fn overlapping(&self, start: u64, end: u64) -> Vec<u64> {
+        let prev = self
+            .writes
+            .range(..start)
+            .next_back()
+            .filter(|(&offset, data)| offset + data.len() as u64 > start)
+            .map(|(&offset, _)| offset);
+        prev.into_iter().chain(self.writes.range(start..end).map(|(&offset, _)| offset)).collect()
+    }
+
+    fn insert_write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let overlapping = self.overlapping(offset, end);
+        // fast path, the write lies entirely inside of a previous one
+        if let [existing] = overlapping[..] {
+            let buf = self.writes.get_mut(&existing).unwrap();
+            if existing <= offset && existing + buf.len() as u64 >= end {
+                let start = (offset - existing) as usize;
+                buf[start..start + data.len()].copy_from_slice(data);
+                return;
+            }
+        }
+        let new_start = overlapping.first().map_or(offset, |&first| first.min(offset));
+        let new_end =
+            overlapping.last().map_or(end, |last| (last + self.writes[last].len() as u64).max(end));
+        let mut buf = vec![0; (new_end - new_start) as usize];
+        for existing in overlapping {
+            let data = self.writes.remove(&existing).unwrap();
+            let start = (existing - new_start) as usize;
+            buf[start..start + data.len()].copy_from_slice(&data);
+        }
+        let start = (offset - new_start) as usize;
+        buf[start..start + data.len()].copy_from_slice(data);
+        self.writes.insert(new_start, buf);
+    }
+}
+
+impl<B: Read + Seek> Read for TransactionStream<'_, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        let (start, end) = (self.pos, self.pos + n as u64);
+        let buf = &mut buf[..n];
+        buf.fill(0);
+        if start < self.inner_len {
+            let m = (self.inner_len - start).min(n as u64) as usize;
+            self.inner.seek(SeekFrom::Start(start))?;
+            self.inner.read_exact(&mut buf[..m])?;
+        }
+        for offset in self.overlapping(start, end) {
+            let data = &self.writes[&offset];
+            let from = start.max(offset);
+            let to = end.min(offset + data.len() as u64);
+            buf[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+        }
+        self.pos = end;
+        Ok(n)
+    }
+}
+
+impl<B> Write for TransactionStream<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.insert_write(self.pos, buf);
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes only reach the underlying stream once the transaction commits.
+impl<B> SyncData for TransactionStream<'_, B> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B> Seek for TransactionStream<'_, B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::End(n) => (self.len, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Write + Seek + SyncData,
+{
+    /// Runs `f` as a single transaction. All modifications done through the
+    /// archive passed to `f` are buffered and only written to the stream if
+    /// `f` succeeds, otherwise they are discarded.
+    ///
+    /// For archives opened from a path the pending writes are first recorded
+    /// in a `.journal` file next to the archive. If the process dies while
+    /// they are being applied, the journal is replayed by the next
+    /// [`Pk2::open`], so the archive never ends up half written. The journal
+    /// is only removed after the stream has been synced through [`SyncData`].
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Pk2<TransactionStream<'_, B>>) -> Result<T>,
+    {
+        let mut tx = Pk2 {
+            stream: StreamCell::new(TransactionStream::new(self.stream.get_mut())?),
+            blowfish: self.blowfish.take(),
+            block_manager: mem::take(&mut self.block_manager),
+            free_space: mem::take(&mut self.free_space),
+            journal: None,
+            options: self.options.clone(),
+        };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx)));
+        let Pk2 { stream, blowfish, block_manager, free_space, .. } = tx;
+        let writes = stream.into_inner().into_writes();
+        self.blowfish = blowfish;
+        self.block_manager = block_manager;
+        self.free_space = free_space;
+        let res = match res {
+            Ok(res) => res,
+            Err(payload) => {
+                // nothing has been written, so the archive stays usable if the panic is caught
+                let _ = self.reload_index();
+                panic::resume_unwind(payload)
+            }
+        };
+
+        let res = res.and_then(|val| {
+            let stream = self.stream.get_mut();
+            match &self.journal {
+                Some(journal) => journal.commit(stream, &writes),
+                None => apply(stream, &writes),
+            }
+            .map(|()| val)
+            .operation(Operation::Transaction)
+        });
+        if res.is_err() {
+            self.reload_index()?;
+        }
+        res
+    }
+
+    /// Loads the index from the stream again, as the one in memory reflects
+    /// an aborted transaction. Lazy archives stay lazy, only space freed
+    /// afterwards is reused then.
+    fn reload_index(&mut self) -> Result<()> {
+        let stream = self.stream.get_mut();
+        let stream_len = crate::io::stream_len(&mut *stream)?;
+        if self.block_manager.is_lazy() {
+            self.block_manager.reload_lazy()?;
+            self.free_space = FreeSpaceMap::fully_used(stream_len);
+        } else {
+            let name_index = self.block_manager.name_index();
+            self.block_manager =
+                BlockManager::new(self.blowfish.as_ref(), self.options.names(), &mut *stream)?;
+            self.block_manager.set_name_index(name_index);
+            self.free_space = FreeSpaceMap::new(&self.block_manager, stream_len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::{Journal, Writes};
+    use crate::{Pk2, SyncData};
+
+    #[test]
+    fn transaction_commit_and_rollback() {
+        let mut archive = Pk2::create_new_in_memory("169841").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 100]).unwrap();
+
+        archive
+            .transaction(|tx| {
+                tx.create_file("/b/file")?.write_all(&[2; 100])?;
+                tx.delete_file("/a/file")?;
+                assert_eq!(tx.read("/b/file")?, [2; 100]);
+                Ok(())
+            })
+            .unwrap();
+        assert!(archive.open_file("/a/file").is_err());
+        assert_eq!(archive.read("/b/file").unwrap(), [2; 100]);
+
+        let before = archive.stream.lock().get_ref().clone();
+        let res = archive.transaction(|tx| {
+            tx.create_file("/c/file")?.write_all(&[3; 100])?;
+            tx.delete_file("/b/file")?;
+            tx.delete_file("/does/not/exist")
+        });
+        assert!(res.is_err());
+        assert_eq!(archive.stream.lock().get_ref(), &before);
+        assert!(archive.open_directory("/c").is_err());
+        assert_eq!(archive.read("/b/file").unwrap(), [2; 100]);
+    }
+
+    #[test]
+    fn transaction_panic_keeps_archive_usable() {
+        let mut archive = Pk2::create_new_in_memory("169841").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 100]).unwrap();
+        let before = archive.stream.lock().get_ref().clone();
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            archive.transaction(|tx| -> crate::Result<()> {
+                tx.create_file("/b/file")?.write_all(&[2; 100])?;
+                panic!("aborted");
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(archive.stream.lock().get_ref(), &before);
+        assert!(archive.open_directory("/b").is_err());
+
+        // the key and the index must have been restored for further writes
+        archive.create_file("/c/file").unwrap().write_all(&[3; 100]).unwrap();
+        let stream = archive.stream.lock().clone();
+        let archive = Pk2::open_in(stream, "169841").unwrap();
+        assert_eq!(archive.read("/a/file").unwrap(), [1; 100]);
+        assert_eq!(archive.read("/c/file").unwrap(), [3; 100]);
+    }
+
+    #[test]
+    fn failed_transaction_keeps_archive_lazy() {
+        let path = std::env::temp_dir().join(format!("pk2_tx_lazy_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = Pk2::create_new(&path, "169841").unwrap();
+        archive.create_file("/a/file").unwrap().write_all(&[1; 100]).unwrap();
+        drop(archive);
+
+        let mut archive = Pk2::open_lazy(&path, "169841").unwrap();
+        let res = archive.transaction(|tx| {
+            tx.create_file("/a/other")?.write_all(&[2; 100])?;
+            tx.delete_file("/does/not/exist")
+        });
+        assert!(res.is_err());
+        assert!(archive.block_manager.is_lazy());
+        assert!(archive.open_file("/a/other").is_err());
+        assert_eq!(archive.read("/a/file").unwrap(), [1; 100]);
+        archive.create_file("/a/new").unwrap().write_all(&[3; 100]).unwrap();
+        drop(archive);
+
+        let archive = Pk2::open(&path, "169841").unwrap();
+        assert_eq!(archive.read("/a/file").unwrap(), [1; 100]);
+        assert_eq!(archive.read("/a/new").unwrap(), [3; 100]);
+        drop(archive);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_recovery() {
+        let path = std::env::temp_dir().join(format!("pk2_journal_{}.pk2", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut archive = Pk2::create_new(&path, "169841").unwrap();
+        archive.create_file("/file").unwrap().write_all(&[1; 100]).unwrap();
+        let (_, _, entry) = archive.root_resolve_path_to_entry_and_parent("/file").unwrap();
+        let pos_data = entry.as_file().unwrap().pos_data().0;
+        drop(archive);
+
+        let journal = Journal::new(&path);
+        let mut writes = Writes::new();
+        writes.insert(pos_data, vec![2; 100]);
+        let serialized = Journal::serialize(&writes).unwrap();
+
+        // an incomplete journal is rolled back
+        std::fs::write(&journal.path, &serialized[..serialized.len() - 1]).unwrap();
+        assert_eq!(Pk2::open(&path, "169841").unwrap().read("/file").unwrap(), [1; 100]);
+        assert!(!journal.path.exists());
+
+        // a complete one gets replayed
+        std::fs::write(&journal.path, &serialized).unwrap();
+        assert_eq!(Pk2::open(&path, "169841").unwrap().read("/file").unwrap(), [2; 100]);
+        assert!(!journal.path.exists());
+
+        let mut archive = Pk2::open(&path, "169841").unwrap();
+        archive.transaction(|tx| Ok(tx.create_file("/other")?.write_all(&[3; 100])?)).unwrap();
+        assert!(!journal.path.exists());
+        drop(archive);
+        assert_eq!(Pk2::open(&path, "169841").unwrap().read("/other").unwrap(), [3; 100]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journal_commit_syncs_written_stream() {
+        struct Synced(std::io::Cursor<Vec<u8>>, bool);
+        impl std::io::Write for Synced {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl std::io::Seek for Synced {
+            fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+        impl SyncData for Synced {
+            fn sync_data(&mut self) -> std::io::Result<()> {
+                self.1 = true;
+                Ok(())
+            }
+        }
+
+        // the archive itself is never opened by the commit, so it does not have to exist
+        let path = std::env::temp_dir().join(format!("pk2_sync_{}.pk2", std::process::id()));
+        let journal = Journal::new(&path);
+        let mut stream = Synced(std::io::Cursor::new(Vec::new()), false);
+        journal.commit(&mut stream, &Writes::from([(4, vec![1; 4])])).unwrap();
+        assert!(stream.1);
+        assert_eq!(stream.0.get_ref(), &[0, 0, 0, 0, 1, 1, 1, 1]);
+        assert!(!journal.path.exists());
+    }
+}
@@ -3,8 +3,6 @@ use std::fmt;
 
 use byteorder::{ByteOrder, LE};
 
-use crate::constants::PK2_SALT;
-
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub struct InvalidKey;
 
@@ -15,6 +13,7 @@ impl fmt::Display for InvalidKey {
     }
 }
 
+#[derive(Clone)]
 pub struct Blowfish {
     s: [[u32; 256]; 4],
     p: [u32; 18],
@@ -35,12 +34,13 @@ fn next_u32_wrap(buf: &[u8], offset: &mut usize) -> u32 {
 }
 
 impl Blowfish {
-    pub fn new(key: &[u8]) -> Result<Self, InvalidKey> {
+    /// Creates a blowfish instance for the given key, combined with `salt`.
+    pub fn new(key: &[u8], salt: &[u8]) -> Result<Self, InvalidKey> {
         if key.len() < 4 || key.len() > 56 {
             return Err(InvalidKey);
         }
         let mut key = key.to_vec();
-        gen_final_blowfish_key_inplace(&mut key);
+        gen_final_blowfish_key_inplace(&mut key, salt);
         let mut this = Blowfish { p: P, s: S };
         this.expand_key(&key);
         Ok(this)
@@ -129,11 +129,11 @@ impl Blowfish {
     }
 }
 
-fn gen_final_blowfish_key_inplace(key: &mut [u8]) {
+fn gen_final_blowfish_key_inplace(key: &mut [u8], salt: &[u8]) {
     let key_len = key.len().min(56);
 
     let mut base_key = [0; 56];
-    base_key[0..PK2_SALT.len()].copy_from_slice(&PK2_SALT);
+    base_key[0..salt.len()].copy_from_slice(salt);
 
     for i in 0..key_len {
         key[i] ^= base_key[i];
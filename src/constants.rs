@@ -20,10 +20,14 @@ pub const PK2_ROOT_BLOCK: ChainIndex = ChainIndex(mem::size_of::<RawPackHeader>(
 // Sentinel entry to give the root block a proper path descriptor
 pub const PK2_ROOT_BLOCK_VIRTUAL: ChainIndex = ChainIndex(0);
 
+/// The maximum length of an encoded name, leaving room for the terminator.
+pub const PK2_MAX_NAME_LEN: usize = 80;
+
 pub static PK2_CURRENT_DIR_IDENT: &str = ".";
 pub static PK2_PARENT_DIR_IDENT: &str = "..";
 
-#[repr(packed)]
+#[allow(dead_code)]
+#[repr(C, packed)]
 pub struct RawPackHeader {
     pub signature: [u8; 30],
     pub version: u32,
@@ -32,7 +36,8 @@ pub struct RawPackHeader {
     pub reserved: [u8; 205],
 }
 
-#[repr(packed)]
+#[allow(dead_code)]
+#[repr(C, packed)]
 #[derive(Copy, Clone)]
 pub struct RawPackFileEntry {
     pub ty: u8, //0 = Empty, 1 = Directory, 2  = File
@@ -1,6 +1,296 @@
+use std::path::{Path, PathBuf};
 use std::{error, fmt, io};
 
-pub use crate::blowfish::InvalidKey;
+use crate::blowfish::InvalidKey;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The error type of all operations on archives.
+///
+/// Besides the [kind](Error::kind) of error, it carries the context the error
+/// occurred in where known: the operation, the path inside the archive (or of
+/// the archive itself when opening it) and the offset in the stream of the
+/// block or file data involved.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    operation: Option<Operation>,
+    path: Option<PathBuf>,
+    offset: Option<u64>,
+}
+
+/// What went wrong, see [`Error::kind`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A path could not be resolved.
+    Lookup(ChainLookupError),
+    /// A name cannot be stored in the archive.
+    InvalidName(InvalidName),
+    /// The key does not match the archive or is not a valid blowfish key.
+    InvalidKey,
+    CorruptedFile,
+    UnsupportedVersion,
+    /// The archive a patch is applied to is not the one it was created from.
+    SourceMismatch,
+    Io(io::Error),
+}
+
+/// The operation an [`Error`] occurred in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Operation {
+    Open,
+    Create,
+    OpenFile,
+    OpenDirectory,
+    /// Opening an entry that may be either a file or a directory.
+    OpenEntry,
+    Metadata,
+    Glob,
+    ForEachFile,
+    Read,
+    Write,
+    CreateFile,
+    CreateDirectory,
+    DeleteFile,
+    DeleteDirectory,
+    Rename,
+    Transaction,
+    Compact,
+    Check,
+    Rekey,
+    Repair,
+    ExportIndex,
+    CreatePatch,
+    ApplyPatch,
+    ReadPatch,
+    WritePatch,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn operation(&self) -> Option<Operation> {
+        self.operation
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The [`io::ErrorKind`] this error turns into when converted into an
+    /// [`io::Error`].
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match &self.kind {
+            ErrorKind::Lookup(e) => e.io_kind(),
+            ErrorKind::InvalidName(_) => io::ErrorKind::InvalidInput,
+            ErrorKind::InvalidKey
+            | ErrorKind::CorruptedFile
+            | ErrorKind::UnsupportedVersion
+            | ErrorKind::SourceMismatch => io::ErrorKind::InvalidData,
+            ErrorKind::Io(e) => e.kind(),
+        }
+    }
+
+    /// Whether this is the given error of resolving a path.
+    pub(crate) fn is_lookup(&self, error: ChainLookupError) -> bool {
+        matches!(self.kind, ErrorKind::Lookup(e) if e == error)
+    }
+
+    /// Copies the error, replacing a contained [`io::Error`] with a new one of
+    /// the same kind and message.
+    pub(crate) fn duplicate(&self) -> Self {
+        let kind = match &self.kind {
+            ErrorKind::Lookup(e) => ErrorKind::Lookup(*e),
+            ErrorKind::InvalidName(e) => ErrorKind::InvalidName(*e),
+            ErrorKind::InvalidKey => ErrorKind::InvalidKey,
+            ErrorKind::CorruptedFile => ErrorKind::CorruptedFile,
+            ErrorKind::UnsupportedVersion => ErrorKind::UnsupportedVersion,
+            ErrorKind::SourceMismatch => ErrorKind::SourceMismatch,
+            ErrorKind::Io(e) => ErrorKind::Io(io::Error::new(e.kind(), e.to_string())),
+        };
+        Error { kind, operation: self.operation, path: self.path.clone(), offset: self.offset }
+    }
+
+    // The setters below keep context that is already present, as the context
+    // closest to where the error occurred is the most precise one.
+
+    pub(crate) fn with_operation(mut self, operation: Operation) -> Self {
+        self.operation.get_or_insert(operation);
+        self
+    }
+
+    pub(crate) fn with_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path.get_or_insert_with(|| path.as_ref().to_owned());
+        self
+    }
+
+    pub(crate) fn with_offset(mut self, offset: u64) -> Self {
+        self.offset.get_or_insert(offset);
+        self
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Lookup(e) => Some(e),
+            ErrorKind::InvalidName(e) => Some(e),
+            ErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(operation) = self.operation {
+            write!(f, "{operation}")?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " {path:?}")?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {offset:#x}")?;
+        }
+        if self.operation.is_some() || self.path.is_some() || self.offset.is_some() {
+            write!(f, ": ")?;
+        }
+        fmt::Display::fmt(&self.kind, f)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Lookup(e) => fmt::Display::fmt(e, f),
+            ErrorKind::InvalidName(e) => fmt::Display::fmt(e, f),
+            ErrorKind::CorruptedFile => write!(f, "archive is invalid or corrupted"),
+            ErrorKind::UnsupportedVersion => write!(f, "archive version is not supported"),
+            ErrorKind::InvalidKey => write!(f, "blowfish key was invalid"),
+            ErrorKind::SourceMismatch => {
+                write!(f, "archive does not match the source of the patch")
+            }
+            ErrorKind::Io(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Open => "failed to open archive",
+            Operation::Create => "failed to create archive",
+            Operation::OpenFile => "failed to open file",
+            Operation::OpenDirectory => "failed to open directory",
+            Operation::OpenEntry => "failed to open entry",
+            Operation::Metadata => "failed to get metadata of",
+            Operation::Glob => "failed to match glob",
+            Operation::ForEachFile => "failed to process file",
+            Operation::Read => "failed to read",
+            Operation::Write => "failed to write",
+            Operation::CreateFile => "failed to create file",
+            Operation::CreateDirectory => "failed to create directory",
+            Operation::DeleteFile => "failed to delete file",
+            Operation::DeleteDirectory => "failed to delete directory",
+            Operation::Rename => "failed to rename",
+            Operation::Transaction => "failed to commit transaction",
+            Operation::Compact => "failed to compact archive",
+            Operation::Check => "failed to check archive",
+            Operation::Rekey => "failed to rekey archive",
+            Operation::Repair => "failed to repair archive",
+            Operation::ExportIndex => "failed to export index",
+            Operation::CreatePatch => "failed to create patch",
+            Operation::ApplyPatch => "failed to apply patch",
+            Operation::ReadPatch => "failed to read patch",
+            Operation::WritePatch => "failed to write patch",
+        })
+    }
+}
+
+impl From<ErrorKind> for Error {
+    #[inline]
+    fn from(kind: ErrorKind) -> Self {
+        Error { kind, operation: None, path: None, offset: None }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Recovers errors of this crate that have been passed through an
+    /// [`io::Error`], wrapping any other error as [`ErrorKind::Io`].
+    fn from(e: io::Error) -> Self {
+        match e.get_ref() {
+            Some(inner) if inner.is::<Error>() => {
+                *e.into_inner().unwrap().downcast::<Error>().unwrap_or_else(|_| unreachable!())
+            }
+            Some(inner) => match (inner.downcast_ref(), inner.downcast_ref()) {
+                (Some(&lookup), _) => ErrorKind::Lookup(lookup).into(),
+                (_, Some(&name)) => ErrorKind::InvalidName(name).into(),
+                _ => ErrorKind::Io(e).into(),
+            },
+            None => ErrorKind::Io(e).into(),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    #[inline]
+    fn from(e: Error) -> Self {
+        match e {
+            Error { kind: ErrorKind::Io(e), operation: None, path: None, offset: None } => e,
+            e => io::Error::new(e.io_kind(), e),
+        }
+    }
+}
+
+impl From<ChainLookupError> for Error {
+    #[inline]
+    fn from(e: ChainLookupError) -> Self {
+        ErrorKind::Lookup(e).into()
+    }
+}
+
+impl From<InvalidName> for Error {
+    #[inline]
+    fn from(e: InvalidName) -> Self {
+        ErrorKind::InvalidName(e).into()
+    }
+}
+
+impl From<InvalidKey> for Error {
+    #[inline]
+    fn from(_: InvalidKey) -> Self {
+        ErrorKind::InvalidKey.into()
+    }
+}
+
+/// Attaches context to the errors of results, see [`Error`].
+pub(crate) trait Context<T> {
+    fn context(self, operation: Operation, path: impl AsRef<Path>) -> Result<T>;
+    fn operation(self, operation: Operation) -> Result<T>;
+    fn offset(self, offset: u64) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context(self, operation: Operation, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|e| e.into().with_operation(operation).with_path(path))
+    }
+
+    fn operation(self, operation: Operation) -> Result<T> {
+        self.map_err(|e| e.into().with_operation(operation))
+    }
+
+    fn offset(self, offset: u64) -> Result<T> {
+        self.map_err(|e| e.into().with_offset(offset))
+    }
+}
 
 pub type ChainLookupResult<T> = Result<T, ChainLookupError>;
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -12,58 +302,66 @@ pub enum ChainLookupError {
     ExpectedFile,
 }
 
+impl ChainLookupError {
+    fn io_kind(self) -> io::ErrorKind {
+        match self {
+            ChainLookupError::NotFound => io::ErrorKind::NotFound,
+            ChainLookupError::InvalidPath => io::ErrorKind::InvalidInput,
+            ChainLookupError::InvalidChainIndex => io::ErrorKind::InvalidData,
+            ChainLookupError::ExpectedDirectory => io::ErrorKind::NotFound,
+            ChainLookupError::ExpectedFile => io::ErrorKind::NotFound,
+        }
+    }
+}
+
 impl error::Error for ChainLookupError {}
 impl fmt::Display for ChainLookupError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&io::Error::from(*self), f)
+        f.write_str(match self {
+            ChainLookupError::NotFound => "entity not found",
+            ChainLookupError::InvalidPath => "invalid path",
+            ChainLookupError::InvalidChainIndex => "invalid chain index",
+            ChainLookupError::ExpectedDirectory => "expected a directory",
+            ChainLookupError::ExpectedFile => "expected a file",
+        })
     }
 }
 
 impl From<ChainLookupError> for io::Error {
     #[inline]
     fn from(this: ChainLookupError) -> Self {
-        match this {
-            ChainLookupError::NotFound => io::ErrorKind::NotFound,
-            ChainLookupError::InvalidPath => io::ErrorKind::InvalidInput,
-            ChainLookupError::InvalidChainIndex => io::ErrorKind::InvalidData,
-            ChainLookupError::ExpectedDirectory => io::ErrorKind::NotFound,
-            ChainLookupError::ExpectedFile => io::ErrorKind::NotFound,
-        }
-        .into()
+        io::Error::new(this.io_kind(), this)
     }
 }
 
-pub type OpenResult<T> = std::result::Result<T, OpenError>;
-#[derive(Debug)]
-pub enum OpenError {
-    InvalidKey,
-    CorruptedFile,
-    UnsupportedVersion,
-    Io(io::Error),
+/// The reason a name cannot be stored in an archive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InvalidName {
+    Empty,
+    /// The encoded name is longer than 80 bytes.
+    TooLong,
+    /// The name contains a `/` or `\`.
+    Separator,
+    /// The name contains characters the encoding of the archive cannot
+    /// represent.
+    Unencodable,
 }
 
-impl error::Error for OpenError {}
-impl fmt::Display for OpenError {
+impl error::Error for InvalidName {}
+impl fmt::Display for InvalidName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            OpenError::CorruptedFile => write!(f, "archive is invalid or corrupted"),
-            OpenError::UnsupportedVersion => write!(f, "archive version is not supported"),
-            OpenError::InvalidKey => write!(f, "blowfish key was invalid"),
-            OpenError::Io(e) => fmt::Display::fmt(e, f),
+            InvalidName::Empty => write!(f, "name is empty"),
+            InvalidName::TooLong => write!(f, "name is longer than 80 bytes"),
+            InvalidName::Separator => write!(f, "name contains a path separator"),
+            InvalidName::Unencodable => write!(f, "name cannot be represented in the encoding"),
         }
     }
 }
 
-impl From<io::Error> for OpenError {
+impl From<InvalidName> for io::Error {
     #[inline]
-    fn from(e: io::Error) -> Self {
-        OpenError::Io(e)
-    }
-}
-
-impl From<InvalidKey> for OpenError {
-    #[inline]
-    fn from(_: InvalidKey) -> Self {
-        OpenError::InvalidKey
+    fn from(e: InvalidName) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
     }
 }
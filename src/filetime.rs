@@ -1,6 +1,6 @@
 use std::time::{Duration, SystemTime};
 
-#[allow(non_snake_case)]
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct FILETIME {
     pub dwLowDateTime: u32,
@@ -15,10 +15,17 @@ impl FILETIME {
         SystemTime::now().into()
     }
 
+    /// The number of 100 nanosecond intervals since 1601-01-01.
+    pub fn ticks(self) -> u64 {
+        ((self.dwHighDateTime as u64) << 32) | self.dwLowDateTime as u64
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        FILETIME { dwLowDateTime: ticks as u32, dwHighDateTime: (ticks >> 32) as u32 }
+    }
+
     pub fn into_systime(self) -> Option<SystemTime> {
-        let FILETIME { dwLowDateTime: low, dwHighDateTime: high } = self;
-        let ftime = ((high as u64) << 32) | low as u64;
-        let nanos = (ftime.checked_sub(Self::MS_EPOCH)?) * 100;
+        let nanos = (self.ticks().checked_sub(Self::MS_EPOCH)?) * 100;
         Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
     }
 }
@@ -26,8 +33,7 @@ impl FILETIME {
 impl From<SystemTime> for FILETIME {
     fn from(time: SystemTime) -> Self {
         let duration = time.duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        let ftime = (duration.as_nanos() / 100) as u64 + Self::MS_EPOCH;
-        FILETIME { dwLowDateTime: ftime as u32, dwHighDateTime: (ftime >> 32) as u32 }
+        FILETIME::from_ticks((duration.as_nanos() / 100) as u64 + Self::MS_EPOCH)
     }
 }
 
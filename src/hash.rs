@@ -0,0 +1,99 @@
+//! Hashing of file contents.
+use std::io;
+
+/// 64 bit FNV-1a. Unlike the hashers of std its output is stable across
+/// platforms and releases, so its hashes can be stored and compared later.
+pub(crate) struct ContentHasher(u64);
+
+impl ContentHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    pub fn new() -> Self {
+        ContentHasher(Self::OFFSET_BASIS)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl io::Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes everything passing through the wrapped reader or writer.
+pub(crate) struct Hashed<T> {
+    inner: T,
+    hasher: ContentHasher,
+}
+
+impl<T> Hashed<T> {
+    pub fn new(inner: T) -> Self {
+        Hashed { inner, hasher: ContentHasher::new() }
+    }
+
+    /// The hash of the data read or written so far, along with the wrapped
+    /// reader or writer.
+    pub fn finish(self) -> (u64, T) {
+        (self.hasher.finish(), self.inner)
+    }
+}
+
+impl<R: io::Read> io::Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: io::Write> io::Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes `data` in one go.
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    let mut hasher = ContentHasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Hashes everything `reader` yields.
+pub(crate) fn hash_reader(mut reader: impl io::Read) -> io::Result<u64> {
+    let mut hasher = ContentHasher::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+#[test]
+fn test_fnv1a() {
+    assert_eq!(hash(b"foobar"), hash_reader(&b"foobar"[..]).unwrap());
+    let mut writer = Hashed::new(Vec::new());
+    io::Write::write_all(&mut writer, b"foobar").unwrap();
+    assert_eq!(writer.finish(), (hash(b"foobar"), b"foobar".to_vec()));
+    assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+}
@@ -3,27 +3,32 @@
 //! General io for reading/writing from/to buffers.
 
 use std::io::{self, SeekFrom};
+use std::sync::{PoisonError, RwLock};
 
 use crate::blowfish::Blowfish;
 use crate::constants::{
     PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT,
 };
-use crate::error::OpenResult;
+use crate::error::{Context, Error, ErrorKind, Result};
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
-use crate::raw::entry::PackEntry;
+use crate::raw::entry::{NameCodec, PackEntry};
+use crate::raw::free_space::FreeSpaceMap;
 use crate::raw::{BlockOffset, ChainIndex, EntryOffset, StreamOffset};
 
+use self::sealed::ReadAt;
+
 /// Read a block at a given offset.
 pub fn read_block_at<F: io::Seek + io::Read>(
     bf: Option<&Blowfish>,
+    names: NameCodec,
     mut stream: F,
     BlockOffset(offset): BlockOffset,
-) -> OpenResult<PackBlock> {
+) -> Result<PackBlock> {
     let mut buf = [0; PK2_FILE_BLOCK_SIZE];
-    stream.seek(SeekFrom::Start(offset))?;
-    stream.read_exact(&mut buf)?;
+    read_exact_at(&mut stream, StreamOffset(offset), &mut buf).offset(offset)?;
     bf.map(|bf| bf.decrypt(&mut buf));
-    PackBlock::from_reader(&buf[..]).map_err(Into::into)
+    PackBlock::read_with(&buf[..], names)
+        .map_err(|_| Error::from(ErrorKind::CorruptedFile).with_offset(offset))
 }
 
 pub fn read_exact_at<F: io::Seek + io::Read>(
@@ -44,19 +49,20 @@ pub fn read_at<F: io::Seek + io::Read>(
     stream.read(buf)
 }
 
-fn stream_len<F: io::Seek>(mut stream: F) -> io::Result<u64> {
+pub fn stream_len<F: io::Seek>(mut stream: F) -> io::Result<u64> {
     stream.seek(SeekFrom::End(0))
 }
 
 /// Write/Update a block at the given block offset in the file.
 pub fn write_block<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    names: NameCodec,
     mut stream: F,
     BlockOffset(offset): BlockOffset,
     block: &PackBlock,
 ) -> io::Result<()> {
     let mut buf = [0; PK2_FILE_BLOCK_SIZE];
-    block.to_writer(&mut buf[..])?;
+    block.write_with(&mut buf[..], names)?;
     bf.map(|bf| bf.encrypt(&mut buf));
     stream.seek(SeekFrom::Start(offset))?;
     stream.write_all(&buf)?;
@@ -66,12 +72,13 @@ pub fn write_block<F: io::Seek + io::Write>(
 /// Write/Update an entry at the given entry offset in the file.
 pub fn write_entry_at<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    names: NameCodec,
     mut stream: F,
     EntryOffset(offset): EntryOffset,
     entry: &PackEntry,
 ) -> io::Result<()> {
     let mut buf = [0; PK2_FILE_ENTRY_SIZE];
-    entry.to_writer(&mut buf[..])?;
+    entry.write_with(&mut buf[..], names)?;
     bf.map(|bf| bf.encrypt(&mut buf));
     stream.seek(SeekFrom::Start(offset))?;
     stream.write_all(&buf)?;
@@ -82,6 +89,7 @@ pub fn write_entry_at<F: io::Seek + io::Write>(
 /// the file.
 pub fn write_chain_entry<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    names: NameCodec,
     stream: F,
     chain: &PackBlockChain,
     entry_index: usize,
@@ -89,23 +97,13 @@ pub fn write_chain_entry<F: io::Seek + io::Write>(
     debug_assert!(chain.contains_entry_index(entry_index));
     write_entry_at(
         bf,
+        names,
         stream,
         chain.stream_offset_for_entry(entry_index).unwrap(),
         &chain[entry_index],
     )
 }
 
-/// Write data to the end of the file returning the offset of the written
-/// data in the file.
-pub fn append_data<F: io::Seek + io::Write>(
-    mut stream: F,
-    data: &[u8],
-) -> io::Result<StreamOffset> {
-    let stream_end = stream_len(&mut stream)?;
-    stream.write_all(data)?;
-    Ok(StreamOffset(stream_end))
-}
-
 /// Write raw data at the given offset into the buffer.
 pub fn write_data_at<F: io::Seek + io::Write>(
     mut stream: F,
@@ -116,17 +114,20 @@ pub fn write_data_at<F: io::Seek + io::Write>(
     stream.write_all(data)
 }
 
-/// Create a new [`PackBlockChain`] at the end of the buffer and update the
+/// Create a new [`PackBlockChain`] in free space of the buffer and update the
 /// corresponding entry in the chain.
 pub fn allocate_new_block_chain<F: io::Seek + io::Write>(
     blowfish: Option<&Blowfish>,
+    names: NameCodec,
     mut stream: F,
+    free_space: &mut FreeSpaceMap,
     current_chain: &mut PackBlockChain,
     dir_name: &str,
     chain_entry_idx: usize,
 ) -> io::Result<PackBlockChain> {
     debug_assert!(current_chain.contains_entry_index(chain_entry_idx));
-    let new_chain_offset = stream_len(&mut stream).map(ChainIndex)?;
+    let StreamOffset(offset) = free_space.allocate(PK2_FILE_BLOCK_SIZE as u64);
+    let new_chain_offset = ChainIndex(offset);
 
     let entry = &mut current_chain[chain_entry_idx];
     debug_assert!(entry.is_empty());
@@ -135,22 +136,222 @@ pub fn allocate_new_block_chain<F: io::Seek + io::Write>(
     let mut block = PackBlock::default();
     block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, new_chain_offset, None);
     block[1] = PackEntry::new_directory(PK2_PARENT_DIR_IDENT, current_chain.chain_index(), None);
-    write_block(blowfish, &mut stream, new_chain_offset.into(), &block)?;
+    write_block(blowfish, names, &mut stream, new_chain_offset.into(), &block)?;
 
     let offset = current_chain.stream_offset_for_entry(chain_entry_idx).unwrap();
 
-    write_entry_at(blowfish, stream, offset, &current_chain[chain_entry_idx])?;
+    write_entry_at(blowfish, names, stream, offset, &current_chain[chain_entry_idx])?;
     Ok(PackBlockChain::from_blocks(vec![(new_chain_offset.into(), block)]))
 }
 
-/// Create a new empty [`PackBlock`] at the end of the buffer.
+/// Create a new empty [`PackBlock`] in free space of the buffer.
 pub fn allocate_empty_block<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
-    mut stream: F,
+    names: NameCodec,
+    stream: F,
+    free_space: &mut FreeSpaceMap,
 ) -> io::Result<(BlockOffset, PackBlock)> {
-    let offset = stream_len(&mut stream).map(BlockOffset)?;
+    let StreamOffset(offset) = free_space.allocate(PK2_FILE_BLOCK_SIZE as u64);
+    let offset = BlockOffset(offset);
     let block = PackBlock::default();
-    write_block(bf, stream, offset, &block).and(Ok((offset, block)))
+    write_block(bf, names, stream, offset, &block).and(Ok((offset, block)))
+}
+
+/// The stream of an archive. File data can be read from it through a shared
+/// reference which makes archives shareable between threads, while all
+/// modifications require exclusive access.
+pub struct StreamCell<B>(RwLock<B>);
+
+impl<B> StreamCell<B> {
+    pub fn new(stream: B) -> Self {
+        StreamCell(RwLock::new(stream))
+    }
+
+    pub fn get_mut(&mut self) -> &mut B {
+        // a panic while holding the lock can't leave the stream in an invalid state
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> B {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Shared access to the stream, only useful for streams that don't need
+    /// to be mutated to be read from.
+    #[cfg(feature = "mmap")]
+    pub fn read(&self) -> std::sync::RwLockReadGuard<'_, B> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Exclusive access to the stream through a shared reference.
+    pub fn lock(&self) -> std::sync::RwLockWriteGuard<'_, B> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<B: ReadStream> StreamCell<B> {
+    pub fn read_at(&self, StreamOffset(offset): StreamOffset, buf: &mut [u8]) -> io::Result<usize> {
+        B::read_at_shared(&self.0, offset, buf)
+    }
+
+    pub fn read_exact_at(
+        &self,
+        StreamOffset(offset): StreamOffset,
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read_at(StreamOffset(offset + read as u64), &mut buf[read..]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream file data can be read from while the archive is only borrowed
+/// immutably.
+///
+/// This is implemented for every [`Read`](io::Read) + [`Seek`](io::Seek)
+/// stream, for which concurrent reads have to take turns as every read
+/// seeks, and for [`PositionalFile`], which reads without seeking so that any
+/// number of threads can read from it at once.
+///
+/// The trait is sealed, it can't be implemented outside of this crate.
+pub trait ReadStream: sealed::ReadAt {}
+
+impl<B: sealed::ReadAt> ReadStream for B {}
+
+mod sealed {
+    use std::io;
+    use std::sync::RwLock;
+
+    pub trait ReadAt: Sized {
+        fn read_at_shared(stream: &RwLock<Self>, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    }
+}
+
+impl<B: io::Read + io::Seek> ReadAt for B {
+    fn read_at_shared(stream: &RwLock<Self>, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut stream = stream.write().unwrap_or_else(PoisonError::into_inner);
+        read_at(&mut *stream, StreamOffset(offset), buf)
+    }
+}
+
+/// A read-only file backend that uses positional reads (`pread` on unix), so
+/// that an archive opened with [`Pk2::open_positional`](crate::Pk2::open_positional)
+/// can be read from many threads at once.
+pub struct PositionalFile(std::fs::File);
+
+impl PositionalFile {
+    pub fn new(file: std::fs::File) -> Self {
+        PositionalFile(file)
+    }
+
+    pub fn into_inner(self) -> std::fs::File {
+        self.0
+    }
+}
+
+impl ReadAt for PositionalFile {
+    fn read_at_shared(stream: &RwLock<Self>, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = &stream.read().unwrap_or_else(PoisonError::into_inner).0;
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::read_at(file, buf, offset);
+        #[cfg(windows)]
+        return std::os::windows::fs::FileExt::seek_read(file, buf, offset);
+        #[cfg(not(any(unix, windows)))]
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "positional reads are unsupported"));
+    }
+}
+
+/// A read-only memory mapped file backend, see [`Pk2::open_mmap`](crate::Pk2::open_mmap).
+#[cfg(feature = "mmap")]
+pub struct MmapFile(memmap2::Mmap);
+
+#[cfg(feature = "mmap")]
+impl MmapFile {
+    /// Maps the given file into memory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it is mapped, see
+    /// [`memmap2::Mmap::map`].
+    pub unsafe fn map(file: &std::fs::File) -> io::Result<Self> {
+        memmap2::Mmap::map(file).map(MmapFile)
+    }
+
+    /// Returns the mapped bytes in the given range, cut off at the end of the
+    /// mapping.
+    pub fn slice(&self, StreamOffset(offset): StreamOffset, len: usize) -> &[u8] {
+        let start = usize::try_from(offset).map_or(self.0.len(), |start| start.min(self.0.len()));
+        let end = start.saturating_add(len).min(self.0.len());
+        &self.0[start..end]
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ReadAt for MmapFile {
+    fn read_at_shared(stream: &RwLock<Self>, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let stream = stream.read().unwrap_or_else(PoisonError::into_inner);
+        let data = stream.slice(StreamOffset(offset), buf.len());
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/// A stream whose length can be changed, allowing space at its end to be given
+/// back after the archive has been compacted.
+pub trait SetLen {
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl SetLen for std::fs::File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        std::fs::File::set_len(self, len)
+    }
+}
+
+impl SetLen for io::Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.get_mut().resize(len, 0);
+        Ok(())
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for &mut T {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        (**self).set_len(len)
+    }
+}
+
+/// A stream whose written data can be flushed to the storage device, so that
+/// the journal of a [transaction](crate::Pk2::transaction) is only removed
+/// once its writes are durable.
+pub trait SyncData {
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl SyncData for std::fs::File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        std::fs::File::sync_data(self)
+    }
+}
+
+impl SyncData for io::Cursor<Vec<u8>> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: SyncData + ?Sized> SyncData for &mut T {
+    fn sync_data(&mut self) -> io::Result<()> {
+        (**self).sync_data()
+    }
 }
 
 pub trait RawIo: Sized {
@@ -0,0 +1,64 @@
+//! Blowfish keys used by the clients of the official regions.
+//!
+//! Only keys that have been checked against archives of their client are
+//! listed, which currently is the international one.
+//!
+//! These can be passed to [`Pk2::open_detect_key`](crate::Pk2::open_detect_key)
+//! to open archives without knowing which region they stem from.
+
+/// The key of the international client (iSRO), which is also used by most
+/// archives derived from it.
+pub const ISRO: &[u8] = b"169841";
+
+/// All known keys together with the name of the region they belong to.
+pub const KNOWN: &[(&str, &[u8])] = &[("iSRO", ISRO)];
+
+/// An iterator over all [known](KNOWN) keys.
+pub fn known() -> impl Iterator<Item = &'static [u8]> {
+    KNOWN.iter().map(|&(_, key)| key)
+}
+
+/// Returns the name of the region the given key belongs to, if it is known.
+pub fn region_of(key: &[u8]) -> Option<&'static str> {
+    KNOWN.iter().find(|&&(_, known)| known == key).map(|&(region, _)| region)
+}
+
+#[cfg(test)]
+mod test {
+    use super::KNOWN;
+    use crate::{ErrorKind, Pk2};
+
+    /// Checks that an archive created with the key of `region` opens with
+    /// that key only and that the key maps back to the region.
+    fn check_region(region: &str) {
+        let &(_, key) = KNOWN.iter().find(|&&(name, _)| name == region).unwrap();
+        assert_eq!(super::region_of(key), Some(region));
+        let mut data = Vec::new();
+        Pk2::create_new_in(std::io::Cursor::new(&mut data), key).unwrap();
+        for &(other, other_key) in KNOWN {
+            let res = Pk2::open_in(std::io::Cursor::new(data.clone()), other_key);
+            match res {
+                Ok(_) => assert_eq!(other, region),
+                Err(e) => {
+                    assert_ne!(other, region);
+                    assert!(matches!(e.kind(), ErrorKind::InvalidKey));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn isro() {
+        check_region("iSRO");
+    }
+
+    /// The start of the encrypted checksum, as stored in the header of
+    /// archives encrypted with the international key.
+    #[test]
+    fn isro_header_checksum() {
+        let mut header = Vec::new();
+        Pk2::create_new_in(std::io::Cursor::new(&mut header), super::ISRO).unwrap();
+        // the checksum follows the signature, the version and the encryption flag
+        assert_eq!(header[35..38], [0xd8, 0xda, 0x30]);
+    }
+}
@@ -12,17 +12,40 @@
 //! ```
 //! # Features
 //!
-//! - `euc-kr`: enabled by default, adds `encoding_rs` as a dependency which changes string reading
-//!             and writing to use the `euc-kr` encoding which is required for the original game
-//!             archives.
+//! - `euc-kr`: enabled by default, but no longer has any effect. Names are decoded with the
+//!   encoding set through [`Pk2Options::encoding`], EUC-KR unless specified otherwise.
+//! - `mmap`: adds `memmap2` as a dependency and [`Pk2::open_mmap`], which opens an archive as a
+//!   read-only memory map whose files can be borrowed without copying via
+//!   [`File::as_bytes`](fs::File::as_bytes).
+//! - `async`: adds `tokio` as a dependency and [`AsyncPk2`], an archive whose file data is read and
+//!   written without blocking the async runtime.
+//! - `serde`: adds `serde` as a dependency and [`Pk2::export_index`], which dumps the whole index
+//!   in a serializable form, as well as [`manifest::ManifestBuilder`] to rebuild archives from such
+//!   a dump.
 mod blowfish;
 mod constants;
 mod filetime;
+mod hash;
 mod io;
+pub mod keys;
+mod options;
+#[cfg(feature = "mmap")]
+pub use self::io::MmapFile;
+pub use self::io::{PositionalFile, ReadStream, SetLen, SyncData};
+pub use self::options::{FormatProfile, Pk2Options};
+pub use encoding_rs;
 mod raw;
 
 mod archive;
-pub use self::archive::{fs, Pk2};
+#[cfg(feature = "async")]
+pub use self::archive::async_pk2::{AsyncFile, AsyncFileMut, AsyncPk2};
+pub use self::archive::diff::{
+    diff, diff_with, ArchiveDiff, Change, ChangeKind, DiffOptions, EntryTimes,
+};
+#[cfg(feature = "serde")]
+pub use self::archive::manifest;
+pub use self::archive::repair::{repair, repair_with, RepairReport};
+pub use self::archive::{check, fs, patch, Pk2, TransactionStream};
 
 mod error;
-pub use self::error::{ChainLookupError, ChainLookupResult, InvalidKey, OpenError};
+pub use self::error::{ChainLookupError, Error, ErrorKind, InvalidName, Operation, Result};
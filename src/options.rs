@@ -0,0 +1,114 @@
+//! Options for opening and creating archives.
+use encoding_rs::Encoding;
+
+use crate::constants::{PK2_CHECKSUM, PK2_SALT, PK2_SIGNATURE, PK2_VERSION};
+use crate::raw::entry::NameCodec;
+
+/// The magic values of a variant of the pk2 format.
+///
+/// Clients derived from the official ones sometimes change the salt the
+/// blowfish key is derived with or patch the header, which makes their
+/// archives fail to open with the official values.
+///
+/// Only the official values are predefined. The profile of another variant
+/// is built from its values, usually by overriding some of the official ones:
+///
+/// ```rust
+/// use pk2::{FormatProfile, Pk2Options};
+/// let profile = FormatProfile { salt: *b"0123456789", ..FormatProfile::JOYMAX };
+/// let options = Pk2Options::new().profile(profile);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FormatProfile {
+    /// The salt the blowfish key is combined with.
+    pub salt: [u8; 10],
+    /// The signature at the start of the header.
+    pub signature: [u8; 30],
+    /// The format version stored in the header.
+    pub version: u32,
+    /// The plaintext whose encrypted form is stored in the header to verify
+    /// the key.
+    pub checksum: [u8; 16],
+    /// Whether opened archives have to carry exactly this signature and
+    /// version. If `false` any signature and version is accepted, while new
+    /// archives still get the ones of this profile.
+    pub strict: bool,
+}
+
+impl FormatProfile {
+    /// The values used by the official clients.
+    pub const JOYMAX: FormatProfile = FormatProfile {
+        salt: PK2_SALT,
+        signature: *PK2_SIGNATURE,
+        version: PK2_VERSION,
+        checksum: *PK2_CHECKSUM,
+        strict: true,
+    };
+
+    /// The official values, but accepting archives with a patched signature
+    /// or version.
+    pub const LENIENT: FormatProfile = FormatProfile { strict: false, ..FormatProfile::JOYMAX };
+}
+
+impl Default for FormatProfile {
+    fn default() -> Self {
+        FormatProfile::JOYMAX
+    }
+}
+
+/// Options for [`Pk2::open_with`](crate::Pk2::open_with) and
+/// [`Pk2::create_new_with`](crate::Pk2::create_new_with).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pk2Options {
+    profile: FormatProfile,
+    names: NameCodec,
+}
+
+impl Pk2Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the format variant of the archive, [`FormatProfile::JOYMAX`] by
+    /// default.
+    pub fn profile(mut self, profile: FormatProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Sets the encoding of the names in the index, [`EUC_KR`] by default as
+    /// used by the korean and international clients.
+    ///
+    /// [`EUC_KR`]: encoding_rs::EUC_KR
+    pub fn encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.names.encoding = encoding;
+        self
+    }
+
+    /// Keeps the bytes the names of entries were read from, so that names
+    /// that are not valid in the encoding are written back unchanged when the
+    /// blocks containing them are rewritten. Disabled by default.
+    ///
+    /// The bytes are available through the `raw_name` methods of files and
+    /// directories.
+    pub fn keep_raw_names(mut self, keep: bool) -> Self {
+        self.names.keep_raw = keep;
+        self
+    }
+
+    pub fn get_profile(&self) -> &FormatProfile {
+        &self.profile
+    }
+
+    pub fn get_encoding(&self) -> &'static Encoding {
+        self.names.encoding
+    }
+
+    pub fn get_keep_raw_names(&self) -> bool {
+        self.names.keep_raw
+    }
+
+    pub(crate) fn names(&self) -> NameCodec {
+        self.names
+    }
+}
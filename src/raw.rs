@@ -1,6 +1,7 @@
 pub mod block_chain;
 pub mod block_manager;
 pub mod entry;
+pub mod free_space;
 pub mod header;
 
 use std::ops;
@@ -1,23 +1,40 @@
+use std::collections::HashMap;
 use std::io::{Read, Result as IoResult, Write};
 use std::ops;
 
 use crate::constants::*;
 use crate::error::{ChainLookupError, ChainLookupResult};
 use crate::io::RawIo;
-use crate::raw::entry::{DirectoryEntry, PackEntry};
-use crate::raw::{BlockOffset, ChainIndex, EntryOffset};
+use crate::raw::entry::{DirectoryEntry, NameCodec, PackEntry};
+use crate::raw::{BlockOffset, ChainIndex, EntryOffset, StreamOffset};
+
+/// The amount of entries that may change before a [`NameIndex`] is rebuilt.
+const NAME_INDEX_MAX_STALE: usize = 64;
+
+/// Maps the ASCII-lowercased names of the entries of a chain to their index.
+///
+/// Entries are only handed out mutably by index, so instead of updating the
+/// map on every change the indices of entries that might have changed are
+/// remembered and checked directly on lookups, until there are too many of
+/// them and the map gets rebuilt.
+struct NameIndex {
+    names: HashMap<Box<str>, usize>,
+    // entries that might have changed since the map was built, `None` if all of them might have
+    stale: Option<Vec<usize>>,
+}
 
 /// A collection of [`PackBlock`]s where each block's next_block field points to
 /// the following block in the file. A PackBlockChain is never empty.
 pub struct PackBlockChain {
     blocks: Vec<(BlockOffset, PackBlock)>,
+    name_index: Option<NameIndex>,
 }
 
 #[allow(dead_code)]
 impl PackBlockChain {
     pub fn from_blocks(blocks: Vec<(BlockOffset, PackBlock)>) -> Self {
         debug_assert!(!blocks.is_empty());
-        PackBlockChain { blocks }
+        PackBlockChain { blocks, name_index: None }
     }
 
     pub fn push_and_link(&mut self, offset: BlockOffset, block: PackBlock) {
@@ -29,6 +46,69 @@ impl PackBlockChain {
         self.blocks.pop();
         assert!(!self.blocks.is_empty());
         self.last_entry_mut().set_next_block(BlockOffset(0));
+        self.mark_all_stale();
+    }
+
+    /// Enables or disables the name index of this chain, which turns lookups
+    /// by name into hash map lookups instead of scanning all entries.
+    pub fn set_name_index(&mut self, enabled: bool) {
+        self.name_index = None;
+        if enabled {
+            self.rebuild_name_index();
+        }
+    }
+
+    fn rebuild_name_index(&mut self) {
+        let mut names = HashMap::new();
+        for (idx, entry) in self.entries().enumerate() {
+            if let Some(name) = entry.name() {
+                // keep the first entry for duplicated names, just like a linear search would
+                names.entry(name.to_ascii_lowercase().into_boxed_str()).or_insert(idx);
+            }
+        }
+        self.name_index = Some(NameIndex { names, stale: Some(Vec::new()) });
+    }
+
+    /// Records that the entry at `idx` is about to be handed out mutably.
+    fn mark_stale(&mut self, idx: usize) {
+        let Some(index) = &mut self.name_index else { return };
+        match &mut index.stale {
+            Some(stale) if stale.len() < NAME_INDEX_MAX_STALE => {
+                if !stale.contains(&idx) {
+                    stale.push(idx);
+                }
+            }
+            // the entries that changed so far have been changed already, so this picks them up
+            _ => {
+                self.rebuild_name_index();
+                self.name_index.as_mut().and_then(|index| index.stale.as_mut()).unwrap().push(idx);
+            }
+        }
+    }
+
+    fn mark_all_stale(&mut self) {
+        if let Some(index) = &mut self.name_index {
+            index.stale = None;
+        }
+    }
+
+    /// Returns the index of the first entry with the given name ignoring
+    /// ASCII case.
+    pub fn find_entry(&self, name: &str) -> Option<usize> {
+        if let Some(NameIndex { names, stale: Some(stale) }) = &self.name_index {
+            let changed = stale
+                .iter()
+                .copied()
+                .filter(|&idx| self.get(idx).is_some_and(|e| e.name_eq_ignore_ascii_case(name)))
+                .min();
+            match names.get(&*name.to_ascii_lowercase()) {
+                // the indexed entry might have been renamed, so fall back to a linear search
+                Some(idx) if stale.contains(idx) => (),
+                Some(&idx) => return Some(changed.map_or(idx, |changed| changed.min(idx))),
+                None => return changed,
+            }
+        }
+        self.entries().position(|entry| entry.name_eq_ignore_ascii_case(name))
     }
 
     /// This blockchains chain index/file offset.
@@ -45,6 +125,16 @@ impl PackBlockChain {
         })
     }
 
+    /// An iterator over the blocks of this chain and their stream offsets.
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockOffset, &PackBlock)> {
+        self.blocks.iter().map(|(offset, block)| (*offset, block))
+    }
+
+    /// An iterator over the stream offsets of the blocks of this chain.
+    pub fn block_offsets(&self) -> impl Iterator<Item = BlockOffset> + '_ {
+        self.blocks.iter().map(|&(offset, _)| offset)
+    }
+
     /// Returns the number of PackEntries in this chain.
     pub fn num_entries(&self) -> usize {
         self.blocks.len() * PK2_FILE_BLOCK_ENTRY_COUNT
@@ -73,6 +163,7 @@ impl PackBlockChain {
 
     /// An iterator over the entries of this chain.
     pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut PackEntry> {
+        self.mark_all_stale();
         self.blocks.iter_mut().flat_map(|block| &mut block.1.entries)
     }
 
@@ -85,6 +176,7 @@ impl PackBlockChain {
 
     /// Get the PackEntry at the specified offset.
     pub fn get_mut(&mut self, entry: usize) -> Option<&mut PackEntry> {
+        self.mark_stale(entry);
         self.blocks
             .get_mut(entry / PK2_FILE_BLOCK_ENTRY_COUNT)
             .and_then(|(_, block)| block.get_mut(entry % PK2_FILE_BLOCK_ENTRY_COUNT))
@@ -102,14 +194,36 @@ impl PackBlockChain {
     /// offset of the ['PackBlockChain'] corresponding to the directory if
     /// successful.
     pub fn find_block_chain_index_of(&self, directory: &str) -> ChainLookupResult<ChainIndex> {
-        self.entries()
-            .find(|entry| entry.name_eq_ignore_ascii_case(directory))
+        self.find_entry(directory)
+            .map(|idx| &self[idx])
             .ok_or(ChainLookupError::NotFound)?
             .as_directory()
             .map(DirectoryEntry::children_position)
             .ok_or(ChainLookupError::ExpectedDirectory)
     }
 
+    /// Moves the blocks of this chain and everything its entries point to
+    /// according to `relocate`, which maps old stream offsets to new ones.
+    pub fn relocate(&mut self, relocate: impl Fn(u64) -> u64) {
+        for (BlockOffset(offset), block) in &mut self.blocks {
+            *offset = relocate(*offset);
+            for entry in block.entries_mut() {
+                match entry {
+                    PackEntry::File(file) if file.size > 0 => {
+                        file.pos_data = StreamOffset(relocate(file.pos_data.0))
+                    }
+                    PackEntry::Directory(dir) => {
+                        dir.pos_children = ChainIndex(relocate(dir.pos_children.0))
+                    }
+                    _ => (),
+                }
+                if let Some(next_block) = entry.next_block() {
+                    entry.set_next_block(BlockOffset(relocate(next_block.get())));
+                }
+            }
+        }
+    }
+
     pub fn sort(&mut self, scratch: &mut Vec<PackEntry>) {
         use std::cmp::Ordering;
         self.entries_mut()
@@ -128,6 +242,9 @@ impl PackBlockChain {
         self.entries_mut()
             .zip(scratch.drain(..))
             .for_each(|(dst, src)| drop(std::mem::replace(dst, src)));
+        if self.name_index.is_some() {
+            self.rebuild_name_index();
+        }
     }
 }
 
@@ -140,6 +257,7 @@ impl ops::Index<usize> for PackBlockChain {
 
 impl ops::IndexMut<usize> for PackBlockChain {
     fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
+        self.mark_stale(idx);
         &mut self.blocks[idx / PK2_FILE_BLOCK_ENTRY_COUNT].1[idx % PK2_FILE_BLOCK_ENTRY_COUNT]
     }
 }
@@ -152,11 +270,11 @@ pub struct PackBlock {
 
 #[allow(dead_code)]
 impl PackBlock {
-    pub fn entries(&self) -> std::slice::Iter<PackEntry> {
+    pub fn entries(&self) -> std::slice::Iter<'_, PackEntry> {
         self.entries.iter()
     }
 
-    pub fn entries_mut(&mut self) -> std::slice::IterMut<PackEntry> {
+    pub fn entries_mut(&mut self) -> std::slice::IterMut<'_, PackEntry> {
         self.entries.iter_mut()
     }
 
@@ -170,16 +288,26 @@ impl PackBlock {
 }
 
 impl RawIo for PackBlock {
-    fn from_reader<R: Read>(mut r: R) -> IoResult<Self> {
+    fn from_reader<R: Read>(r: R) -> IoResult<Self> {
+        Self::read_with(r, NameCodec::default())
+    }
+
+    fn to_writer<W: Write>(&self, w: W) -> IoResult<()> {
+        self.write_with(w, NameCodec::default())
+    }
+}
+
+impl PackBlock {
+    pub fn read_with<R: Read>(mut r: R, names: NameCodec) -> IoResult<Self> {
         let mut entries: [PackEntry; PK2_FILE_BLOCK_ENTRY_COUNT] = Default::default();
         for entry in &mut entries {
-            *entry = PackEntry::from_reader(&mut r)?;
+            *entry = PackEntry::read_with(&mut r, names)?;
         }
         Ok(PackBlock { entries })
     }
 
-    fn to_writer<W: Write>(&self, mut w: W) -> IoResult<()> {
-        self.entries.iter().try_for_each(|entry| entry.to_writer(&mut w))
+    pub fn write_with<W: Write>(&self, mut w: W, names: NameCodec) -> IoResult<()> {
+        self.entries.iter().try_for_each(|entry| entry.write_with(&mut w, names))
     }
 }
 
@@ -1,22 +1,54 @@
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::path::{Component, Path};
+use std::sync::{Mutex, PoisonError};
 
 use crate::blowfish::Blowfish;
 use crate::constants::{PK2_FILE_BLOCK_ENTRY_COUNT, PK2_ROOT_BLOCK, PK2_ROOT_BLOCK_VIRTUAL};
-use crate::error::{ChainLookupError, ChainLookupResult, OpenResult};
+use crate::error::{ChainLookupError, Error, Result};
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
-use crate::raw::entry::{DirectoryEntry, PackEntry};
+use crate::raw::entry::{DirectoryEntry, NameCodec, PackEntry};
 use crate::raw::{BlockOffset, ChainIndex};
 
+type ChainMap<T> = HashMap<ChainIndex, T, NoHashHasherBuilder>;
+
+trait IndexStream: io::Read + io::Seek + Send {}
+impl<T: io::Read + io::Seek + Send> IndexStream for T {}
+
 /// Simple BlockManager backed by a hashmap.
+#[derive(Default)]
 pub struct BlockManager {
-    chains: HashMap<ChainIndex, PackBlockChain, NoHashHasherBuilder>,
+    chains: ChainMap<PackBlockChain>,
+    name_index: bool,
+    lazy: Option<LazyChains>,
+    /// The number of directories linking to chains that are linked more than
+    /// once. Only known for managers that are not lazy.
+    shared: ChainMap<usize>,
+}
+
+/// The state of a [`BlockManager`] that loads chains the first time they are
+/// accessed instead of parsing the whole index up front.
+struct LazyChains {
+    blowfish: Option<Blowfish>,
+    names: NameCodec,
+    stream: Mutex<Box<dyn IndexStream>>,
+    /// Chains loaded through shared references. Their boxes are only ever
+    /// removed through mutable references to the manager, which keeps the
+    /// chains in place for as long as they are borrowed.
+    loaded: Mutex<ChainMap<Box<PackBlockChain>>>,
+    /// Chains that have been removed and must not be loaded again.
+    removed: HashSet<ChainIndex, NoHashHasherBuilder>,
+    /// Chains that failed to load, so that they are not read over and over.
+    failed: Mutex<ChainMap<Error>>,
 }
 
 impl BlockManager {
     /// Parses the complete index of a pk2 file
-    pub fn new<F: io::Read + io::Seek>(bf: Option<&Blowfish>, mut stream: F) -> OpenResult<Self> {
+    pub fn new<F: io::Read + io::Seek>(
+        bf: Option<&Blowfish>,
+        names: NameCodec,
+        mut stream: F,
+    ) -> Result<Self> {
         let mut chains = HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder);
         // used to prevent an infinite loop that can be caused by specific files
         let mut visited_block_set = HashSet::with_capacity_and_hasher(32, NoHashHasherBuilder);
@@ -26,8 +58,13 @@ impl BlockManager {
                 // skip offsets that are being pointed to multiple times
                 continue;
             }
-            let block_chain =
-                Self::read_chain_from_stream_at(&mut visited_block_set, bf, &mut stream, offset)?;
+            let block_chain = Self::read_chain_from_stream_at(
+                &mut visited_block_set,
+                bf,
+                names,
+                &mut stream,
+                offset,
+            )?;
             visited_block_set.clear();
 
             // put all folder offsets of this chain into the stack to parse them next
@@ -40,11 +77,198 @@ impl BlockManager {
             );
             chains.insert(offset, block_chain);
         }
-        let mut this = BlockManager { chains };
+        let mut this = BlockManager { chains, name_index: false, lazy: None, ..Self::default() };
         this.insert_virtual_root();
+        this.count_shared();
         Ok(this)
     }
 
+    /// Parses only the root chain of a pk2 file, loading all other chains
+    /// from `stream` the first time they are accessed.
+    pub fn new_lazy<F: io::Read + io::Seek + Send + 'static>(
+        bf: Option<&Blowfish>,
+        names: NameCodec,
+        mut stream: F,
+    ) -> Result<Self> {
+        let root = Self::read_chain_from_stream_at(
+            &mut HashSet::default(),
+            bf,
+            names,
+            &mut stream,
+            PK2_ROOT_BLOCK,
+        )?;
+        let mut chains = ChainMap::default();
+        chains.insert(PK2_ROOT_BLOCK, root);
+        let lazy = LazyChains {
+            blowfish: bf.cloned(),
+            names,
+            stream: Mutex::new(Box::new(stream)),
+            loaded: Mutex::default(),
+            removed: HashSet::default(),
+            failed: Mutex::default(),
+        };
+        let mut this =
+            BlockManager { chains, name_index: false, lazy: Some(lazy), ..Self::default() };
+        this.insert_virtual_root();
+        Ok(this)
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    /// Loads every chain that has not been loaded yet, turning a lazy manager
+    /// into an eager one.
+    pub fn load_all(&mut self) -> Result<()> {
+        self.absorb_loaded();
+        let Some(lazy) = &self.lazy else { return Ok(()) };
+        let mut stream = lazy.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut offsets = Self::child_chains(self.chains.values()).collect::<Vec<_>>();
+        let mut visited_block_set = HashSet::default();
+        while let Some(offset) = offsets.pop() {
+            if self.chains.contains_key(&offset) || lazy.removed.contains(&offset) {
+                continue;
+            }
+            let mut chain = Self::read_chain_from_stream_at(
+                &mut visited_block_set,
+                lazy.blowfish.as_ref(),
+                lazy.names,
+                &mut **stream,
+                offset,
+            )?;
+            visited_block_set.clear();
+            chain.set_name_index(self.name_index);
+            offsets.extend(Self::child_chains([&chain]));
+            self.chains.insert(offset, chain);
+        }
+        drop(stream);
+        self.lazy = None;
+        self.count_shared();
+        Ok(())
+    }
+
+    /// Counts the links to every chain, remembering the ones that are linked
+    /// more than once. The root chain is linked by the virtual root.
+    fn count_shared(&mut self) {
+        let mut links = ChainMap::<usize>::default();
+        for chain in Self::child_chains(self.chains.values()) {
+            *links.entry(chain).or_default() += 1;
+        }
+        links.retain(|_, &mut count| count > 1);
+        self.shared = links;
+    }
+
+    /// Drops one of the links to `chain`, returning whether it was the last
+    /// one, so that the chain can be released. Lazy managers do not know all
+    /// links, so they have to be [fully loaded](BlockManager::load_all) first.
+    pub fn unlink(&mut self, chain: ChainIndex) -> bool {
+        if self.is_lazy() {
+            return false;
+        }
+        match self.shared.get_mut(&chain) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    self.shared.remove(&chain);
+                }
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn child_chains<'a>(
+        chains: impl IntoIterator<Item = &'a PackBlockChain>,
+    ) -> impl Iterator<Item = ChainIndex> {
+        chains
+            .into_iter()
+            .flat_map(PackBlockChain::entries)
+            .filter_map(PackEntry::as_directory)
+            .filter(|d| d.is_normal_link())
+            .map(DirectoryEntry::children_position)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Reads the chain at the given index from the stream of a lazy manager,
+    /// returning `None` if it is not one of its chains.
+    fn read_lazy_chain(&self, chain: ChainIndex) -> Result<Option<PackBlockChain>> {
+        let Some(lazy) = self.lazy.as_ref() else { return Ok(None) };
+        if chain == PK2_ROOT_BLOCK_VIRTUAL || lazy.removed.contains(&chain) {
+            return Ok(None);
+        }
+        let mut failed = lazy.failed.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(e) = failed.get(&chain) {
+            return Err(e.duplicate());
+        }
+        let mut stream = lazy.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        let res = Self::read_chain_from_stream_at(
+            &mut HashSet::default(),
+            lazy.blowfish.as_ref(),
+            lazy.names,
+            &mut **stream,
+            chain,
+        );
+        match res {
+            Ok(mut chain) => {
+                chain.set_name_index(self.name_index);
+                Ok(Some(chain))
+            }
+            Err(e) => {
+                failed.insert(chain, e.duplicate());
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns a chain of a lazy manager, loading it if it has not been
+    /// accessed before.
+    fn get_lazy(&self, chain: ChainIndex) -> Result<Option<&PackBlockChain>> {
+        let Some(lazy) = self.lazy.as_ref() else { return Ok(None) };
+        let mut loaded = lazy.loaded.lock().unwrap_or_else(PoisonError::into_inner);
+        let ptr: *const PackBlockChain = match loaded.get(&chain) {
+            Some(loaded) => &**loaded,
+            None => match self.read_lazy_chain(chain)? {
+                Some(read) => &**loaded.entry(chain).or_insert(Box::new(read)),
+                None => return Ok(None),
+            },
+        };
+        // SAFETY: The chain is boxed, so it stays in place when the map reallocates. Boxes are
+        // only removed from the map through a mutable reference to self, which cannot exist
+        // while the returned reference is alive.
+        Ok(Some(unsafe { &*ptr }))
+    }
+
+    /// Moves the chains loaded through shared references into the main map.
+    /// Discards all chains of a lazy manager and parses its root chain again,
+    /// leaving it as if it had just been created.
+    pub fn reload_lazy(&mut self) -> Result<()> {
+        let Some(lazy) = &mut self.lazy else { return Ok(()) };
+        let stream = lazy.stream.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let mut root = Self::read_chain_from_stream_at(
+            &mut HashSet::default(),
+            lazy.blowfish.as_ref(),
+            lazy.names,
+            &mut **stream,
+            PK2_ROOT_BLOCK,
+        )?;
+        root.set_name_index(self.name_index);
+        lazy.loaded.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        lazy.failed.get_mut().unwrap_or_else(PoisonError::into_inner).clear();
+        lazy.removed.clear();
+        self.chains.clear();
+        self.chains.insert(PK2_ROOT_BLOCK, root);
+        self.insert_virtual_root();
+        Ok(())
+    }
+
+    fn absorb_loaded(&mut self) {
+        if let Some(lazy) = &mut self.lazy {
+            let loaded = lazy.loaded.get_mut().unwrap_or_else(PoisonError::into_inner);
+            self.chains.extend(loaded.drain().map(|(idx, chain)| (idx, *chain)));
+        }
+    }
+
     fn insert_virtual_root(&mut self) {
         // dummy entry to give root a proper name
         let mut virtual_root = PackBlockChain::from_blocks(vec![(
@@ -56,17 +280,18 @@ impl BlockManager {
     }
 
     /// Reads a [`PackBlockChain`] from the given file at the specified offset.
-    fn read_chain_from_stream_at<F: io::Read + io::Seek>(
+    fn read_chain_from_stream_at<F: io::Read + io::Seek + ?Sized>(
         visited_block_set: &mut HashSet<BlockOffset, NoHashHasherBuilder>,
         bf: Option<&Blowfish>,
+        names: NameCodec,
         stream: &mut F,
         offset: ChainIndex,
-    ) -> OpenResult<PackBlockChain> {
+    ) -> Result<PackBlockChain> {
         let mut blocks = Vec::new();
         let mut offset = offset.into();
 
         while visited_block_set.insert(offset) {
-            let block = crate::io::read_block_at(bf, &mut *stream, offset)?;
+            let block = crate::io::read_block_at(bf, names, &mut *stream, offset)?;
             let nc = block.entries().last().and_then(PackEntry::next_block);
             blocks.push((offset, block));
             match nc {
@@ -78,23 +303,77 @@ impl BlockManager {
     }
 
     pub fn get(&self, chain: ChainIndex) -> Option<&PackBlockChain> {
-        self.chains.get(&chain)
+        self.try_get(chain).ok()
+    }
+
+    /// Returns the chain, failing with the error that occurred while loading
+    /// it for lazy managers.
+    pub fn try_get(&self, chain: ChainIndex) -> Result<&PackBlockChain> {
+        match self.chains.get(&chain) {
+            Some(chain) => Ok(chain),
+            None => self.get_lazy(chain)?.ok_or(ChainLookupError::InvalidChainIndex.into()),
+        }
     }
 
     pub fn get_mut(&mut self, chain: ChainIndex) -> Option<&mut PackBlockChain> {
+        self.try_get_mut(chain).ok()
+    }
+
+    pub fn try_get_mut(&mut self, chain: ChainIndex) -> Result<&mut PackBlockChain> {
         assert_ne!(chain, PK2_ROOT_BLOCK_VIRTUAL);
-        self.chains.get_mut(&chain)
+        self.absorb_loaded();
+        if !self.chains.contains_key(&chain) {
+            let loaded = self.read_lazy_chain(chain)?.ok_or(ChainLookupError::InvalidChainIndex)?;
+            self.chains.insert(chain, loaded);
+        }
+        Ok(self.chains.get_mut(&chain).unwrap())
     }
 
-    pub fn insert(&mut self, chain: ChainIndex, block: PackBlockChain) {
+    pub fn insert(&mut self, chain: ChainIndex, mut block: PackBlockChain) {
+        if self.name_index {
+            block.set_name_index(true);
+        }
+        if let Some(lazy) = &mut self.lazy {
+            lazy.removed.remove(&chain);
+        }
         self.chains.insert(chain, block);
     }
 
+    pub fn name_index(&self) -> bool {
+        self.name_index
+    }
+
+    /// Enables or disables the name index of all chains, see
+    /// [`PackBlockChain::set_name_index`].
+    pub fn set_name_index(&mut self, enabled: bool) {
+        self.absorb_loaded();
+        self.name_index = enabled;
+        self.chains.values_mut().for_each(|chain| chain.set_name_index(enabled));
+    }
+
+    pub fn remove(&mut self, chain: ChainIndex) -> Option<PackBlockChain> {
+        self.get_mut(chain)?;
+        if let Some(lazy) = &mut self.lazy {
+            lazy.removed.insert(chain);
+        }
+        self.chains.remove(&chain)
+    }
+
+    /// An iterator over all chains of the archive, excluding the virtual root.
+    /// Lazy managers have to be [fully loaded](BlockManager::load_all) first.
+    pub fn chains(&self) -> impl Iterator<Item = &PackBlockChain> {
+        debug_assert!(!self.is_lazy(), "iterating the chains of a partially loaded index");
+        self.chains
+            .iter()
+            .filter(|&(&idx, _)| idx != PK2_ROOT_BLOCK_VIRTUAL)
+            .map(|(_, chain)| chain)
+    }
+
     pub fn resolve_path_to_parent<'path>(
         &self,
         current_chain: ChainIndex,
         path: &'path Path,
-    ) -> ChainLookupResult<(ChainIndex, &'path str)> {
+    ) -> Result<(ChainIndex, &'path str)> {
         let mut components = path.components();
 
         if let Some(c) = components.next_back() {
@@ -103,7 +382,7 @@ impl BlockManager {
             let name = c.as_os_str().to_str().ok_or(ChainLookupError::InvalidPath)?;
             Ok((parent_index, name))
         } else {
-            Err(ChainLookupError::InvalidPath)
+            Err(ChainLookupError::InvalidPath.into())
         }
     }
 
@@ -114,16 +393,11 @@ impl BlockManager {
         &self,
         current_chain: ChainIndex,
         path: &Path,
-    ) -> ChainLookupResult<(ChainIndex, usize, &PackEntry)> {
+    ) -> Result<(ChainIndex, usize, &PackEntry)> {
         self.resolve_path_to_parent(current_chain, path).and_then(|(parent_index, name)| {
-            self.chains
-                .get(&parent_index)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .entries()
-                .enumerate()
-                .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
-                .ok_or(ChainLookupError::NotFound)
-                .map(|(idx, entry)| (parent_index, idx, entry))
+            let chain = self.try_get(parent_index)?;
+            let idx = chain.find_entry(name).ok_or(ChainLookupError::NotFound)?;
+            Ok((parent_index, idx, &chain[idx]))
         })
     }
 
@@ -131,16 +405,11 @@ impl BlockManager {
         &mut self,
         current_chain: ChainIndex,
         path: &Path,
-    ) -> ChainLookupResult<(ChainIndex, usize, &mut PackEntry)> {
+    ) -> Result<(ChainIndex, usize, &mut PackEntry)> {
         self.resolve_path_to_parent(current_chain, path).and_then(move |(parent_index, name)| {
-            self.chains
-                .get_mut(&parent_index)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .entries_mut()
-                .enumerate()
-                .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
-                .ok_or(ChainLookupError::NotFound)
-                .map(|(idx, entry)| (parent_index, idx, entry))
+            let chain = self.try_get_mut(parent_index)?;
+            let idx = chain.find_entry(name).ok_or(ChainLookupError::NotFound)?;
+            Ok((parent_index, idx, &mut chain[idx]))
         })
     }
 
@@ -150,13 +419,10 @@ impl BlockManager {
         &self,
         current_chain: ChainIndex,
         path: &Path,
-    ) -> ChainLookupResult<ChainIndex> {
+    ) -> Result<ChainIndex> {
         path.components().try_fold(current_chain, |idx, component| {
             let comp = component.as_os_str().to_str().ok_or(ChainLookupError::InvalidPath)?;
-            self.chains
-                .get(&idx)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .find_block_chain_index_of(comp)
+            Ok(self.try_get(idx)?.find_block_chain_index_of(comp)?)
         })
     }
 
@@ -168,21 +434,15 @@ impl BlockManager {
         &self,
         mut chain: ChainIndex,
         path: &'p Path,
-    ) -> ChainLookupResult<Option<(ChainIndex, std::iter::Peekable<std::path::Components<'p>>)>>
-    {
+    ) -> Result<Option<(ChainIndex, std::iter::Peekable<std::path::Components<'p>>)>> {
         let mut components = path.components().peekable();
         while let Some(component) = components.peek() {
             let name = component.as_os_str().to_str().ok_or(ChainLookupError::InvalidPath)?;
-            match self
-                .chains
-                .get(&chain)
-                .ok_or(ChainLookupError::InvalidChainIndex)?
-                .find_block_chain_index_of(name)
-            {
+            match self.try_get(chain)?.find_block_chain_index_of(name) {
                 Ok(i) => chain = i,
                 // lies outside of the archive
                 Err(ChainLookupError::NotFound) if component == &Component::ParentDir => {
-                    return Err(ChainLookupError::InvalidPath)
+                    return Err(ChainLookupError::InvalidPath.into())
                 }
                 // found a non-existent part, we are done here
                 Err(ChainLookupError::NotFound) => break,
@@ -192,7 +452,7 @@ impl BlockManager {
                         // this means the path has been fully searched
                         Ok(None)
                     } else {
-                        Err(ChainLookupError::ExpectedDirectory)
+                        Err(ChainLookupError::ExpectedDirectory.into())
                     };
                 }
                 Err(_) => unreachable!(),
@@ -206,6 +466,17 @@ impl BlockManager {
         }
     }
 
+    /// Moves all chains according to `relocate`, which maps old stream offsets
+    /// to new ones.
+    pub fn relocate(&mut self, relocate: impl Fn(u64) -> u64) {
+        let mut chains = HashMap::with_capacity_and_hasher(self.chains.len(), NoHashHasherBuilder);
+        for (_, mut chain) in self.chains.drain() {
+            chain.relocate(&relocate);
+            chains.insert(chain.chain_index(), chain);
+        }
+        self.chains = chains;
+    }
+
     pub fn sort(&mut self) {
         let scratch = &mut Vec::with_capacity(4 * PK2_FILE_BLOCK_ENTRY_COUNT);
         for chain in self.chains.values_mut() {
@@ -1,15 +1,65 @@
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 
+use encoding_rs::Encoding;
+
 use std::io::{Read, Result as IoResult, Write};
 use std::mem;
 use std::num::NonZeroU64;
 use std::time::SystemTime;
 
-use crate::constants::{PK2_CURRENT_DIR_IDENT, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT};
+use crate::constants::{
+    PK2_CURRENT_DIR_IDENT, PK2_FILE_ENTRY_SIZE, PK2_MAX_NAME_LEN, PK2_PARENT_DIR_IDENT,
+};
+use crate::error::InvalidName;
 use crate::filetime::FILETIME;
 use crate::io::RawIo;
 use crate::raw::{BlockOffset, ChainIndex, StreamOffset};
 
+/// How the names of entries are encoded in the index.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NameCodec {
+    pub encoding: &'static Encoding,
+    /// Whether to keep the bytes names were read from, which are written back
+    /// instead of the re-encoded name as long as the entry is not renamed.
+    pub keep_raw: bool,
+}
+
+impl Default for NameCodec {
+    fn default() -> Self {
+        NameCodec { encoding: encoding_rs::EUC_KR, keep_raw: false }
+    }
+}
+
+impl NameCodec {
+    fn decode(self, raw: &[u8]) -> (Box<str>, Option<Box<[u8]>>) {
+        let name = self.encoding.decode_without_bom_handling(raw).0;
+        (name.into_owned().into_boxed_str(), self.keep_raw.then(|| raw.into()))
+    }
+
+    fn encode(self, name: &str) -> Vec<u8> {
+        self.encoding.encode(name).0.into_owned()
+    }
+
+    /// Checks that `name` can be written to an entry without being altered.
+    pub fn validate(self, name: &str) -> Result<(), InvalidName> {
+        if name.is_empty() {
+            return Err(InvalidName::Empty);
+        }
+        if name.contains(['/', '\\']) {
+            return Err(InvalidName::Separator);
+        }
+        let (encoded, _, unmappable) = self.encoding.encode(name);
+        // a nul would terminate the name early
+        if unmappable || name.contains('\0') {
+            Err(InvalidName::Unencodable)
+        } else if encoded.len() > PK2_MAX_NAME_LEN {
+            Err(InvalidName::TooLong)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct EmptyEntry {
     next_block: Option<NonZeroU64>,
@@ -24,10 +74,11 @@ impl EmptyEntry {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DirectoryEntry {
     name: Box<str>,
+    raw_name: Option<Box<[u8]>>,
     pub(crate) access_time: FILETIME,
     pub(crate) create_time: FILETIME,
     pub(crate) modify_time: FILETIME,
-    pos_children: ChainIndex,
+    pub(crate) pos_children: ChainIndex,
     next_block: Option<NonZeroU64>,
 }
 
@@ -36,6 +87,7 @@ impl DirectoryEntry {
         let ftime = FILETIME::now();
         DirectoryEntry {
             name,
+            raw_name: None,
             access_time: ftime,
             create_time: ftime,
             modify_time: ftime,
@@ -52,6 +104,7 @@ impl DirectoryEntry {
     ) -> Self {
         DirectoryEntry {
             name,
+            raw_name: None,
             access_time: FILETIME::default(),
             create_time: FILETIME::default(),
             modify_time: FILETIME::default(),
@@ -64,6 +117,11 @@ impl DirectoryEntry {
         &self.name
     }
 
+    /// The bytes the name was read from, if they have been kept.
+    pub fn raw_name(&self) -> Option<&[u8]> {
+        self.raw_name.as_deref()
+    }
+
     pub fn access_time(&self) -> Option<SystemTime> {
         self.access_time.into_systime()
     }
@@ -96,6 +154,7 @@ impl DirectoryEntry {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FileEntry {
     name: Box<str>,
+    raw_name: Option<Box<[u8]>>,
     pub(crate) access_time: FILETIME,
     pub(crate) create_time: FILETIME,
     pub(crate) modify_time: FILETIME,
@@ -114,6 +173,7 @@ impl FileEntry {
         let ftime = FILETIME::now();
         FileEntry {
             name,
+            raw_name: None,
             access_time: ftime,
             create_time: ftime,
             modify_time: ftime,
@@ -132,6 +192,7 @@ impl FileEntry {
     ) -> Self {
         FileEntry {
             name,
+            raw_name: None,
             access_time: FILETIME::default(),
             create_time: FILETIME::default(),
             modify_time: FILETIME::default(),
@@ -145,6 +206,11 @@ impl FileEntry {
         &self.name
     }
 
+    /// The bytes the name was read from, if they have been kept.
+    pub fn raw_name(&self) -> Option<&[u8]> {
+        self.raw_name.as_deref()
+    }
+
     pub fn access_time(&self) -> Option<SystemTime> {
         self.access_time.into_systime()
     }
@@ -209,6 +275,13 @@ impl PackEntry {
         }
     }
 
+    pub fn as_directory_mut(&mut self) -> Option<&mut DirectoryEntry> {
+        match self {
+            PackEntry::Directory(entry) => Some(entry),
+            _ => None,
+        }
+    }
+
     pub fn as_file(&self) -> Option<&FileEntry> {
         match self {
             PackEntry::File(entry) => Some(entry),
@@ -256,6 +329,18 @@ impl PackEntry {
         }
     }
 
+    /// Renames this entry, does nothing if the entry is empty.
+    pub fn set_name(&mut self, new_name: impl Into<Box<str>>) {
+        match self {
+            PackEntry::Empty(_) => (),
+            PackEntry::Directory(DirectoryEntry { name, raw_name, .. })
+            | PackEntry::File(FileEntry { name, raw_name, .. }) => {
+                *name = new_name.into();
+                *raw_name = None;
+            }
+        }
+    }
+
     pub fn name_eq_ignore_ascii_case(&self, other: &str) -> bool {
         self.name().map(|this| this.eq_ignore_ascii_case(other)).unwrap_or(false)
     }
@@ -274,9 +359,19 @@ impl PackEntry {
 }
 
 impl RawIo for PackEntry {
+    fn from_reader<R: Read>(r: R) -> IoResult<Self> {
+        Self::read_with(r, NameCodec::default())
+    }
+
+    fn to_writer<W: Write>(&self, w: W) -> IoResult<()> {
+        self.write_with(w, NameCodec::default())
+    }
+}
+
+impl PackEntry {
     /// Reads an entry from the given Read instance always reading exactly
-    /// PK2_FILE_ENTRY_SIZE bytes.
-    fn from_reader<R: Read>(mut r: R) -> IoResult<Self> {
+    /// PK2_FILE_ENTRY_SIZE bytes, decoding its name with `names`.
+    pub fn read_with<R: Read>(mut r: R, names: NameCodec) -> IoResult<Self> {
         match r.read_u8()? {
             0 => {
                 r.read_exact(
@@ -290,15 +385,11 @@ impl RawIo for PackEntry {
                 Ok(PackEntry::new_empty(next_block))
             }
             ty @ (1 | 2) => {
-                let name = {
+                let (name, raw_name) = {
                     let mut buf = [0; 81];
                     r.read_exact(&mut buf)?;
-                    let end = buf.iter().position(|b| *b == 0).unwrap_or_else(|| buf.len());
-                    #[cfg(feature = "euc-kr")]
-                    let name = encoding_rs::EUC_KR.decode_without_bom_handling(&buf[..end]).0;
-                    #[cfg(not(feature = "euc-kr"))]
-                    let name = String::from_utf8_lossy(&buf[..end]);
-                    name.into_owned().into_boxed_str()
+                    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+                    names.decode(&buf[..end])
                 };
                 let access_time = FILETIME {
                     dwLowDateTime: r.read_u32::<LE>()?,
@@ -320,6 +411,7 @@ impl RawIo for PackEntry {
                 Ok(if ty == 1 {
                     PackEntry::Directory(DirectoryEntry {
                         name,
+                        raw_name,
                         access_time,
                         create_time,
                         modify_time,
@@ -329,6 +421,7 @@ impl RawIo for PackEntry {
                 } else {
                     PackEntry::File(FileEntry {
                         name,
+                        raw_name,
                         access_time,
                         create_time,
                         modify_time,
@@ -345,7 +438,9 @@ impl RawIo for PackEntry {
         }
     }
 
-    fn to_writer<W: Write>(&self, mut w: W) -> IoResult<()> {
+    /// Writes the entry, encoding its name with `names` unless the bytes it
+    /// was read from have been kept.
+    pub fn write_with<W: Write>(&self, mut w: W, names: NameCodec) -> IoResult<()> {
         match self {
             PackEntry::Empty(EmptyEntry { next_block }) => {
                 w.write_all(
@@ -357,6 +452,7 @@ impl RawIo for PackEntry {
             }
             PackEntry::Directory(DirectoryEntry {
                 name,
+                raw_name,
                 access_time,
                 create_time,
                 modify_time,
@@ -365,6 +461,7 @@ impl RawIo for PackEntry {
             })
             | PackEntry::File(FileEntry {
                 name,
+                raw_name,
                 access_time,
                 create_time,
                 modify_time,
@@ -373,10 +470,10 @@ impl RawIo for PackEntry {
                 ..
             }) => {
                 w.write_u8(if self.is_dir() { 1 } else { 2 })?;
-                #[cfg(feature = "euc-kr")]
-                let mut encoded = encoding_rs::EUC_KR.encode(name).0.into_owned();
-                #[cfg(not(feature = "euc-kr"))]
-                let mut encoded = name.as_bytes().to_owned();
+                let mut encoded = match raw_name {
+                    Some(raw) => raw.to_vec(),
+                    None => names.encode(name),
+                };
                 encoded.resize(81, 0);
                 w.write_all(&encoded)?;
                 w.write_u32::<LE>(access_time.dwLowDateTime)?;
@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK};
+use crate::raw::block_manager::BlockManager;
+use crate::raw::entry::PackEntry;
+use crate::raw::StreamOffset;
+
+/// Keeps track of the byte ranges of the stream that are not referenced by
+/// any [`PackBlock`](crate::raw::block_chain::PackBlock) or file, so that
+/// their space can be handed out again instead of growing the stream.
+#[derive(Clone, Debug, Default)]
+pub struct FreeSpaceMap {
+    /// holes keyed by their start offset, mapping to their length
+    by_offset: BTreeMap<u64, u64>,
+    /// holes ordered by (length, start offset) for best-fit lookups
+    by_len: BTreeSet<(u64, u64)>,
+    /// the current length of the stream
+    end: u64,
+}
+
+impl FreeSpaceMap {
+    /// Computes the free space of a stream of length `stream_len` from all
+    /// blocks and file data referenced by the `block_manager`.
+    pub fn new(block_manager: &BlockManager, stream_len: u64) -> Self {
+        // the header occupies everything in front of the root block
+        let mut used = vec![(0, PK2_ROOT_BLOCK.0)];
+        for chain in block_manager.chains() {
+            used.extend(
+                chain
+                    .block_offsets()
+                    .map(|offset| (offset.0, offset.0 + PK2_FILE_BLOCK_SIZE as u64)),
+            );
+            used.extend(chain.entries().filter_map(PackEntry::as_file).map(|file| {
+                let StreamOffset(pos) = file.pos_data();
+                (pos, pos.saturating_add(file.size() as u64))
+            }));
+        }
+        used.sort_unstable();
+
+        let mut this = FreeSpaceMap { end: stream_len, ..Self::default() };
+        let mut cursor = 0;
+        for (start, end) in used {
+            if start > cursor {
+                this.insert_hole(cursor, start.min(stream_len) - cursor.min(stream_len));
+            }
+            cursor = cursor.max(end);
+        }
+        if cursor < stream_len {
+            this.insert_hole(cursor, stream_len - cursor);
+        }
+        this.end = this.end.max(cursor);
+        this
+    }
+
+    /// Creates a map for a stream of length `stream_len` whose unused space is
+    /// not known, treating all of it as used. Space freed afterwards is
+    /// tracked as usual.
+    pub fn fully_used(stream_len: u64) -> Self {
+        FreeSpaceMap { end: stream_len, ..Self::default() }
+    }
+
+    /// The current length of the stream including all allocations.
+    pub fn stream_len(&self) -> u64 {
+        self.end
+    }
+
+    /// Returns the total amount of bytes that are currently unused.
+    #[cfg(test)]
+    pub fn free_bytes(&self) -> u64 {
+        self.by_offset.values().sum()
+    }
+
+    /// Returns an iterator over all holes as `(offset, len)` pairs in stream
+    /// order.
+    pub fn holes(&self) -> impl Iterator<Item = (StreamOffset, u64)> + '_ {
+        self.by_offset.iter().map(|(&offset, &len)| (StreamOffset(offset), len))
+    }
+
+    /// Reserves `len` bytes, returning the offset of the smallest hole that
+    /// fits. If no hole is big enough the space is taken from the end of the
+    /// stream, extending a trailing hole if there is one.
+    pub fn allocate(&mut self, len: u64) -> StreamOffset {
+        if len == 0 {
+            return StreamOffset(0);
+        }
+        if let Some(&(hole_len, offset)) = self.by_len.range((len, 0)..).next() {
+            self.remove_hole(offset, hole_len);
+            if hole_len > len {
+                self.insert_hole(offset + len, hole_len - len);
+            }
+            return StreamOffset(offset);
+        }
+        self.append(len)
+    }
+
+    /// Returns the offset at which [`FreeSpaceMap::append`] places its data,
+    /// the start of the trailing hole or the end of the stream.
+    pub fn tail(&self) -> StreamOffset {
+        match self.by_offset.iter().next_back() {
+            Some((&offset, &hole_len)) if offset + hole_len == self.end => StreamOffset(offset),
+            _ => StreamOffset(self.end),
+        }
+    }
+
+    /// Reserves `len` bytes at the [tail](FreeSpaceMap::tail) of the stream.
+    pub fn append(&mut self, len: u64) -> StreamOffset {
+        let StreamOffset(offset) = self.tail();
+        if let Some(hole_len) = self.by_offset.get(&offset).copied() {
+            self.remove_hole(offset, hole_len);
+            // the appended data might not fill the trailing hole completely
+            if hole_len > len {
+                self.insert_hole(offset + len, hole_len - len);
+            }
+        }
+        self.end = self.end.max(offset + len);
+        StreamOffset(offset)
+    }
+
+    /// Marks the range starting at `offset` of length `len` as unused,
+    /// merging it with neighbouring holes.
+    ///
+    /// The range usually stems from the index of the archive, so the part of
+    /// it past the end of the stream is ignored and parts that are free
+    /// already, as with files sharing their data, are merged.
+    pub fn free(&mut self, StreamOffset(offset): StreamOffset, len: u64) {
+        let mut start = offset.min(self.end);
+        let mut end = offset.saturating_add(len).min(self.end);
+        if start >= end {
+            return;
+        }
+        // holes never overlap, so their ends decrease when walking them backwards
+        let touching = self
+            .by_offset
+            .range(..=end)
+            .rev()
+            .take_while(|&(&hole, &hole_len)| hole + hole_len >= start)
+            .map(|(&hole, &hole_len)| (hole, hole_len))
+            .collect::<Vec<_>>();
+        for (hole, hole_len) in touching {
+            self.remove_hole(hole, hole_len);
+            start = start.min(hole);
+            end = end.max(hole + hole_len);
+        }
+        self.insert_hole(start, end - start);
+    }
+
+    fn insert_hole(&mut self, offset: u64, len: u64) {
+        if len != 0 {
+            self.by_offset.insert(offset, len);
+            self.by_len.insert((len, offset));
+        }
+    }
+
+    fn remove_hole(&mut self, offset: u64, len: u64) {
+        self.by_offset.remove(&offset);
+        self.by_len.remove(&(len, offset));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FreeSpaceMap;
+    use crate::raw::StreamOffset;
+
+    fn map_with_holes(holes: &[(u64, u64)], end: u64) -> FreeSpaceMap {
+        let mut map = FreeSpaceMap { end, ..FreeSpaceMap::default() };
+        holes.iter().for_each(|&(offset, len)| map.insert_hole(offset, len));
+        map
+    }
+
+    #[test]
+    fn allocate_best_fit() {
+        let mut map = map_with_holes(&[(100, 50), (200, 20), (300, 30)], 1000);
+        assert_eq!(map.allocate(25), StreamOffset(300));
+        assert_eq!(map.allocate(20), StreamOffset(200));
+        assert_eq!(map.allocate(10), StreamOffset(100));
+        assert_eq!(
+            map.holes().collect::<Vec<_>>(),
+            [(StreamOffset(110), 40), (StreamOffset(325), 5)]
+        );
+        assert_eq!(map.allocate(100), StreamOffset(1000));
+        assert_eq!(map.stream_len(), 1100);
+    }
+
+    #[test]
+    fn allocate_extends_trailing_hole() {
+        let mut map = map_with_holes(&[(100, 50), (900, 100)], 1000);
+        assert_eq!(map.allocate(150), StreamOffset(900));
+        assert_eq!(map.stream_len(), 1050);
+        assert_eq!(map.free_bytes(), 50);
+    }
+
+    #[test]
+    fn free_coalesces() {
+        let mut map = map_with_holes(&[(100, 50), (200, 50)], 1000);
+        map.free(StreamOffset(150), 50);
+        assert_eq!(map.holes().collect::<Vec<_>>(), [(StreamOffset(100), 150)]);
+        assert_eq!(map.allocate(150), StreamOffset(100));
+        assert_eq!(map.free_bytes(), 0);
+    }
+
+    #[test]
+    fn free_ignores_data_past_end() {
+        let mut map = map_with_holes(&[(100, 50)], 1000);
+        map.free(StreamOffset(900), 500);
+        map.free(StreamOffset(2000), 10);
+        map.free(StreamOffset(u64::MAX - 1), 10);
+        assert_eq!(
+            map.holes().collect::<Vec<_>>(),
+            [(StreamOffset(100), 50), (StreamOffset(900), 100)]
+        );
+        assert_eq!(map.stream_len(), 1000);
+    }
+
+    #[test]
+    fn free_merges_overlapping_holes() {
+        let mut map = map_with_holes(&[(100, 50), (200, 50), (400, 10)], 1000);
+        map.free(StreamOffset(120), 100);
+        map.free(StreamOffset(100), 20);
+        assert_eq!(
+            map.holes().collect::<Vec<_>>(),
+            [(StreamOffset(100), 150), (StreamOffset(400), 10)]
+        );
+        assert_eq!(map.free_bytes(), 160);
+    }
+}
@@ -5,8 +5,9 @@ use std::io::{Read, Result as IoResult, Write};
 
 use crate::blowfish::Blowfish;
 use crate::constants::*;
-use crate::error::{OpenError, OpenResult};
+use crate::error::{ErrorKind, Result};
 use crate::io::RawIo;
+use crate::options::FormatProfile;
 
 pub struct PackHeader {
     pub signature: [u8; 30],
@@ -18,41 +19,59 @@ pub struct PackHeader {
 
 impl Default for PackHeader {
     fn default() -> Self {
+        Self::new(&FormatProfile::JOYMAX)
+    }
+}
+
+impl PackHeader {
+    /// Creates the header of an unencrypted archive of the given format.
+    pub fn new(profile: &FormatProfile) -> Self {
         PackHeader {
-            signature: *PK2_SIGNATURE,
-            version: PK2_VERSION,
+            signature: profile.signature,
+            version: profile.version,
             encrypted: false,
-            verify: *PK2_CHECKSUM,
+            verify: profile.checksum,
             reserved: [0; 205],
         }
     }
-}
 
-impl PackHeader {
-    pub fn new_encrypted(bf: &Blowfish) -> Self {
-        let mut this = Self::default();
-        let _ = bf.encrypt(&mut this.verify);
-        this.encrypted = true;
+    pub fn new_encrypted(bf: &Blowfish, profile: &FormatProfile) -> Self {
+        let mut this = Self::new(profile);
+        this.set_encryption(Some(bf), profile);
         this
     }
 
+    /// Marks the header as encrypted with the given blowfish key, or as
+    /// unencrypted if there is none.
+    pub fn set_encryption(&mut self, bf: Option<&Blowfish>, profile: &FormatProfile) {
+        self.verify = profile.checksum;
+        if let Some(bf) = bf {
+            bf.encrypt(&mut self.verify);
+        }
+        self.encrypted = bf.is_some();
+    }
+
     /// Validate the signature of this header. Returns an error if the version
-    /// or signature does not match.
-    pub fn validate_sig(&self) -> OpenResult<()> {
-        if &self.signature != PK2_SIGNATURE {
-            Err(OpenError::CorruptedFile)
-        } else if self.version != PK2_VERSION {
-            Err(OpenError::UnsupportedVersion)
+    /// or signature does not match the profile, unless it is lenient.
+    pub fn validate_sig(&self, profile: &FormatProfile) -> Result<()> {
+        if !profile.strict {
+            Ok(())
+        } else if self.signature != profile.signature {
+            Err(ErrorKind::CorruptedFile.into())
+        } else if self.version != profile.version {
+            Err(ErrorKind::UnsupportedVersion.into())
         } else {
             Ok(())
         }
     }
 
-    /// Verifies the calculated checksum against this header returning an error
-    /// if it doesn't match.
-    pub fn verify(&self, checksum: [u8; 16]) -> OpenResult<()> {
+    /// Verifies the key `bf` against the checksum stored in this header,
+    /// returning an error if it doesn't match.
+    pub fn verify(&self, bf: &Blowfish, profile: &FormatProfile) -> Result<()> {
+        let mut checksum = profile.checksum;
+        bf.encrypt(&mut checksum);
         if checksum[..PK2_CHECKSUM_STORED] != self.verify[..PK2_CHECKSUM_STORED] {
-            Err(OpenError::InvalidKey)
+            Err(ErrorKind::InvalidKey.into())
         } else {
             Ok(())
         }
@@ -85,8 +104,7 @@ impl RawIo for PackHeader {
 
 impl fmt::Debug for PackHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let sig_end =
-            self.signature.iter().position(|&b| b == 0).unwrap_or_else(|| self.signature.len());
+        let sig_end = self.signature.iter().position(|&b| b == 0).unwrap_or(self.signature.len());
         f.debug_struct("PackHeader")
             .field("signature", &std::str::from_utf8(&self.signature[..sig_end]))
             .field("version", &self.version)